@@ -0,0 +1,201 @@
+// AC'97 PCM output (Intel ICH style, as emulated by QEMU's AC97 device).
+// The mixer lives in BAR0 (NAM) and the bus master engines in BAR1 (NABM).
+// Playback uses a 32-entry buffer descriptor list over one page per entry,
+// with the controller running at its fixed 48 kHz rate.
+use super::{AudioError, SAMPLE_RATE};
+use crate::pci::{self, Bar};
+use crate::sync::SpinLock;
+use core::ptr::write_volatile;
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::{FrameAllocator, Size4KiB};
+use x86_64::VirtAddr;
+
+// Mixer registers (NAM)
+const NAM_RESET: u16 = 0x00;
+const NAM_MASTER_VOLUME: u16 = 0x02;
+const NAM_PCM_OUT_VOLUME: u16 = 0x18;
+
+// Bus master registers (NABM), PCM out box
+const PO_BDBAR: u16 = 0x10;
+const PO_CIV: u16 = 0x14;
+const PO_LVI: u16 = 0x15;
+const PO_SR: u16 = 0x16;
+const PO_CR: u16 = 0x1B;
+const GLOBAL_CONTROL: u16 = 0x2C;
+const GLOBAL_STATUS: u16 = 0x30;
+
+const CR_RUN: u8 = 1 << 0;
+const CR_RESET: u8 = 1 << 1;
+const SR_HALTED: u16 = 1 << 0;
+// Write-1-to-clear interrupt status bits
+const SR_CLEAR: u16 = 0x1C;
+const GLOBAL_CONTROL_COLD_RESET: u32 = 1 << 1;
+const GLOBAL_STATUS_CODEC_READY: u32 = 1 << 8;
+
+// 0 dB, unmuted
+const VOLUME_0DB: u16 = 0x0808;
+
+const DESCRIPTORS: usize = 32;
+const BUFFER_SAMPLES: usize = 4096 / 2;
+const POLL_LIMIT: usize = 10_000_000;
+
+#[repr(C)]
+struct BufferDescriptor {
+    address: u32,
+    // Number of 16-bit samples
+    samples: u16,
+    flags: u16,
+}
+
+struct Ac97 {
+    nabm: u16,
+    descriptors: *mut BufferDescriptor,
+    buffers: [VirtAddr; DESCRIPTORS],
+    // Next descriptor to fill
+    next: usize,
+    started: bool,
+}
+
+// Only ever touched with DEVICE locked
+unsafe impl Send for Ac97 {}
+
+static DEVICE: SpinLock<Option<Ac97>> = SpinLock::new("ac97", None);
+
+pub fn init(
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+) -> Result<(), AudioError> {
+    let device = pci::find_by_class(0x04, 0x01, 0x00)
+        .into_iter()
+        .next()
+        .ok_or(AudioError::NoDevice)?;
+    let (nam, nabm) = match (device.bar(0), device.bar(1)) {
+        (Some(Bar::Io { port: nam, .. }), Some(Bar::Io { port: nabm, .. })) => (nam, nabm),
+        _ => return Err(AudioError::NoDevice),
+    };
+    device.enable_bus_master();
+
+    unsafe {
+        Port::<u32>::new(nabm + GLOBAL_CONTROL).write(GLOBAL_CONTROL_COLD_RESET);
+    }
+    wait(|| unsafe { Port::<u32>::new(nabm + GLOBAL_STATUS).read() } & GLOBAL_STATUS_CODEC_READY != 0)?;
+    unsafe {
+        Port::<u16>::new(nam + NAM_RESET).write(1);
+        Port::<u16>::new(nam + NAM_MASTER_VOLUME).write(0);
+        Port::<u16>::new(nam + NAM_PCM_OUT_VOLUME).write(VOLUME_0DB);
+        Port::<u8>::new(nabm + PO_CR).write(CR_RESET);
+    }
+    wait(|| unsafe { Port::<u8>::new(nabm + PO_CR).read() } & CR_RESET == 0)?;
+
+    let (list_phys, list_virt) = dma_frame(frame_allocator, physical_memory_offset)?;
+    let descriptors: *mut BufferDescriptor = list_virt.as_mut_ptr();
+    let mut buffers = [VirtAddr::zero(); DESCRIPTORS];
+    for (index, buffer) in buffers.iter_mut().enumerate() {
+        let (phys, virt) = dma_frame(frame_allocator, physical_memory_offset)?;
+        *buffer = virt;
+        unsafe {
+            write_volatile(
+                descriptors.add(index),
+                BufferDescriptor {
+                    address: phys.as_u64() as u32,
+                    samples: 0,
+                    flags: 0,
+                },
+            );
+        }
+    }
+    unsafe {
+        Port::<u32>::new(nabm + PO_BDBAR).write(list_phys.as_u64() as u32);
+    }
+
+    info!(
+        "ac97: {:02x}:{:02x}.{}, {} Hz stereo, {} ms of buffering",
+        device.address.bus,
+        device.address.device,
+        device.address.function,
+        SAMPLE_RATE,
+        (DESCRIPTORS * BUFFER_SAMPLES / 2) as u32 * 1000 / SAMPLE_RATE
+    );
+    crate::device::bind(device.node, "ac97");
+    *DEVICE.lock() = Some(Ac97 {
+        nabm,
+        descriptors,
+        buffers,
+        next: 0,
+        started: false,
+    });
+    Ok(())
+}
+
+fn dma_frame(
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    offset: VirtAddr,
+) -> Result<(x86_64::PhysAddr, VirtAddr), AudioError> {
+    let (phys, virt) =
+        crate::alloc_dma_frame(frame_allocator, offset).ok_or(AudioError::OutOfMemory)?;
+    if phys.as_u64() > u32::MAX as u64 {
+        return Err(AudioError::AddressTooHigh);
+    }
+    Ok((phys, virt))
+}
+
+fn wait(mut done: impl FnMut() -> bool) -> Result<(), AudioError> {
+    for _ in 0..POLL_LIMIT {
+        if done() {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(AudioError::Timeout)
+}
+
+pub fn play(samples: &[i16]) -> Result<(), AudioError> {
+    let mut device = DEVICE.lock();
+    let device = device.as_mut().ok_or(AudioError::NoDevice)?;
+    for chunk in samples.chunks(BUFFER_SAMPLES) {
+        device.queue(chunk)?;
+    }
+    Ok(())
+}
+
+impl Ac97 {
+    fn halted(&self) -> bool {
+        unsafe { Port::<u16>::new(self.nabm + PO_SR).read() }
+        &SR_HALTED != 0
+    }
+
+    fn queue(&mut self, chunk: &[i16]) -> Result<(), AudioError> {
+        // The descriptor being played can't be refilled; all others past the
+        // last valid index are free
+        let nabm = self.nabm;
+        let next = self.next;
+        if self.started {
+            wait(|| {
+                let civ = unsafe { Port::<u8>::new(nabm + PO_CIV).read() } as usize;
+                civ % DESCRIPTORS != next || self.halted()
+            })?;
+        }
+
+        unsafe {
+            let buffer: *mut i16 = self.buffers[next].as_mut_ptr();
+            core::ptr::copy_nonoverlapping(chunk.as_ptr(), buffer, chunk.len());
+            let descriptor = self.descriptors.add(next);
+            write_volatile(
+                core::ptr::addr_of_mut!((*descriptor).samples),
+                chunk.len() as u16,
+            );
+            Port::<u8>::new(nabm + PO_LVI).write(next as u8);
+        }
+        self.next = (next + 1) % DESCRIPTORS;
+
+        // Start the engine, or restart it after it ran dry
+        if !self.started || self.halted() {
+            unsafe {
+                Port::<u16>::new(nabm + PO_SR).write(SR_CLEAR);
+                Port::<u8>::new(nabm + PO_CR).write(CR_RUN);
+            }
+            self.started = true;
+        }
+        Ok(())
+    }
+}
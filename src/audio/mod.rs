@@ -0,0 +1,32 @@
+// Audio subsystem: PC speaker tones and PCM playback through an AC'97
+// controller. PCM samples are signed 16-bit, stereo interleaved, at 48 kHz.
+use x86_64::structures::paging::{FrameAllocator, Size4KiB};
+use x86_64::VirtAddr;
+
+pub mod ac97;
+pub mod speaker;
+
+pub const SAMPLE_RATE: u32 = 48_000;
+pub const CHANNELS: usize = 2;
+
+#[derive(Debug)]
+pub enum AudioError {
+    NoDevice,
+    OutOfMemory,
+    // DMA buffers must sit below 4 GiB for the controller
+    AddressTooHigh,
+    Timeout,
+}
+
+pub fn init(frame_allocator: &mut impl FrameAllocator<Size4KiB>, physical_memory_offset: VirtAddr) {
+    match ac97::init(frame_allocator, physical_memory_offset) {
+        Ok(()) | Err(AudioError::NoDevice) => {}
+        Err(err) => warn!("ac97: {:?}", err),
+    }
+}
+
+// Queues samples for playback, blocking while the output ring is full.
+// Returns once everything is queued, not once it has played.
+pub fn play(samples: &[i16]) -> Result<(), AudioError> {
+    ac97::play(samples)
+}
@@ -0,0 +1,58 @@
+// PC speaker driven by PIT channel 2 in square wave mode
+use x86_64::instructions::port::Port;
+
+const PIT_FREQUENCY: u32 = 1_193_182;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const SYSTEM_CONTROL: u16 = 0x61;
+
+// Channel 2, low byte then high byte, mode 3 (square wave)
+const CHANNEL2_SQUARE_WAVE: u8 = 0xB6;
+const SPEAKER_GATE: u8 = 1 << 0;
+const SPEAKER_DATA: u8 = 1 << 1;
+// Toggles every 15.085 us, driven by the (legacy) DRAM refresh timer
+const REFRESH_TOGGLE: u8 = 1 << 4;
+
+pub fn start(frequency: u32) {
+    let divisor = (PIT_FREQUENCY / frequency.clamp(19, PIT_FREQUENCY)) as u16;
+    unsafe {
+        Port::<u8>::new(PIT_COMMAND).write(CHANNEL2_SQUARE_WAVE);
+        let mut channel = Port::<u8>::new(PIT_CHANNEL2);
+        channel.write(divisor as u8);
+        channel.write((divisor >> 8) as u8);
+
+        let mut control = Port::<u8>::new(SYSTEM_CONTROL);
+        let value = control.read();
+        control.write(value | SPEAKER_GATE | SPEAKER_DATA);
+    }
+}
+
+pub fn stop() {
+    unsafe {
+        let mut control = Port::<u8>::new(SYSTEM_CONTROL);
+        let value = control.read();
+        control.write(value & !(SPEAKER_GATE | SPEAKER_DATA));
+    }
+}
+
+// Plays a tone for `ms` milliseconds. Busy-waits, as there is no timer
+// interrupt to sleep on.
+pub fn beep(frequency: u32, ms: u32) {
+    start(frequency);
+    wait_us(ms as u64 * 1000);
+    stop();
+}
+
+fn wait_us(us: u64) {
+    let mut control = Port::<u8>::new(SYSTEM_CONTROL);
+    let mut last = unsafe { control.read() } & REFRESH_TOGGLE;
+    let mut toggles = us * 1000 / 15_085;
+    while toggles > 0 {
+        let now = unsafe { control.read() } & REFRESH_TOGGLE;
+        if now != last {
+            last = now;
+            toggles -= 1;
+        }
+        core::hint::spin_loop();
+    }
+}
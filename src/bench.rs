@@ -0,0 +1,160 @@
+// Micro-benchmarks for kernel primitives, run at boot with `bench=1`.
+// Reports TSC cycles per operation over serial, so numbers are comparable
+// between builds on the same machine.
+use crate::block::{self, BlockError};
+use crate::memory::layout;
+use crate::serial_println;
+use crate::sync::SpinLock;
+use alloc::boxed::Box;
+use core::arch::x86_64::{_mm_lfence, _rdtsc};
+use core::hint::black_box;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+const ITERATIONS: u64 = 1000;
+const SCRATCH_PAGE: u64 = layout::BENCH_SCRATCH_START;
+const DISK_TRANSFER: usize = 4096;
+
+struct Stats {
+    min: u64,
+    max: u64,
+    total: u64,
+    iterations: u64,
+}
+
+fn rdtsc() -> u64 {
+    unsafe {
+        _mm_lfence();
+        let tsc = _rdtsc();
+        _mm_lfence();
+        tsc
+    }
+}
+
+fn measure(iterations: u64, mut f: impl FnMut()) -> Stats {
+    let mut stats = Stats {
+        min: u64::MAX,
+        max: 0,
+        total: 0,
+        iterations,
+    };
+    for _ in 0..iterations {
+        let start = rdtsc();
+        f();
+        let cycles = rdtsc() - start;
+        stats.min = stats.min.min(cycles);
+        stats.max = stats.max.max(cycles);
+        stats.total += cycles;
+    }
+    stats
+}
+
+fn report(name: &str, stats: &Stats) {
+    serial_println!(
+        "bench: {:<24} {:>8} {:>8} {:>8} {:>8}",
+        name,
+        stats.iterations,
+        stats.min,
+        stats.total / stats.iterations,
+        stats.max
+    );
+}
+
+// Like `report`, but for an operation that can fail; a failure is logged
+// instead of a timing
+fn report_io(name: &str, mut f: impl FnMut() -> Result<(), BlockError>) {
+    let mut error = None;
+    let stats = measure(ITERATIONS, || {
+        if let Err(err) = f() {
+            error = Some(err);
+        }
+    });
+    match error {
+        Some(err) => warn!("bench: {} failed: {:?}", name, err),
+        None => report(name, &stats),
+    }
+}
+
+pub fn run(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    serial_println!(
+        "bench: {:<24} {:>8} {:>8} {:>8} {:>8}",
+        "primitive",
+        "iters",
+        "min",
+        "avg",
+        "max"
+    );
+
+    report("tsc read overhead", &measure(ITERATIONS, || {}));
+
+    report(
+        "heap alloc+free 64B",
+        &measure(ITERATIONS, || {
+            black_box(Box::new([0u8; 64]));
+        }),
+    );
+    report(
+        "heap alloc+free 4KiB",
+        &measure(ITERATIONS, || {
+            black_box(Box::new([0u8; 4096]));
+        }),
+    );
+
+    let raw = spin::Mutex::new(0u64);
+    report(
+        "spin::Mutex lock+unlock",
+        &measure(ITERATIONS, || {
+            *raw.lock() += 1;
+        }),
+    );
+    let lock = SpinLock::new("bench", 0u64);
+    report(
+        "SpinLock lock+unlock",
+        &measure(ITERATIONS, || {
+            *lock.lock() += 1;
+        }),
+    );
+
+    // Map and unmap the same frame over and over
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(SCRATCH_PAGE));
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe {
+        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+    }
+    mapper.unmap(page).expect("bench page not mapped").1.flush();
+    report(
+        "page map+unmap",
+        &measure(ITERATIONS, || unsafe {
+            mapper
+                .map_to(page, frame, flags, frame_allocator)
+                .expect("bench page map failed")
+                .flush();
+            mapper.unmap(page).expect("bench page not mapped").1.flush();
+        }),
+    );
+
+    if let Some(disk) = block::find("ram0") {
+        let capacity = disk.block_count() * disk.block_size() as u64;
+        if capacity < DISK_TRANSFER as u64 || DISK_TRANSFER % disk.block_size() != 0 {
+            info!(
+                "bench: ram0 cannot take a {}-byte transfer, skipping it",
+                DISK_TRANSFER
+            );
+        } else {
+            let mut buffer = alloc::vec![0u8; DISK_TRANSFER];
+            report_io("ram0 write 4KiB", || disk.write_blocks(0, &buffer));
+            report_io("ram0 read 4KiB", || disk.read_blocks(0, &mut buffer));
+        }
+    }
+
+    info!("Benchmarks complete (cycles per operation reported over serial)");
+    Ok(())
+}
@@ -0,0 +1,79 @@
+// Block device layer. Storage drivers register their devices here under a
+// short name ("usb0", ...) and filesystems look them up by name.
+use crate::sync::SpinLock;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    // Request runs past the end of the device or is not a whole number of blocks
+    OutOfRange,
+    Io,
+    ReadOnly,
+}
+
+// Devices are shared between users, so requests take `&self` and drivers
+// serialize internally
+pub trait BlockDevice: Send + Sync {
+    fn name(&self) -> &str;
+    fn block_size(&self) -> usize;
+    fn block_count(&self) -> u64;
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>;
+}
+
+static DEVICES: SpinLock<Vec<Arc<dyn BlockDevice>>> = SpinLock::new("block_devices", Vec::new());
+
+// Checks a request against the device geometry and returns the block count
+pub fn check_request(device: &dyn BlockDevice, lba: u64, length: usize) -> Result<u64, BlockError> {
+    let size = device.block_size();
+    if length % size != 0 {
+        return Err(BlockError::OutOfRange);
+    }
+    let blocks = (length / size) as u64;
+    match lba.checked_add(blocks) {
+        Some(end) if end <= device.block_count() => Ok(blocks),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+pub fn register(device: Arc<dyn BlockDevice>) {
+    info!(
+        "block: {}: {} blocks of {} bytes ({} MiB)",
+        device.name(),
+        device.block_count(),
+        device.block_size(),
+        device.block_count() * device.block_size() as u64 / (1024 * 1024)
+    );
+    DEVICES.lock().push(device);
+}
+
+// Drops a device from the registry, for storage that goes away. Users still
+// holding it get errors from then on.
+pub fn unregister(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    let mut devices = DEVICES.lock();
+    let index = devices.iter().position(|device| device.name() == name)?;
+    info!("block: {}: removed", name);
+    Some(devices.remove(index))
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
+
+// For the panic path, which must not wait for a registry lock it may hold
+pub fn try_find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .try_lock()?
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
+
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
@@ -0,0 +1,95 @@
+// Early boot allocator, for memory needed before the heap is up. Small
+// objects are bumped out of the current frame; anything larger than a frame
+// needs that many physically contiguous frames from the frame allocator,
+// which hands them out in ascending order within a region. Memory is reached
+// through the physical memory window and is never freed: once the heap is
+// up, `finish` closes the allocator and what it handed out stays with its
+// users for good.
+use crate::sync::SpinLock;
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use x86_64::structures::paging::{FrameAllocator, Size4KiB};
+use x86_64::VirtAddr;
+
+const FRAME_SIZE: u64 = 4096;
+
+struct BootMem {
+    // Free part of the current frame, as window addresses
+    next: u64,
+    end: u64,
+    frames: usize,
+    closed: bool,
+}
+
+static BOOTMEM: SpinLock<BootMem> = SpinLock::new(
+    "bootmem",
+    BootMem {
+        next: 0,
+        end: 0,
+        frames: 0,
+        closed: false,
+    },
+);
+
+// Returns zeroed memory for `layout`, or None if the frame allocator is out
+// of (contiguous) frames or the heap is already up
+pub fn alloc(
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+    layout: Layout,
+) -> Option<NonNull<u8>> {
+    let mut bootmem = BOOTMEM.lock();
+    if bootmem.closed || layout.align() as u64 > FRAME_SIZE {
+        return None;
+    }
+
+    let start = (bootmem.next + layout.align() as u64 - 1) & !(layout.align() as u64 - 1);
+    if bootmem.next != 0 && start + layout.size() as u64 <= bootmem.end {
+        bootmem.next = start + layout.size() as u64;
+        return NonNull::new(start as *mut u8);
+    }
+
+    let frames = ((layout.size() as u64).max(1) + FRAME_SIZE - 1) / FRAME_SIZE;
+    let first = frame_allocator.allocate_frame()?.start_address();
+    for index in 1..frames {
+        let frame = frame_allocator.allocate_frame()?.start_address();
+        if frame != first + index * FRAME_SIZE {
+            warn!("bootmem: no {} contiguous frames", frames);
+            return None;
+        }
+    }
+    bootmem.frames += frames as usize;
+
+    let start = (physical_memory_offset + first.as_u64()).as_u64();
+    unsafe { core::ptr::write_bytes(start as *mut u8, 0, (frames * FRAME_SIZE) as usize) };
+    bootmem.next = start + layout.size() as u64;
+    bootmem.end = start + frames * FRAME_SIZE;
+    NonNull::new(start as *mut u8)
+}
+
+// Allocates a slice of `len` copies of `value`
+pub fn alloc_slice<T: Copy>(
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+    len: usize,
+    value: T,
+) -> Option<&'static mut [T]> {
+    let layout = Layout::array::<T>(len).ok()?;
+    let ptr = alloc(frame_allocator, physical_memory_offset, layout)?.cast::<T>();
+    for index in 0..len {
+        unsafe { ptr.as_ptr().add(index).write(value) };
+    }
+    Some(unsafe { core::slice::from_raw_parts_mut(ptr.as_ptr(), len) })
+}
+
+// Called once the heap is up; later allocations go through the heap
+pub fn finish() {
+    let mut bootmem = BOOTMEM.lock();
+    bootmem.closed = true;
+    if bootmem.frames > 0 {
+        info!(
+            "bootmem: {} KiB used before the heap was up",
+            bootmem.frames * 4
+        );
+    }
+}
@@ -0,0 +1,24 @@
+// Kernel command line. The bootloader does not pass one, so it is baked in at
+// build time from the KERNEL_CMDLINE environment variable, e.g.
+// `KERNEL_CMDLINE="selftest=1" cargo bootimage`.
+const CMDLINE: &str = match option_env!("KERNEL_CMDLINE") {
+    Some(cmdline) => cmdline,
+    None => "",
+};
+
+pub fn get() -> &'static str {
+    CMDLINE
+}
+
+// Value of a `key=value` option, or "" for a bare `key`
+pub fn value(key: &str) -> Option<&'static str> {
+    CMDLINE.split_whitespace().find_map(|option| {
+        let (name, value) = option.split_once('=').unwrap_or((option, ""));
+        (name == key).then_some(value)
+    })
+}
+
+// Whether a boolean option is set, as `key`, `key=1`, `key=on` or `key=yes`
+pub fn enabled(key: &str) -> bool {
+    matches!(value(key), Some("" | "1" | "on" | "yes" | "true"))
+}
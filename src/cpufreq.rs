@@ -0,0 +1,264 @@
+// Basic CPU frequency management through model-specific registers: Intel
+// Enhanced SpeedStep (IA32_PERF_CTL) and AMD hardware P-states. Frequencies
+// are exposed as performance levels, 0 being the slowest, and an on-demand
+// governor hooked into the idle loop moves between them based on idle
+// residency.
+use crate::idle::{self, IdleState, IdleStats};
+use crate::msr::{
+    self, AMD_PSTATE_CTL, AMD_PSTATE_DEF_BASE, AMD_PSTATE_LIMIT, AMD_PSTATE_STATUS, IA32_PERF_CTL,
+    IA32_PERF_STATUS, MSR_PLATFORM_INFO,
+};
+use crate::sync::SpinLock;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+// Bus clock for ratio-based Intel frequencies (Sandy Bridge and later)
+const INTEL_BUS_MHZ: u32 = 100;
+const MAX_AMD_PSTATES: usize = 8;
+
+// Governor sampling period and thresholds, in idle residency
+const SAMPLE_CYCLES: u64 = 100_000_000;
+const UP_BELOW_IDLE_PERMILLE: u64 = 200;
+const DOWN_ABOVE_IDLE_PERMILLE: u64 = 800;
+
+#[derive(Clone, Copy)]
+enum Driver {
+    IntelEist {
+        min_ratio: u32,
+        max_ratio: u32,
+    },
+    AmdPstate {
+        // Frequencies of P0 (fastest) onwards
+        mhz: [u32; MAX_AMD_PSTATES],
+        count: usize,
+    },
+}
+
+struct Governor {
+    level: usize,
+    // Highest level the governor may pick, while thermal throttling
+    max_level: Option<usize>,
+    last_sample: u64,
+    last_stats: IdleStats,
+}
+
+static DRIVER: SpinLock<Option<Driver>> = SpinLock::new("cpufreq_driver", None);
+static GOVERNOR: SpinLock<Governor> = SpinLock::new(
+    "cpufreq_governor",
+    Governor {
+        level: 0,
+        max_level: None,
+        last_sample: 0,
+        last_stats: IdleStats {
+            entries: 0,
+            idle_cycles: 0,
+            total_cycles: 0,
+        },
+    },
+);
+
+fn vendor() -> [u8; 12] {
+    let leaf = unsafe { __cpuid(0) };
+    let mut vendor = [0; 12];
+    vendor[0..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&leaf.edx.to_le_bytes());
+    vendor[8..12].copy_from_slice(&leaf.ecx.to_le_bytes());
+    vendor
+}
+
+fn family() -> u32 {
+    let eax = unsafe { __cpuid(1) }.eax;
+    let base = (eax >> 8) & 0xF;
+    if base == 0xF {
+        base + ((eax >> 20) & 0xFF)
+    } else {
+        base
+    }
+}
+
+fn probe_intel() -> Option<Driver> {
+    // CPUID.01H:ECX.EIST[bit 7]
+    if unsafe { __cpuid(1) }.ecx & (1 << 7) == 0 {
+        return None;
+    }
+    // Nehalem and later; older models and some virtual CPUs fault on it
+    let info = unsafe { msr::probe(MSR_PLATFORM_INFO) }.ok()?;
+    let max_ratio = ((info >> 8) & 0xFF) as u32;
+    let min_ratio = ((info >> 40) & 0xFF) as u32;
+    if max_ratio == 0 || min_ratio == 0 || min_ratio > max_ratio {
+        return None;
+    }
+    Some(Driver::IntelEist {
+        min_ratio,
+        max_ratio,
+    })
+}
+
+fn amd_pstate_mhz(def: u64, family: u32) -> u32 {
+    if family >= 0x17 {
+        let fid = (def & 0xFF) as u32;
+        let dfs = ((def >> 8) & 0x3F) as u32;
+        if dfs == 0 {
+            0
+        } else {
+            fid * 200 / dfs
+        }
+    } else {
+        let fid = (def & 0x3F) as u32;
+        let did = ((def >> 6) & 0x7) as u32;
+        (100 * (fid + 0x10)) >> did
+    }
+}
+
+fn probe_amd() -> Option<Driver> {
+    // CPUID.80000007H:EDX.HwPstate[bit 7]
+    if unsafe { __cpuid(0x8000_0000) }.eax < 0x8000_0007
+        || unsafe { __cpuid(0x8000_0007) }.edx & (1 << 7) == 0
+    {
+        return None;
+    }
+    let family = family();
+    let limit = unsafe { msr::read(AMD_PSTATE_LIMIT) }.ok()?;
+    let highest = ((limit >> 4) & 0x7) as usize;
+
+    let mut mhz = [0; MAX_AMD_PSTATES];
+    let mut count = 0;
+    for pstate in 0..=highest {
+        let def = unsafe { msr::read(AMD_PSTATE_DEF_BASE + pstate as u32) }.ok()?;
+        // PstateEn[bit 63]
+        if def & (1 << 63) == 0 {
+            break;
+        }
+        mhz[pstate] = amd_pstate_mhz(def, family);
+        count += 1;
+    }
+    if count == 0 {
+        return None;
+    }
+    Some(Driver::AmdPstate { mhz, count })
+}
+
+pub fn init() {
+    let driver = match &vendor() {
+        b"GenuineIntel" => probe_intel(),
+        b"AuthenticAMD" => probe_amd(),
+        _ => None,
+    };
+    let driver = match driver {
+        Some(driver) => driver,
+        None => {
+            info!("cpufreq: no supported frequency scaling interface");
+            return;
+        }
+    };
+
+    *DRIVER.lock() = Some(driver);
+    let top = levels() - 1;
+    GOVERNOR.lock().level = top;
+    set_level(top);
+    idle::set_governor(ondemand);
+    info!(
+        "cpufreq: {} levels, {}-{} MHz, now {} MHz",
+        levels(),
+        level_mhz(0),
+        level_mhz(top),
+        current_mhz().unwrap_or(0)
+    );
+}
+
+pub fn levels() -> usize {
+    match *DRIVER.lock() {
+        Some(Driver::IntelEist {
+            min_ratio,
+            max_ratio,
+        }) => (max_ratio - min_ratio + 1) as usize,
+        Some(Driver::AmdPstate { count, .. }) => count,
+        None => 0,
+    }
+}
+
+pub fn level_mhz(level: usize) -> u32 {
+    match *DRIVER.lock() {
+        Some(Driver::IntelEist { min_ratio, .. }) => (min_ratio + level as u32) * INTEL_BUS_MHZ,
+        Some(Driver::AmdPstate { mhz, count }) => mhz[count - 1 - level],
+        None => 0,
+    }
+}
+
+pub fn set_level(level: usize) {
+    match *DRIVER.lock() {
+        Some(Driver::IntelEist {
+            min_ratio,
+            max_ratio,
+        }) => {
+            let ratio = (min_ratio + level as u32).min(max_ratio) as u64;
+            unsafe {
+                if let Ok(ctl) = msr::read(IA32_PERF_CTL) {
+                    msr::write(IA32_PERF_CTL, (ctl & !0xFF00) | (ratio << 8)).ok();
+                }
+            }
+        }
+        Some(Driver::AmdPstate { count, .. }) => {
+            let pstate = count - 1 - level.min(count - 1);
+            unsafe { msr::write(AMD_PSTATE_CTL, pstate as u64) }.ok();
+        }
+        None => {}
+    }
+}
+
+// Keeps the governor at or below `max`, stepping down now if needed; None
+// lifts the cap
+pub fn set_max_level(max: Option<usize>) {
+    let mut governor = GOVERNOR.lock();
+    governor.max_level = max;
+    if let Some(max) = max {
+        if governor.level > max {
+            set_level(max);
+            governor.level = max;
+        }
+    }
+}
+
+pub fn current_mhz() -> Option<u32> {
+    match *DRIVER.lock() {
+        Some(Driver::IntelEist { .. }) => {
+            let status = unsafe { msr::read(IA32_PERF_STATUS) }.ok()?;
+            Some(((status >> 8) & 0xFF) as u32 * INTEL_BUS_MHZ)
+        }
+        Some(Driver::AmdPstate { mhz, count }) => {
+            let pstate = unsafe { msr::read(AMD_PSTATE_STATUS) }.ok()? as usize & 0x7;
+            (pstate < count).then(|| mhz[pstate])
+        }
+        None => None,
+    }
+}
+
+// Idle governor hook: every SAMPLE_CYCLES, steps the frequency up when the CPU
+// was mostly busy and down when it was mostly idle
+fn ondemand(stats: &IdleStats) -> IdleState {
+    let now = unsafe { _rdtsc() };
+    let mut governor = GOVERNOR.lock();
+    if now - governor.last_sample >= SAMPLE_CYCLES {
+        let idle = stats.idle_cycles - governor.last_stats.idle_cycles;
+        let total = stats.total_cycles - governor.last_stats.total_cycles;
+        let idle_permille = if total == 0 { 0 } else { idle * 1000 / total };
+
+        let level = governor.level;
+        let top = levels()
+            .saturating_sub(1)
+            .min(governor.max_level.unwrap_or(usize::MAX));
+        let next = if idle_permille < UP_BELOW_IDLE_PERMILLE {
+            top
+        } else if idle_permille > DOWN_ABOVE_IDLE_PERMILLE {
+            level.saturating_sub(1)
+        } else {
+            level
+        };
+        if next != level {
+            set_level(next);
+            governor.level = next;
+        }
+        governor.last_sample = now;
+        governor.last_stats = *stats;
+    }
+    idle::default_state()
+}
@@ -0,0 +1,366 @@
+// Structured crash report, written to the serial port when the kernel panics.
+// Everything but the log is repeated at the end on one line for tools, as
+// JSON or, with `crashdump=binary`, the binary encoding in hex. With
+// `crashdump.device=<name>` the binary encoding is also saved to the end of
+// that block device.
+use crate::block::{self, BlockDevice, BlockError};
+use crate::serialize::{self, Binary, Displayed, Hex, Serialize, Serializer};
+use crate::sync::SpinLock;
+use crate::{cmdline, interrupts, irqstats, klog, serial, serial_print, serial_println};
+use core::arch::asm;
+use core::fmt;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::VirtAddr;
+
+const MAX_FRAMES: usize = 16;
+
+// The saved record takes the last RECORD_SIZE bytes of the device: a header
+// block holding RECORD_MAGIC and the record length (u32, little endian), then
+// the record itself
+const RECORD_SIZE: u64 = 64 * 1024;
+const RECORD_MAGIC: [u8; 8] = *b"KCRASH01";
+const MAX_BLOCK_SIZE: usize = 4096;
+
+// Staging for the record, so saving it needs neither the heap nor much stack
+static RECORD_BLOCK: SpinLock<[u8; MAX_BLOCK_SIZE]> =
+    SpinLock::new("crash_record", [0; MAX_BLOCK_SIZE]);
+
+static IN_PANIC: AtomicBool = AtomicBool::new(false);
+
+pub fn report(info: &PanicInfo) {
+    // A panic while reporting a panic: print the bare message and stop there
+    if IN_PANIC.swap(true, Ordering::SeqCst) {
+        serial::force_unlock();
+        serial_println!("\nNESTED PANIC: {}", info);
+        return;
+    }
+
+    serial::force_unlock();
+    serial_println!("\n==================== KERNEL CRASH ====================");
+    serial_println!("{}", info);
+
+    let registers = Registers::read();
+    let mut frames = [0; MAX_FRAMES];
+    let depth = backtrace(&mut frames);
+
+    dump_registers(&registers);
+    dump_backtrace(&frames[..depth]);
+    dump_memory_stats();
+    dump_interrupt_stats();
+    dump_klog();
+    let dump = Dump {
+        info,
+        registers: &registers,
+        frames: &frames[..depth],
+    };
+    dump_structured(&dump);
+    save_structured(&dump);
+
+    serial_println!("======================================================");
+
+    #[cfg(feature = "graphics")]
+    crate::panic_screen::show(info, &registers, &frames[..depth]);
+}
+
+pub struct Registers {
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rflags: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+}
+
+impl Registers {
+    fn read() -> Self {
+        let (rsp, rbp, rflags): (u64, u64, u64);
+        unsafe {
+            asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+            asm!("pushfq; pop {}", out(reg) rflags, options(nomem, preserves_flags));
+        }
+        Registers {
+            rsp,
+            rbp,
+            rflags,
+            cr2: Cr2::read().as_u64(),
+            cr3: Cr3::read().0.start_address().as_u64(),
+        }
+    }
+}
+
+impl Serialize for Registers {
+    fn serialize(&self, out: &mut dyn Serializer) -> fmt::Result {
+        crate::serialize_fields!(out,
+            "rsp" => Hex(self.rsp),
+            "rbp" => Hex(self.rbp),
+            "rflags" => Hex(self.rflags),
+            "cr2" => Hex(self.cr2),
+            "cr3" => Hex(self.cr3),
+        )
+    }
+}
+
+fn dump_registers(registers: &Registers) {
+    serial_println!("--- registers ---");
+    serial_println!(
+        "RSP={:#018x} RBP={:#018x} RFLAGS={:#018x}",
+        registers.rsp,
+        registers.rbp,
+        registers.rflags
+    );
+    serial_println!("CR0={:?}", Cr0::read());
+    serial_println!("CR2={:#018x}", registers.cr2);
+    serial_println!("CR3={:#018x} {:?}", registers.cr3, Cr3::read().1);
+    serial_println!("CR4={:?}", Cr4::read());
+}
+
+fn dump_backtrace(frames: &[u64]) {
+    serial_println!("--- backtrace ---");
+    for (index, ret) in frames.iter().enumerate() {
+        serial_println!("  #{:<2} {:#018x}", index, ret);
+    }
+}
+
+// Largest distance between RSP and a frame pointer that is still trusted to be
+// part of the current stack
+const STACK_WINDOW: u64 = 1024 * 1024;
+
+// Fills `frames` with return addresses by walking the frame pointer chain,
+// starting at the caller. Only meaningful when the kernel is built with frame
+// pointers; otherwise the chain ends early.
+#[inline(never)]
+pub fn backtrace(frames: &mut [u64]) -> usize {
+    let (mut rbp, rsp): (u64, u64);
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+    }
+
+    let mut depth = 0;
+    while depth < frames.len() {
+        if rbp % 8 != 0 || rbp < rsp || rbp - rsp > STACK_WINDOW {
+            break;
+        }
+        if VirtAddr::try_new(rbp).is_err() {
+            break;
+        }
+        let frame = rbp as *const u64;
+        let (next, ret) = unsafe { (*frame, *frame.add(1)) };
+        if ret == 0 {
+            break;
+        }
+        frames[depth] = ret;
+        depth += 1;
+        // Stack grows down, so the caller's frame must be above this one
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    depth
+}
+
+struct HeapStats {
+    used: usize,
+    size: usize,
+    free: usize,
+}
+
+impl Serialize for HeapStats {
+    fn serialize(&self, out: &mut dyn Serializer) -> fmt::Result {
+        crate::serialize_fields!(out,
+            "used" => self.used,
+            "size" => self.size,
+            "free" => self.free,
+        )
+    }
+}
+
+// None if the allocator is locked, as it is when it panicked itself
+fn heap_stats() -> Option<HeapStats> {
+    crate::ALLOCATOR.try_lock().map(|heap| HeapStats {
+        used: heap.used(),
+        size: heap.size(),
+        free: heap.free(),
+    })
+}
+
+fn dump_memory_stats() {
+    serial_println!("--- memory ---");
+    match heap_stats() {
+        Some(heap) => serial_println!(
+            "heap: {} / {} bytes used, {} free",
+            heap.used,
+            heap.size,
+            heap.free
+        ),
+        None => serial_println!("heap: allocator locked, stats unavailable"),
+    }
+}
+
+fn dump_interrupt_stats() {
+    serial_println!("--- interrupts ---");
+    irqstats::for_each(|vector, count, cycles| {
+        serial_println!(
+            "  {:>3} {:<26} {:>10} calls {:>12} cycles avg",
+            vector,
+            interrupts::vector_name(vector),
+            count,
+            cycles / count
+        );
+    });
+}
+
+fn dump_klog() {
+    serial_println!("--- recent log ---");
+    klog::force_unlock();
+    let ring = klog::KLOG.lock();
+    ring.for_each_chunk(|chunk| {
+        for &byte in chunk {
+            serial_print!("{}", byte as char);
+        }
+    });
+}
+
+struct Backtrace<'a>(&'a [u64]);
+
+impl Serialize for Backtrace<'_> {
+    fn serialize(&self, out: &mut dyn Serializer) -> fmt::Result {
+        out.begin_array()?;
+        for &frame in self.0 {
+            out.hex(frame)?;
+        }
+        out.end_array()
+    }
+}
+
+struct Dump<'a> {
+    info: &'a PanicInfo<'a>,
+    registers: &'a Registers,
+    frames: &'a [u64],
+}
+
+impl Serialize for Dump<'_> {
+    fn serialize(&self, out: &mut dyn Serializer) -> fmt::Result {
+        crate::serialize_fields!(out,
+            "panic" => Displayed(self.info),
+            "registers" => self.registers,
+            "backtrace" => Backtrace(self.frames),
+            "heap" => heap_stats(),
+            "interrupts" => irqstats::Summary,
+        )
+    }
+}
+
+fn dump_structured(dump: &Dump) {
+    serial_println!("--- dump ---");
+    if cmdline::value("crashdump") == Some("binary") {
+        let mut out = Binary::new(|byte| serial_print!("{:02x}", byte));
+        dump.serialize(&mut out).ok();
+        serial_println!();
+    } else {
+        serial_println!("{}", serialize::json(dump));
+    }
+}
+
+// Streams the record into the device a block at a time
+struct RecordWriter<'a> {
+    device: &'a dyn BlockDevice,
+    block: &'a mut [u8],
+    lba: u64,
+    end: u64,
+    fill: usize,
+    length: u32,
+    truncated: bool,
+    result: Result<(), BlockError>,
+}
+
+impl RecordWriter<'_> {
+    fn push(&mut self, byte: u8) {
+        if self.lba == self.end {
+            self.truncated = true;
+            return;
+        }
+        self.block[self.fill] = byte;
+        self.fill += 1;
+        self.length += 1;
+        if self.fill == self.block.len() {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        self.block[self.fill..].fill(0);
+        if self.result.is_ok() {
+            self.result = self.device.write_blocks(self.lba, self.block);
+        }
+        self.lba += 1;
+        self.fill = 0;
+    }
+}
+
+// The header is cleared before the record is written and filled in after,
+// so a save cut short never leaves a header describing someone else's data.
+// A panic inside the device's own driver may hang here, but the serial
+// report is out by then.
+fn save_structured(dump: &Dump) {
+    let name = match cmdline::value("crashdump.device") {
+        Some(name) => name,
+        None => return,
+    };
+    let device = match block::try_find(name) {
+        Some(device) => device,
+        None => {
+            serial_println!("crash: no block device {} to save the dump to", name);
+            return;
+        }
+    };
+    let block_size = device.block_size();
+    if block_size > MAX_BLOCK_SIZE
+        || RECORD_SIZE % block_size as u64 != 0
+        || device.block_count() < RECORD_SIZE / block_size as u64
+    {
+        serial_println!("crash: {} cannot hold a crash record", name);
+        return;
+    }
+
+    let mut block = RECORD_BLOCK.lock();
+    let header = device.block_count() - RECORD_SIZE / block_size as u64;
+    let mut writer = RecordWriter {
+        device: &*device,
+        block: &mut block[..block_size],
+        lba: header + 1,
+        end: device.block_count(),
+        fill: 0,
+        length: 0,
+        truncated: false,
+        result: Ok(()),
+    };
+    writer.block.fill(0);
+    writer.result = device.write_blocks(header, writer.block);
+
+    let mut out = Binary::new(|byte| writer.push(byte));
+    dump.serialize(&mut out).ok();
+    if writer.fill > 0 {
+        writer.flush();
+    }
+    writer.block.fill(0);
+    writer.block[..8].copy_from_slice(&RECORD_MAGIC);
+    writer.block[8..12].copy_from_slice(&writer.length.to_le_bytes());
+    if writer.result.is_ok() {
+        writer.result = device.write_blocks(header, writer.block);
+    }
+
+    match writer.result {
+        Ok(()) => serial_println!(
+            "crash: dump saved to {} at block {}, {} bytes{}",
+            name,
+            header,
+            writer.length,
+            if writer.truncated { " (truncated)" } else { "" }
+        ),
+        Err(err) => serial_println!("crash: saving the dump to {} failed: {:?}", name, err),
+    }
+}
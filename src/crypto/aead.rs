@@ -0,0 +1,51 @@
+// ChaCha20-Poly1305 authenticated encryption (RFC 8439). Data is encrypted
+// and decrypted in place; the 16-byte tag travels separately.
+use super::chacha20::{self, KEY_LEN, NONCE_LEN};
+use super::poly1305::{Poly1305, TAG_LEN};
+use super::CryptoError;
+
+fn tag(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> [u8; TAG_LEN] {
+    // The one-time Poly1305 key is the first half of keystream block 0
+    let block = chacha20::block(key, 0, nonce);
+    let mut mac = Poly1305::new(block[..32].try_into().unwrap());
+    let zeros = [0u8; 16];
+    mac.update(aad);
+    mac.update(&zeros[..(16 - aad.len() % 16) % 16]);
+    mac.update(ciphertext);
+    mac.update(&zeros[..(16 - ciphertext.len() % 16) % 16]);
+    mac.update(&(aad.len() as u64).to_le_bytes());
+    mac.update(&(ciphertext.len() as u64).to_le_bytes());
+    mac.finish()
+}
+
+// Encrypts `data` and returns the tag over it and `aad`. A nonce must never
+// be reused with the same key.
+pub fn seal(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    data: &mut [u8],
+) -> [u8; TAG_LEN] {
+    chacha20::apply_keystream(key, 1, nonce, data);
+    tag(key, nonce, aad, data)
+}
+
+// Checks the tag, then decrypts `data`. On failure `data` is left as it was.
+pub fn open(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    data: &mut [u8],
+    expected: &[u8; TAG_LEN],
+) -> Result<(), CryptoError> {
+    if !super::ct_eq(&tag(key, nonce, aad, data), expected) {
+        return Err(CryptoError::AuthenticationFailed);
+    }
+    chacha20::apply_keystream(key, 1, nonce, data);
+    Ok(())
+}
@@ -0,0 +1,162 @@
+// AES block cipher (FIPS 197) with 128, 192 and 256-bit keys. The software
+// path computes the S-box arithmetically (inversion in GF(2^8) and the affine
+// map) instead of looking it up, so no memory access depends on the data;
+// it is slow, and AES-NI is used whenever the CPU has it. Encryption only:
+// counter-based modes such as CTR and GCM never run the inverse cipher.
+use super::CryptoError;
+use core::arch::x86_64::__cpuid;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+
+pub const BLOCK_LEN: usize = 16;
+
+const MAX_ROUNDS: usize = 14;
+
+pub struct Aes {
+    round_keys: [[u8; BLOCK_LEN]; MAX_ROUNDS + 1],
+    rounds: usize,
+    aesni: bool,
+}
+
+// Multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x + 1, without
+// data-dependent branches
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & 0u8.wrapping_sub(b & 1);
+        a = (a << 1) ^ (0x1b & 0u8.wrapping_sub(a >> 7));
+        b >>= 1;
+    }
+    product
+}
+
+fn sbox(x: u8) -> u8 {
+    // x^254 is the multiplicative inverse, and maps 0 to 0
+    let mut inverse = x;
+    for _ in 0..6 {
+        inverse = gf_mul(gf_mul(inverse, inverse), x);
+    }
+    inverse = gf_mul(inverse, inverse);
+    inverse
+        ^ inverse.rotate_left(1)
+        ^ inverse.rotate_left(2)
+        ^ inverse.rotate_left(3)
+        ^ inverse.rotate_left(4)
+        ^ 0x63
+}
+
+fn xtime(b: u8) -> u8 {
+    (b << 1) ^ (0x1b & 0u8.wrapping_sub(b >> 7))
+}
+
+// AES-NI needs the CPU feature and SSE enabled by the kernel
+fn aesni_available() -> bool {
+    let aes = unsafe { __cpuid(1) }.ecx & (1 << 25) != 0;
+    aes && Cr4::read().contains(Cr4Flags::OSFXSR)
+}
+
+impl Aes {
+    pub fn new(key: &[u8]) -> Result<Self, CryptoError> {
+        let key_words = match key.len() {
+            16 | 24 | 32 => key.len() / 4,
+            _ => return Err(CryptoError::InvalidKeyLength),
+        };
+        let rounds = key_words + 6;
+
+        let mut words = [[0u8; 4]; 4 * (MAX_ROUNDS + 1)];
+        for (word, bytes) in words.iter_mut().zip(key.chunks_exact(4)) {
+            word.copy_from_slice(bytes);
+        }
+        let mut rcon = 1u8;
+        for i in key_words..4 * (rounds + 1) {
+            let mut temp = words[i - 1];
+            if i % key_words == 0 {
+                temp.rotate_left(1);
+                temp = temp.map(sbox);
+                temp[0] ^= rcon;
+                rcon = xtime(rcon);
+            } else if key_words > 6 && i % key_words == 4 {
+                temp = temp.map(sbox);
+            }
+            for j in 0..4 {
+                words[i][j] = words[i - key_words][j] ^ temp[j];
+            }
+        }
+
+        let mut round_keys = [[0u8; BLOCK_LEN]; MAX_ROUNDS + 1];
+        for (round, key) in round_keys.iter_mut().take(rounds + 1).enumerate() {
+            for (column, bytes) in key.chunks_exact_mut(4).enumerate() {
+                bytes.copy_from_slice(&words[round * 4 + column]);
+            }
+        }
+        Ok(Aes {
+            round_keys,
+            rounds,
+            aesni: aesni_available(),
+        })
+    }
+
+    pub fn encrypt_block(&self, block: &mut [u8; BLOCK_LEN]) {
+        if self.aesni {
+            unsafe { self.encrypt_aesni(block) }
+        } else {
+            self.encrypt_soft(block)
+        }
+    }
+
+    fn encrypt_soft(&self, state: &mut [u8; BLOCK_LEN]) {
+        add_round_key(state, &self.round_keys[0]);
+        for round in 1..=self.rounds {
+            for byte in state.iter_mut() {
+                *byte = sbox(*byte);
+            }
+            shift_rows(state);
+            if round != self.rounds {
+                mix_columns(state);
+            }
+            add_round_key(state, &self.round_keys[round]);
+        }
+    }
+
+    // No interrupt handler touches the vector registers, so they need no
+    // saving around this
+    #[target_feature(enable = "aes,sse2")]
+    unsafe fn encrypt_aesni(&self, block: &mut [u8; BLOCK_LEN]) {
+        use core::arch::x86_64::*;
+        let keys = &self.round_keys;
+        let mut state = _mm_loadu_si128(block.as_ptr().cast());
+        state = _mm_xor_si128(state, _mm_loadu_si128(keys[0].as_ptr().cast()));
+        for key in keys[1..self.rounds].iter() {
+            state = _mm_aesenc_si128(state, _mm_loadu_si128(key.as_ptr().cast()));
+        }
+        let last = _mm_loadu_si128(keys[self.rounds].as_ptr().cast());
+        state = _mm_aesenclast_si128(state, last);
+        _mm_storeu_si128(block.as_mut_ptr().cast(), state);
+    }
+}
+
+fn add_round_key(state: &mut [u8; BLOCK_LEN], key: &[u8; BLOCK_LEN]) {
+    for (byte, key) in state.iter_mut().zip(key.iter()) {
+        *byte ^= key;
+    }
+}
+
+// The state is column-major: byte `column * 4 + row`
+fn shift_rows(state: &mut [u8; BLOCK_LEN]) {
+    let old = *state;
+    for column in 0..4 {
+        for row in 1..4 {
+            state[column * 4 + row] = old[((column + row) % 4) * 4 + row];
+        }
+    }
+}
+
+fn mix_columns(state: &mut [u8; BLOCK_LEN]) {
+    for column in state.chunks_exact_mut(4) {
+        let [a0, a1, a2, a3] = [column[0], column[1], column[2], column[3]];
+        let all = a0 ^ a1 ^ a2 ^ a3;
+        column[0] ^= all ^ xtime(a0 ^ a1);
+        column[1] ^= all ^ xtime(a1 ^ a2);
+        column[2] ^= all ^ xtime(a2 ^ a3);
+        column[3] ^= all ^ xtime(a3 ^ a0);
+    }
+}
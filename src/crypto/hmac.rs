@@ -0,0 +1,54 @@
+// HMAC-SHA-256 (RFC 2104)
+use super::sha256::{self, Sha256, BLOCK_LEN, DIGEST_LEN};
+
+#[derive(Clone)]
+pub struct HmacSha256 {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl HmacSha256 {
+    pub fn new(key: &[u8]) -> Self {
+        // Keys longer than a block are hashed first
+        let mut block = [0u8; BLOCK_LEN];
+        if key.len() > BLOCK_LEN {
+            block[..DIGEST_LEN].copy_from_slice(&sha256::digest(key));
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+
+        let mut inner = Sha256::new();
+        let mut outer = Sha256::new();
+        let mut pad = [0u8; BLOCK_LEN];
+        for (pad, byte) in pad.iter_mut().zip(block.iter()) {
+            *pad = byte ^ 0x36;
+        }
+        inner.update(&pad);
+        for (pad, byte) in pad.iter_mut().zip(block.iter()) {
+            *pad = byte ^ 0x5c;
+        }
+        outer.update(&pad);
+        HmacSha256 { inner, outer }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    pub fn finish(self) -> [u8; DIGEST_LEN] {
+        let mut outer = self.outer;
+        outer.update(&self.inner.finish());
+        outer.finish()
+    }
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; DIGEST_LEN] {
+    let mut mac = HmacSha256::new(key);
+    mac.update(data);
+    mac.finish()
+}
+
+// Checks a tag in constant time
+pub fn verify(key: &[u8], data: &[u8], tag: &[u8]) -> bool {
+    super::ct_eq(&hmac_sha256(key, data), tag)
+}
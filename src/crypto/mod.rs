@@ -0,0 +1,123 @@
+// Kernel crypto library: SHA-256, HMAC-SHA-256, AES, ChaCha20-Poly1305 and
+// X25519. Everything is software written to run in constant time (no
+// branches or memory accesses that depend on secret data), except that AES
+// uses AES-NI when the CPU supports it. Meant for TLS, image signature
+// checks and random number expansion as those arrive.
+use crate::serial_println;
+
+pub mod aead;
+pub mod aes;
+pub mod chacha20;
+pub mod hmac;
+pub mod poly1305;
+pub mod sha256;
+pub mod x25519;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoError {
+    InvalidKeyLength,
+    AuthenticationFailed,
+}
+
+// Compares secrets such as MAC tags without an early exit
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+fn unhex<const N: usize>(hex: &str) -> [u8; N] {
+    let mut bytes = [0u8; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        let pair = core::str::from_utf8(pair).unwrap();
+        *byte = u8::from_str_radix(pair, 16).unwrap();
+    }
+    bytes
+}
+
+fn sequence<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = index as u8;
+    }
+    bytes
+}
+
+// Known-answer tests from FIPS 197, RFC 4231 and RFC 7748, plus a
+// ChaCha20-Poly1305 round trip. Run at boot with `selftest=1`.
+pub fn self_test() {
+    let sha256 = sha256::digest(b"abc")
+        == unhex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+    let hmac = hmac::hmac_sha256(b"Jefe", b"what do ya want for nothing?")
+        == unhex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+
+    let aes = [
+        (&sequence::<32>()[..16], "69c4e0d86a7b0430d8cdb78070b4c55a"),
+        (&sequence::<32>()[..24], "dda97ca4864cdfe06eaf70a0ec0d7191"),
+        (&sequence::<32>()[..], "8ea2b7ca516745bfeafc49904b496089"),
+    ]
+    .iter()
+    .all(|(key, expected)| {
+        let mut block = unhex("00112233445566778899aabbccddeeff");
+        match aes::Aes::new(key) {
+            Ok(cipher) => {
+                cipher.encrypt_block(&mut block);
+                block == unhex(expected)
+            }
+            Err(_) => false,
+        }
+    });
+
+    let aead = {
+        let key = sequence::<32>();
+        let nonce = sequence::<12>();
+        let plaintext = *b"The quick brown fox jumps over the lazy dog";
+        let mut data = plaintext;
+        let tag = aead::seal(&key, &nonce, b"hobbyOS", &mut data);
+        let sealed = data
+            == unhex::<43>(
+                "dd936d205862cc23dca35d81f76a6043af1fcac73b01c0c995b740b310b2864884e50c9f8764c8b8535d11",
+            )
+            && tag == unhex("09beb9f1927765fe2f1399ae80c3b8ab");
+        let mut tampered = data;
+        tampered[0] ^= 1;
+        sealed
+            && aead::open(&key, &nonce, b"hobbyOS", &mut tampered, &tag).is_err()
+            && aead::open(&key, &nonce, b"hobbyOS", &mut data, &tag).is_ok()
+            && data == plaintext
+    };
+
+    let x25519 = x25519::public_key(&unhex(
+        "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a",
+    )) == unhex("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+
+    let results = [
+        ("sha-256", sha256),
+        ("hmac-sha-256", hmac),
+        ("aes", aes),
+        ("chacha20-poly1305", aead),
+        ("x25519", x25519),
+    ];
+    for (name, passed) in results.iter() {
+        serial_println!(
+            "selftest: {:<20} {}",
+            name,
+            if *passed { "PASS" } else { "FAIL" }
+        );
+    }
+    let failed = results.iter().filter(|(_, passed)| !passed).count();
+    if failed == 0 {
+        info!("Crypto self-test passed ({} tests)", results.len());
+    } else {
+        error!(
+            "Crypto self-test: {} of {} tests failed",
+            failed,
+            results.len()
+        );
+    }
+}
@@ -0,0 +1,158 @@
+// Poly1305 one-time authenticator (RFC 8439), with the accumulator in five
+// 26-bit limbs
+pub const KEY_LEN: usize = 32;
+pub const TAG_LEN: usize = 16;
+
+const LIMB_MASK: u32 = 0x3ff_ffff;
+
+pub struct Poly1305 {
+    r: [u32; 5],
+    h: [u32; 5],
+    pad: [u32; 4],
+    buffer: [u8; 16],
+    buffered: usize,
+}
+
+fn words(bytes: &[u8]) -> [u32; 4] {
+    let mut words = [0u32; 4];
+    for (word, bytes) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_le_bytes(bytes.try_into().unwrap());
+    }
+    words
+}
+
+impl Poly1305 {
+    // The key must never be used for more than one message
+    pub fn new(key: &[u8; KEY_LEN]) -> Self {
+        let [t0, t1, t2, t3] = words(&key[..16]);
+        // r is clamped as the spec requires
+        let r = [
+            t0 & 0x3ff_ffff,
+            ((t0 >> 26) | (t1 << 6)) & 0x3ff_ff03,
+            ((t1 >> 20) | (t2 << 12)) & 0x3ff_c0ff,
+            ((t2 >> 14) | (t3 << 18)) & 0x3f0_3fff,
+            (t3 >> 8) & 0x00f_ffff,
+        ];
+        Poly1305 {
+            r,
+            h: [0; 5],
+            pad: words(&key[16..]),
+            buffer: [0; 16],
+            buffered: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        if self.buffered > 0 {
+            let take = data.len().min(16 - self.buffered);
+            self.buffer[self.buffered..self.buffered + take].copy_from_slice(&data[..take]);
+            self.buffered += take;
+            data = &data[take..];
+            if self.buffered < 16 {
+                return;
+            }
+            let block = self.buffer;
+            self.block(&block, 1 << 24);
+            self.buffered = 0;
+        }
+        let mut blocks = data.chunks_exact(16);
+        for block in &mut blocks {
+            self.block(block.try_into().unwrap(), 1 << 24);
+        }
+        let rest = blocks.remainder();
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    // Adds a block (with its high bit, 2^128, set when `hibit` is 1 << 24)
+    // and multiplies by r modulo 2^130 - 5
+    fn block(&mut self, block: &[u8; 16], hibit: u32) {
+        let [t0, t1, t2, t3] = words(block);
+        let [r0, r1, r2, r3, r4] = self.r.map(|r| r as u64);
+        let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+
+        let h = &mut self.h;
+        h[0] += t0 & LIMB_MASK;
+        h[1] += ((t0 >> 26) | (t1 << 6)) & LIMB_MASK;
+        h[2] += ((t1 >> 20) | (t2 << 12)) & LIMB_MASK;
+        h[3] += ((t2 >> 14) | (t3 << 18)) & LIMB_MASK;
+        h[4] += (t3 >> 8) | hibit;
+        let [h0, h1, h2, h3, h4] = h.map(|h| h as u64);
+
+        let d0 = h0 * r0 + h1 * s4 + h2 * s3 + h3 * s2 + h4 * s1;
+        let mut d1 = h0 * r1 + h1 * r0 + h2 * s4 + h3 * s3 + h4 * s2;
+        let mut d2 = h0 * r2 + h1 * r1 + h2 * r0 + h3 * s4 + h4 * s3;
+        let mut d3 = h0 * r3 + h1 * r2 + h2 * r1 + h3 * r0 + h4 * s4;
+        let mut d4 = h0 * r4 + h1 * r3 + h2 * r2 + h3 * r1 + h4 * r0;
+
+        d1 += d0 >> 26;
+        d2 += d1 >> 26;
+        d3 += d2 >> 26;
+        d4 += d3 >> 26;
+        h[0] = d0 as u32 & LIMB_MASK;
+        h[1] = d1 as u32 & LIMB_MASK;
+        h[2] = d2 as u32 & LIMB_MASK;
+        h[3] = d3 as u32 & LIMB_MASK;
+        h[4] = d4 as u32 & LIMB_MASK;
+        h[0] += (d4 >> 26) as u32 * 5;
+        h[1] += h[0] >> 26;
+        h[0] &= LIMB_MASK;
+    }
+
+    pub fn finish(mut self) -> [u8; TAG_LEN] {
+        if self.buffered > 0 {
+            // A short final block is padded with a 1 byte instead of the
+            // high bit
+            let mut block = [0u8; 16];
+            block[..self.buffered].copy_from_slice(&self.buffer[..self.buffered]);
+            block[self.buffered] = 1;
+            self.block(&block, 0);
+        }
+
+        let mut h = self.h;
+        for i in 1..5 {
+            h[i] += h[i - 1] >> 26;
+            h[i - 1] &= LIMB_MASK;
+        }
+        h[0] += (h[4] >> 26) * 5;
+        h[4] &= LIMB_MASK;
+        h[1] += h[0] >> 26;
+        h[0] &= LIMB_MASK;
+
+        // g = h - p; keep it instead of h if it did not go negative
+        let mut g = [0u32; 5];
+        let mut carry = 5;
+        for i in 0..4 {
+            g[i] = h[i] + carry;
+            carry = g[i] >> 26;
+            g[i] &= LIMB_MASK;
+        }
+        g[4] = h[4].wrapping_add(carry).wrapping_sub(1 << 26);
+        let keep_g = (g[4] >> 31).wrapping_sub(1);
+        for i in 0..5 {
+            h[i] = (h[i] & !keep_g) | (g[i] & keep_g);
+        }
+
+        // h mod 2^128, plus the pad
+        let h = [
+            h[0] | (h[1] << 26),
+            (h[1] >> 6) | (h[2] << 20),
+            (h[2] >> 12) | (h[3] << 14),
+            (h[3] >> 18) | (h[4] << 8),
+        ];
+        let mut tag = [0u8; TAG_LEN];
+        let mut carry = 0u64;
+        for i in 0..4 {
+            let sum = h[i] as u64 + self.pad[i] as u64 + carry;
+            tag[i * 4..i * 4 + 4].copy_from_slice(&(sum as u32).to_le_bytes());
+            carry = sum >> 32;
+        }
+        tag
+    }
+}
+
+pub fn authenticate(key: &[u8; KEY_LEN], message: &[u8]) -> [u8; TAG_LEN] {
+    let mut mac = Poly1305::new(key);
+    mac.update(message);
+    mac.finish()
+}
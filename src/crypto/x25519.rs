@@ -0,0 +1,206 @@
+// X25519 Diffie-Hellman (RFC 7748). Field elements mod 2^255 - 19 are kept in
+// five 51-bit limbs; the Montgomery ladder swaps with masks, not branches.
+pub const KEY_LEN: usize = 32;
+
+const BASE_POINT: [u8; KEY_LEN] = {
+    let mut point = [0u8; KEY_LEN];
+    point[0] = 9;
+    point
+};
+
+const MASK: u64 = (1 << 51) - 1;
+
+type Fe = [u64; 5];
+
+fn load64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+// Reads a u-coordinate, ignoring the top bit as the spec requires
+fn from_bytes(bytes: &[u8; KEY_LEN]) -> Fe {
+    [
+        load64(bytes, 0) & MASK,
+        (load64(bytes, 6) >> 3) & MASK,
+        (load64(bytes, 12) >> 6) & MASK,
+        (load64(bytes, 19) >> 1) & MASK,
+        (load64(bytes, 24) >> 12) & MASK,
+    ]
+}
+
+fn to_bytes(a: &Fe) -> [u8; KEY_LEN] {
+    let mut t = carry(*a);
+    t = carry(t);
+    // Subtract p if t >= p, found by whether t + 19 overflows 2^255
+    let mut q = (t[0] + 19) >> 51;
+    for limb in t.iter().skip(1) {
+        q = (limb + q) >> 51;
+    }
+    t[0] += 19 * q;
+    for i in 0..4 {
+        t[i + 1] += t[i] >> 51;
+        t[i] &= MASK;
+    }
+    t[4] &= MASK;
+
+    let words = [
+        t[0] | t[1] << 51,
+        t[1] >> 13 | t[2] << 38,
+        t[2] >> 26 | t[3] << 25,
+        t[3] >> 39 | t[4] << 12,
+    ];
+    let mut bytes = [0u8; KEY_LEN];
+    for (chunk, word) in bytes.chunks_exact_mut(8).zip(words.iter()) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    bytes
+}
+
+fn carry(mut a: Fe) -> Fe {
+    for i in 0..4 {
+        a[i + 1] += a[i] >> 51;
+        a[i] &= MASK;
+    }
+    a[0] += 19 * (a[4] >> 51);
+    a[4] &= MASK;
+    a
+}
+
+fn add(a: &Fe, b: &Fe) -> Fe {
+    carry([
+        a[0] + b[0],
+        a[1] + b[1],
+        a[2] + b[2],
+        a[3] + b[3],
+        a[4] + b[4],
+    ])
+}
+
+// Adds 4p first so no limb underflows
+fn sub(a: &Fe, b: &Fe) -> Fe {
+    carry([
+        a[0] + 0x1f_ffff_ffff_ffb4 - b[0],
+        a[1] + 0x1f_ffff_ffff_fffc - b[1],
+        a[2] + 0x1f_ffff_ffff_fffc - b[2],
+        a[3] + 0x1f_ffff_ffff_fffc - b[3],
+        a[4] + 0x1f_ffff_ffff_fffc - b[4],
+    ])
+}
+
+fn mul(a: &Fe, b: &Fe) -> Fe {
+    let m = |x: u64, y: u64| x as u128 * y as u128;
+    let [a0, a1, a2, a3, a4] = *a;
+    let [b0, b1, b2, b3, b4] = *b;
+    let (b1_19, b2_19, b3_19, b4_19) = (b1 * 19, b2 * 19, b3 * 19, b4 * 19);
+
+    let r0 = m(a0, b0) + m(a1, b4_19) + m(a2, b3_19) + m(a3, b2_19) + m(a4, b1_19);
+    let mut r1 = m(a0, b1) + m(a1, b0) + m(a2, b4_19) + m(a3, b3_19) + m(a4, b2_19);
+    let mut r2 = m(a0, b2) + m(a1, b1) + m(a2, b0) + m(a3, b4_19) + m(a4, b3_19);
+    let mut r3 = m(a0, b3) + m(a1, b2) + m(a2, b1) + m(a3, b0) + m(a4, b4_19);
+    let mut r4 = m(a0, b4) + m(a1, b3) + m(a2, b2) + m(a3, b1) + m(a4, b0);
+
+    r1 += r0 >> 51;
+    r2 += r1 >> 51;
+    r3 += r2 >> 51;
+    r4 += r3 >> 51;
+    let mut out = [
+        r0 as u64 & MASK,
+        r1 as u64 & MASK,
+        r2 as u64 & MASK,
+        r3 as u64 & MASK,
+        r4 as u64 & MASK,
+    ];
+    out[0] += (r4 >> 51) as u64 * 19;
+    out[1] += out[0] >> 51;
+    out[0] &= MASK;
+    out
+}
+
+fn square(a: &Fe) -> Fe {
+    mul(a, a)
+}
+
+fn mul_small(a: &Fe, n: u64) -> Fe {
+    let mut out = [0u64; 5];
+    let mut c: u128 = 0;
+    for i in 0..5 {
+        let t = a[i] as u128 * n as u128 + c;
+        out[i] = t as u64 & MASK;
+        c = t >> 51;
+    }
+    out[0] += c as u64 * 19;
+    carry(out)
+}
+
+// a^(p - 2); the exponent is public, so branching on its bits is fine
+fn invert(a: &Fe) -> Fe {
+    // p - 2 = 2^255 - 21: bits 254..5 set, then 01011
+    let mut result = [1, 0, 0, 0, 0];
+    for bit in (0..255).rev() {
+        result = square(&result);
+        if bit >= 5 || (0b01011 >> bit) & 1 == 1 {
+            result = mul(&result, a);
+        }
+    }
+    result
+}
+
+fn cswap(swap: u64, a: &mut Fe, b: &mut Fe) {
+    let mask = 0u64.wrapping_sub(swap);
+    for i in 0..5 {
+        let t = mask & (a[i] ^ b[i]);
+        a[i] ^= t;
+        b[i] ^= t;
+    }
+}
+
+pub fn x25519(scalar: &[u8; KEY_LEN], point: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+    let mut k = *scalar;
+    k[0] &= 248;
+    k[31] &= 127;
+    k[31] |= 64;
+
+    let x1 = from_bytes(point);
+    let mut x2: Fe = [1, 0, 0, 0, 0];
+    let mut z2: Fe = [0; 5];
+    let mut x3 = x1;
+    let mut z3: Fe = [1, 0, 0, 0, 0];
+    let mut swap = 0u64;
+
+    for t in (0..255).rev() {
+        let bit = ((k[t / 8] >> (t % 8)) & 1) as u64;
+        swap ^= bit;
+        cswap(swap, &mut x2, &mut x3);
+        cswap(swap, &mut z2, &mut z3);
+        swap = bit;
+
+        let a = add(&x2, &z2);
+        let aa = square(&a);
+        let b = sub(&x2, &z2);
+        let bb = square(&b);
+        let e = sub(&aa, &bb);
+        let c = add(&x3, &z3);
+        let d = sub(&x3, &z3);
+        let da = mul(&d, &a);
+        let cb = mul(&c, &b);
+        x3 = square(&add(&da, &cb));
+        z3 = mul(&x1, &square(&sub(&da, &cb)));
+        x2 = mul(&aa, &bb);
+        z2 = mul(&e, &add(&aa, &mul_small(&e, 121_665)));
+    }
+    cswap(swap, &mut x2, &mut x3);
+    cswap(swap, &mut z2, &mut z3);
+
+    to_bytes(&mul(&x2, &invert(&z2)))
+}
+
+pub fn public_key(secret: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+    x25519(secret, &BASE_POINT)
+}
+
+// The shared secret, or None if the peer sent a low-order point and the
+// result is all zeros
+pub fn shared_secret(secret: &[u8; KEY_LEN], peer: &[u8; KEY_LEN]) -> Option<[u8; KEY_LEN]> {
+    let shared = x25519(secret, peer);
+    let zero = shared.iter().fold(0u8, |acc, byte| acc | byte) == 0;
+    (!zero).then_some(shared)
+}
@@ -0,0 +1,272 @@
+// Debug heap: wraps the kernel heap with redzones around every allocation and
+// poisons freed memory, so overflows and double frees are caught on free.
+// Freed blocks sit in a short quarantine before going back to the allocator,
+// which would otherwise overwrite the header with its free list, and their
+// poison is checked on the way out to catch writes after free.
+// With the `kasan` feature it also keeps the heap shadow up to date, so
+// checked accesses catch them at the access.
+use crate::crash;
+use crate::interrupts;
+#[cfg(feature = "kasan")]
+use crate::kasan;
+use crate::sync::{SpinLock, SpinLockGuard};
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::{align_of, size_of};
+use core::ops::Deref;
+use core::ptr;
+use linked_list_allocator::LockedHeap;
+
+const REDZONE: usize = 16;
+const REDZONE_BYTE: u8 = 0xFD;
+const ALLOC_BYTE: u8 = 0xA5;
+const POISON_BYTE: u8 = 0x6B;
+
+const LIVE_MAGIC: u64 = 0xA110_CA7E_D0D0_CAFE;
+const FREED_MAGIC: u64 = 0xF4EE_D0D0_DEAD_BEEF;
+
+pub const BACKTRACE_DEPTH: usize = 4;
+
+const QUARANTINE: usize = 64;
+
+// Sits right below the front redzone of each allocation
+#[repr(C)]
+pub struct Header {
+    magic: u64,
+    pub size: usize,
+    // Allocation sequence number, used as the block's age
+    pub seq: u64,
+    pub prev: *mut Header,
+    pub next: *mut Header,
+    // Scratch state for the leak scanner
+    pub mark: u8,
+    pub backtrace: [u64; BACKTRACE_DEPTH],
+}
+
+impl Header {
+    pub fn start(&self) -> usize {
+        self as *const Header as usize + size_of::<Header>() + REDZONE
+    }
+}
+
+// Every live allocation, linked through its header
+pub struct LiveList {
+    head: *mut Header,
+    next_seq: u64,
+}
+
+unsafe impl Send for LiveList {}
+
+impl LiveList {
+    unsafe fn insert(&mut self, header: *mut Header) {
+        (*header).seq = self.next_seq;
+        (*header).prev = ptr::null_mut();
+        (*header).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = header;
+        }
+        self.head = header;
+        self.next_seq += 1;
+    }
+
+    unsafe fn remove(&mut self, header: *mut Header) {
+        let (prev, next) = ((*header).prev, (*header).next);
+        if prev.is_null() {
+            self.head = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+    }
+
+    pub fn head(&self) -> *mut Header {
+        self.head
+    }
+
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+}
+
+static LIVE: SpinLock<LiveList> = SpinLock::new(
+    "debug_heap_live",
+    LiveList {
+        head: ptr::null_mut(),
+        next_seq: 0,
+    },
+);
+
+// Locks the live allocation list. The heap must not be used while the guard
+// is held, and interrupts should be disabled by the caller.
+#[track_caller]
+pub fn live() -> SpinLockGuard<'static, LiveList> {
+    LIVE.lock()
+}
+
+// A freed block that has not been handed back to the allocator yet
+#[derive(Clone, Copy)]
+struct Quarantined {
+    base: *mut u8,
+    outer: Layout,
+    prefix: usize,
+    size: usize,
+}
+
+struct Quarantine {
+    blocks: [Option<Quarantined>; QUARANTINE],
+    next: usize,
+}
+
+unsafe impl Send for Quarantine {}
+
+static QUARANTINED: SpinLock<Quarantine> = SpinLock::new(
+    "debug_heap_quarantine",
+    Quarantine {
+        blocks: [None; QUARANTINE],
+        next: 0,
+    },
+);
+
+pub struct DebugHeap {
+    heap: LockedHeap,
+}
+
+impl DebugHeap {
+    pub const fn empty() -> Self {
+        DebugHeap {
+            heap: LockedHeap::empty(),
+        }
+    }
+}
+
+impl Deref for DebugHeap {
+    type Target = LockedHeap;
+
+    fn deref(&self) -> &LockedHeap {
+        &self.heap
+    }
+}
+
+// Returns the layout of the underlying block and the offset of the user
+// pointer within it
+fn outer_layout(layout: Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(align_of::<Header>());
+    let prefix = (size_of::<Header>() + REDZONE + align - 1) & !(align - 1);
+    let size = prefix.checked_add(layout.size())?.checked_add(REDZONE)?;
+    let outer = Layout::from_size_align(size, align).ok()?;
+    Some((outer, prefix))
+}
+
+unsafe fn header_of(user: *mut u8) -> *mut Header {
+    user.sub(REDZONE + size_of::<Header>()) as *mut Header
+}
+
+fn corruption(what: &str, addr: *const u8, header: &Header) -> ! {
+    panic!(
+        "heap corruption: {} at {:p} (allocation of {} bytes, allocated from {:#x?})",
+        what, addr, header.size, header.backtrace
+    );
+}
+
+unsafe fn check_redzone(start: *const u8, header: &Header, what: &str) {
+    for offset in 0..REDZONE {
+        let byte = start.add(offset);
+        if *byte != REDZONE_BYTE {
+            corruption(what, byte, header);
+        }
+    }
+}
+
+// Checks that a block leaving quarantine still holds only poison
+unsafe fn check_poison(block: &Quarantined) {
+    let user = block.base.add(block.prefix);
+    let header = &*header_of(user);
+    if header.magic != FREED_MAGIC {
+        corruption("smashed header after free", user, header);
+    }
+    for offset in 0..block.size {
+        let byte = user.add(offset);
+        if *byte != POISON_BYTE {
+            corruption("write after free", byte, header);
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for DebugHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (outer, prefix) = match outer_layout(layout) {
+            Some(outer) => outer,
+            None => return ptr::null_mut(),
+        };
+        let base = self.heap.alloc(outer);
+        if base.is_null() {
+            return base;
+        }
+
+        let user = base.add(prefix);
+        let header = header_of(user);
+        let mut backtrace = [0; BACKTRACE_DEPTH];
+        crash::backtrace(&mut backtrace);
+        header.write(Header {
+            magic: LIVE_MAGIC,
+            size: layout.size(),
+            seq: 0,
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            mark: 0,
+            backtrace,
+        });
+        interrupts::without_interrupts(|| LIVE.lock().insert(header));
+        ptr::write_bytes(user.sub(REDZONE), REDZONE_BYTE, REDZONE);
+        ptr::write_bytes(user, ALLOC_BYTE, layout.size());
+        ptr::write_bytes(user.add(layout.size()), REDZONE_BYTE, REDZONE);
+        #[cfg(feature = "kasan")]
+        {
+            kasan::poison(base, prefix, kasan::REDZONE);
+            kasan::unpoison(user, layout.size());
+            kasan::poison(user.add(layout.size()), REDZONE, kasan::REDZONE);
+        }
+        user
+    }
+
+    unsafe fn dealloc(&self, user: *mut u8, layout: Layout) {
+        let (outer, prefix) = outer_layout(layout).expect("dealloc with invalid layout");
+        let header = &mut *header_of(user);
+
+        match header.magic {
+            LIVE_MAGIC => {}
+            FREED_MAGIC => corruption("double free", user, header),
+            _ => corruption("free of unknown pointer or smashed header", user, header),
+        }
+        if header.size != layout.size() {
+            corruption("free with mismatched size", user, header);
+        }
+        check_redzone(user.sub(REDZONE), header, "buffer underflow");
+        check_redzone(user.add(layout.size()), header, "buffer overflow");
+
+        let header_ptr: *mut Header = header;
+        interrupts::without_interrupts(|| LIVE.lock().remove(header_ptr));
+        header.magic = FREED_MAGIC;
+        ptr::write_bytes(user, POISON_BYTE, layout.size());
+        #[cfg(feature = "kasan")]
+        kasan::poison(user.sub(prefix), outer.size(), kasan::FREED);
+
+        let block = Quarantined {
+            base: user.sub(prefix),
+            outer,
+            prefix,
+            size: layout.size(),
+        };
+        let evicted = interrupts::without_interrupts(|| {
+            let mut quarantine = QUARANTINED.lock();
+            let slot = quarantine.next;
+            quarantine.next = (slot + 1) % QUARANTINE;
+            quarantine.blocks[slot].replace(block)
+        });
+        if let Some(evicted) = evicted {
+            check_poison(&evicted);
+            self.heap.dealloc(evicted.base, evicted.outer);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// Device model. Bus drivers give every device they find a node here, linked
+// to the device it hangs off (a USB device to its host controller's PCI
+// function). Nodes are shared through Arc, so a driver may keep one after the
+// device is unplugged and check `is_present`. Listeners are told about
+// devices coming and going; removing a node removes its children first.
+use crate::sync::SpinLock;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+pub type DeviceId = u32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bus {
+    Pci,
+    Usb,
+}
+
+pub struct Device {
+    pub id: DeviceId,
+    pub name: String,
+    pub bus: Bus,
+    pub parent: Option<DeviceId>,
+    driver: SpinLock<Option<&'static str>>,
+    present: AtomicBool,
+}
+
+pub enum Event {
+    Added(Arc<Device>),
+    Removed(Arc<Device>),
+}
+
+pub type Listener = fn(&Event);
+
+static DEVICES: SpinLock<Vec<Arc<Device>>> = SpinLock::new("devices", Vec::new());
+static LISTENERS: SpinLock<Vec<Listener>> = SpinLock::new("device_listeners", Vec::new());
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+impl Device {
+    pub fn driver(&self) -> Option<&'static str> {
+        *self.driver.lock()
+    }
+
+    // False once the device has been removed
+    pub fn is_present(&self) -> bool {
+        self.present.load(Ordering::Acquire)
+    }
+}
+
+// Listeners run without any device model lock held, so they may look devices
+// up. Devices already present are replayed as Added events.
+pub fn subscribe(listener: Listener) {
+    LISTENERS.lock().push(listener);
+    for device in devices() {
+        listener(&Event::Added(device));
+    }
+}
+
+fn notify(event: Event) {
+    let listeners = LISTENERS.lock().clone();
+    for listener in listeners.iter() {
+        listener(&event);
+    }
+}
+
+pub fn add(name: String, bus: Bus, parent: Option<DeviceId>) -> Arc<Device> {
+    let device = Arc::new(Device {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        name,
+        bus,
+        parent,
+        driver: SpinLock::new("device_driver", None),
+        present: AtomicBool::new(true),
+    });
+    DEVICES.lock().push(device.clone());
+    notify(Event::Added(device.clone()));
+    device
+}
+
+// Records which driver took a device
+pub fn bind(id: DeviceId, driver: &'static str) {
+    if let Some(device) = find(id) {
+        *device.driver.lock() = Some(driver);
+    }
+}
+
+// Removes a device and, before it, everything below it. The bus driver must
+// have detached the device's driver already.
+pub fn remove(id: DeviceId) {
+    for child in children(id) {
+        remove(child.id);
+    }
+    let device = {
+        let mut devices = DEVICES.lock();
+        match devices.iter().position(|device| device.id == id) {
+            Some(index) => devices.remove(index),
+            None => return,
+        }
+    };
+    device.present.store(false, Ordering::Release);
+    *device.driver.lock() = None;
+    notify(Event::Removed(device));
+}
+
+pub fn find(id: DeviceId) -> Option<Arc<Device>> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|device| device.id == id)
+        .cloned()
+}
+
+pub fn children(id: DeviceId) -> Vec<Arc<Device>> {
+    DEVICES
+        .lock()
+        .iter()
+        .filter(|device| device.parent == Some(id))
+        .cloned()
+        .collect()
+}
+
+pub fn devices() -> Vec<Arc<Device>> {
+    DEVICES.lock().clone()
+}
@@ -0,0 +1,827 @@
+// FAT32 consistency checker. Walks the directory tree from the root, claiming
+// every cluster chain it reaches, and reports:
+//
+// - chains that run into a cluster another file already claimed (cross-links)
+// - chains with a link out of range, to a free cluster or to a bad one
+// - file sizes that do not match the chain length
+// - malformed directory entries, and "." and ".." entries that point elsewhere
+// - allocated clusters nothing reaches (lost clusters)
+// - a free cluster count in FSInfo that does not match the FAT
+//
+// In repair mode chains are cut at the first bad link, sizes are fitted to
+// the chain, entries that cannot be fixed are deleted, lost clusters are
+// freed and the volume is marked clean. FAT updates go to every copy.
+//
+// At boot, `fsck=<device>` checks that device, and any other device holding a
+// FAT32 volume with its dirty flag set is checked as well; `fsck.repair=1`
+// repairs what is found. The volume may sit on the whole device or in the
+// first FAT32 partition of an MBR.
+//
+// `self_test` builds a damaged volume on `ram0` and checks that it is found
+// and repaired; it runs with `selftest=1` and needs `ramdisk=33M` or more.
+use crate::block::{self, BlockDevice, BlockError};
+use crate::{cmdline, serial_println};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+// The claimed-cluster bitmap has to fit in the heap
+const MAX_CLUSTERS: u32 = 256 * 1024;
+const MIN_FAT32_CLUSTERS: u64 = 65525;
+
+const FAT_MASK: u32 = 0x0FFF_FFFF;
+const FAT_BAD: u32 = 0x0FFF_FFF7;
+const FAT_END: u32 = 0x0FFF_FFF8;
+// In FAT entry 1; clear while the volume is mounted or after an I/O error
+const FAT_CLEAN: u32 = 0x0800_0000;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xE5;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const ATTR_RESERVED: u8 = 0xC0;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_FREE_COUNT: usize = 488;
+
+const MBR_PARTITIONS: usize = 446;
+const PARTITION_FAT32: u8 = 0x0B;
+const PARTITION_FAT32_LBA: u8 = 0x0C;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsckError {
+    Io(BlockError),
+    NotFat32,
+    // More clusters than the checker has memory to track
+    TooLarge,
+    // The root directory chain itself is unusable
+    BadRoot,
+    // The partition does not start on a device block boundary
+    Unaligned,
+}
+
+impl From<BlockError> for FsckError {
+    fn from(err: BlockError) -> Self {
+        FsckError::Io(err)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Report {
+    pub problems: usize,
+    pub repaired: bool,
+    pub lost_clusters: u32,
+    pub free_clusters: u32,
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+fn set_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+struct Volume<'a> {
+    device: &'a dyn BlockDevice,
+    // Device block of the volume's first sector
+    start: u64,
+    blocks_per_sector: u64,
+    sector_size: usize,
+    sectors_per_cluster: u64,
+    reserved_sectors: u64,
+    fats: u64,
+    fat_sectors: u64,
+    data_start: u64,
+    clusters: u32,
+    root: u32,
+    fs_info: u64,
+    // One sector of the first FAT
+    fat_cache: Vec<u8>,
+    fat_cached: Option<u64>,
+}
+
+impl<'a> Volume<'a> {
+    fn open(device: &'a dyn BlockDevice) -> Result<Self, FsckError> {
+        let block_size = device.block_size();
+        if block_size < 512 {
+            return Err(FsckError::NotFat32);
+        }
+        let mut buf = vec![0; block_size];
+        device.read_blocks(0, &mut buf)?;
+        let offset = match Self::parse(device, 0, &buf) {
+            Some(volume) => return Ok(volume),
+            None => Self::partition(&buf).ok_or(FsckError::NotFat32)? * 512,
+        };
+        if offset % block_size as u64 != 0 {
+            return Err(FsckError::Unaligned);
+        }
+        let start = offset / block_size as u64;
+        device.read_blocks(start, &mut buf)?;
+        Self::parse(device, start, &buf).ok_or(FsckError::NotFat32)
+    }
+
+    // Start of the first FAT32 partition, in 512-byte sectors
+    fn partition(mbr: &[u8]) -> Option<u64> {
+        if mbr[510..512] != [0x55, 0xAA] {
+            return None;
+        }
+        (0..4).find_map(|index| {
+            let entry = &mbr[MBR_PARTITIONS + index * 16..][..16];
+            let lba = u32_at(entry, 8) as u64;
+            matches!(entry[4], PARTITION_FAT32 | PARTITION_FAT32_LBA)
+                .then_some(lba)
+                .filter(|&lba| lba != 0)
+        })
+    }
+
+    fn parse(device: &'a dyn BlockDevice, start: u64, boot: &[u8]) -> Option<Self> {
+        let sector_size = u16_at(boot, 11) as usize;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved_sectors = u16_at(boot, 14) as u64;
+        let fats = boot[16] as u64;
+        let total = match u16_at(boot, 19) {
+            0 => u32_at(boot, 32) as u64,
+            total => total as u64,
+        };
+        let fat_sectors = u32_at(boot, 36) as u64;
+        let valid = boot[510..512] == [0x55, 0xAA]
+            && matches!(sector_size, 512 | 1024 | 2048 | 4096)
+            && sector_size >= device.block_size()
+            && sectors_per_cluster.is_power_of_two()
+            && reserved_sectors > 0
+            && fats > 0
+            && u16_at(boot, 17) == 0
+            && u16_at(boot, 22) == 0
+            && fat_sectors > 0;
+        if !valid {
+            return None;
+        }
+        let data_start = reserved_sectors + fats * fat_sectors;
+        let clusters = total.checked_sub(data_start)? / sectors_per_cluster;
+        // Entries past the FAT's end cannot be looked up
+        let clusters = clusters.min(fat_sectors * sector_size as u64 / 4 - 2);
+        if clusters < MIN_FAT32_CLUSTERS {
+            return None;
+        }
+        Some(Volume {
+            device,
+            start,
+            blocks_per_sector: (sector_size / device.block_size()) as u64,
+            sector_size,
+            sectors_per_cluster,
+            reserved_sectors,
+            fats,
+            fat_sectors,
+            data_start,
+            clusters: clusters.min(u32::MAX as u64) as u32,
+            root: u32_at(boot, 44),
+            fs_info: u16_at(boot, 48) as u64,
+            fat_cache: vec![0; sector_size],
+            fat_cached: None,
+        })
+    }
+
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), FsckError> {
+        let lba = self.start + sector * self.blocks_per_sector;
+        Ok(self.device.read_blocks(lba, buf)?)
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), FsckError> {
+        let lba = self.start + sector * self.blocks_per_sector;
+        Ok(self.device.write_blocks(lba, buf)?)
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.sectors_per_cluster
+    }
+
+    // Clusters 2 and up hold data
+    fn in_range(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster - 2 < self.clusters
+    }
+
+    fn fat_position(&self, cluster: u32) -> (u64, usize) {
+        let offset = cluster as u64 * 4;
+        (
+            self.reserved_sectors + offset / self.sector_size as u64,
+            (offset % self.sector_size as u64) as usize,
+        )
+    }
+
+    fn raw_entry(&mut self, cluster: u32) -> Result<u32, FsckError> {
+        let (sector, offset) = self.fat_position(cluster);
+        if self.fat_cached != Some(sector) {
+            let mut cache = core::mem::take(&mut self.fat_cache);
+            let result = self.read_sector(sector, &mut cache);
+            self.fat_cache = cache;
+            self.fat_cached = result.is_ok().then_some(sector);
+            result?;
+        }
+        Ok(u32_at(&self.fat_cache, offset))
+    }
+
+    fn entry(&mut self, cluster: u32) -> Result<u32, FsckError> {
+        Ok(self.raw_entry(cluster)? & FAT_MASK)
+    }
+
+    // Writes the low 28 bits to every FAT copy, keeping the reserved top four
+    fn set_entry(&mut self, cluster: u32, value: u32) -> Result<(), FsckError> {
+        let (sector, offset) = self.fat_position(cluster);
+        let mut buf = vec![0; self.sector_size];
+        for fat in 0..self.fats {
+            let sector = sector + fat * self.fat_sectors;
+            self.read_sector(sector, &mut buf)?;
+            let old = u32_at(&buf, offset);
+            set_u32(&mut buf, offset, old & !FAT_MASK | value & FAT_MASK);
+            self.write_sector(sector, &buf)?;
+        }
+        self.fat_cached = None;
+        Ok(())
+    }
+
+    fn dirty(&mut self) -> Result<bool, FsckError> {
+        Ok(self.entry(1)? & FAT_CLEAN == 0)
+    }
+}
+
+// Clusters handed out so far, one bit each
+struct Claimed(Vec<u64>);
+
+impl Claimed {
+    fn get(&self, cluster: u32) -> bool {
+        self.0[cluster as usize / 64] & (1 << (cluster % 64)) != 0
+    }
+
+    fn set(&mut self, cluster: u32) {
+        self.0[cluster as usize / 64] |= 1 << (cluster % 64);
+    }
+}
+
+struct Checker<'a> {
+    volume: Volume<'a>,
+    claimed: Claimed,
+    repair: bool,
+    report: Report,
+}
+
+struct Directory {
+    first: u32,
+    // Length of the chain as claimed, which may stop short of where the FAT
+    // leads when not repairing
+    clusters: u64,
+    // 0 for the root
+    parent: u32,
+    path: String,
+}
+
+// Short name as it would be written, for messages
+fn display_name(name: &[u8]) -> String {
+    let base = core::str::from_utf8(&name[..8]).unwrap_or("?").trim_end();
+    let extension = core::str::from_utf8(&name[8..11]).unwrap_or("?").trim_end();
+    let mut display = String::from(base);
+    if !extension.is_empty() {
+        display.push('.');
+        display.push_str(extension);
+    }
+    display
+}
+
+fn valid_name(name: &[u8]) -> bool {
+    const INVALID: &[u8] = b"\"*+,./:;<=>?[\\]|";
+    // 0x05 stands for a leading 0xE5
+    name[0] != b' '
+        && name.iter().enumerate().all(|(index, &byte)| {
+            (byte >= 0x20 || (index == 0 && byte == 0x05)) && !INVALID.contains(&byte)
+        })
+}
+
+impl<'a> Checker<'a> {
+    fn problem(&mut self, args: fmt::Arguments) {
+        warn!("fsck: {}: {}", self.volume.device.name(), args);
+        self.report.problems += 1;
+    }
+
+    // Claims the chain starting at `first`, which the caller has checked is
+    // in range, and returns its length in clusters. A chain that ends early,
+    // links somewhere invalid or runs past `limit` clusters is cut there in
+    // repair mode. None if `first` is already someone else's.
+    fn claim(
+        &mut self,
+        first: u32,
+        limit: Option<u64>,
+        path: &str,
+    ) -> Result<Option<u64>, FsckError> {
+        if self.claimed.get(first) {
+            self.problem(format_args!(
+                "{} is cross-linked at cluster {}",
+                path, first
+            ));
+            return Ok(None);
+        }
+        let mut cluster = first;
+        let mut length = 0;
+        loop {
+            self.claimed.set(cluster);
+            length += 1;
+            let next = self.volume.entry(cluster)?;
+            if next >= FAT_END {
+                break;
+            }
+            let reason = if limit == Some(length) {
+                Some("runs past the end of the file")
+            } else if next == FAT_BAD {
+                Some("links to a bad cluster")
+            } else if !self.volume.in_range(next) {
+                Some("has an invalid link")
+            } else if self.claimed.get(next) {
+                Some("is cross-linked")
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                self.problem(format_args!("{} {} at cluster {}", path, reason, cluster));
+                if self.repair {
+                    self.volume.set_entry(cluster, FAT_END | 0xF)?;
+                }
+                break;
+            }
+            cluster = next;
+        }
+        Ok(Some(length))
+    }
+
+    fn check(&mut self) -> Result<(), FsckError> {
+        let root = self.volume.root;
+        if !self.volume.in_range(root) {
+            return Err(FsckError::BadRoot);
+        }
+        let clusters = self.claim(root, None, "/")?.ok_or(FsckError::BadRoot)?;
+        let mut pending = vec![Directory {
+            first: root,
+            clusters,
+            parent: 0,
+            path: String::from(""),
+        }];
+        while let Some(directory) = pending.pop() {
+            self.walk(&directory, &mut pending)?;
+        }
+        self.lost_clusters()?;
+        self.fs_info()?;
+        if self.repair && self.volume.dirty()? {
+            let flags = self.volume.raw_entry(1)?;
+            self.volume.set_entry(1, flags | FAT_CLEAN)?;
+        }
+        Ok(())
+    }
+
+    // Checks every entry in a directory, claiming the chains they point to
+    // and queueing subdirectories
+    fn walk(
+        &mut self,
+        directory: &Directory,
+        pending: &mut Vec<Directory>,
+    ) -> Result<(), FsckError> {
+        let is_root = directory.first == self.volume.root;
+        let mut buf = vec![0; self.volume.sector_size];
+        let mut cluster = directory.first;
+        let mut index = 0;
+        for position in 0..directory.clusters {
+            if position > 0 {
+                cluster = self.volume.entry(cluster)?;
+            }
+            for offset in 0..self.volume.sectors_per_cluster {
+                let sector = self.volume.cluster_sector(cluster) + offset;
+                self.volume.read_sector(sector, &mut buf)?;
+                let mut changed = false;
+                let mut end = false;
+                for start in (0..buf.len()).step_by(ENTRY_SIZE) {
+                    let entry = &mut buf[start..start + ENTRY_SIZE];
+                    if entry[0] == ENTRY_END {
+                        end = true;
+                        break;
+                    }
+                    index += 1;
+                    changed |= self.check_entry(entry, index, is_root, directory, pending)?;
+                }
+                if changed {
+                    self.volume.write_sector(sector, &buf)?;
+                }
+                if end {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Returns whether the entry was changed
+    fn check_entry(
+        &mut self,
+        entry: &mut [u8],
+        index: usize,
+        is_root: bool,
+        directory: &Directory,
+        pending: &mut Vec<Directory>,
+    ) -> Result<bool, FsckError> {
+        let attributes = entry[11];
+        if entry[0] == ENTRY_DELETED || attributes & ATTR_LONG_NAME == ATTR_LONG_NAME {
+            return Ok(false);
+        }
+        let name = display_name(&entry[..11]);
+        let path = alloc::format!("{}/{}", directory.path, name);
+        let first = (u16_at(entry, 20) as u32) << 16 | u16_at(entry, 26) as u32;
+        let size = u32_at(entry, 28);
+        let mut changed = false;
+
+        if attributes & ATTR_RESERVED != 0 {
+            self.problem(format_args!("{} has reserved attribute bits set", path));
+            if self.repair {
+                entry[11] &= !ATTR_RESERVED;
+                changed = true;
+            }
+        }
+        if attributes & ATTR_VOLUME_ID != 0 {
+            return Ok(changed);
+        }
+
+        // "." and ".." come first in every subdirectory, and nowhere else
+        let dot = &entry[..11] == b".          ";
+        let dot_dot = &entry[..11] == b"..         ";
+        if !is_root && index <= 2 && (dot || dot_dot) {
+            let expected = if dot {
+                directory.first
+            } else {
+                directory.parent
+            };
+            if (index == 1) != dot {
+                self.problem(format_args!("{}: {:?} out of order", directory.path, name));
+            } else if first != expected {
+                self.problem(format_args!(
+                    "{}: {:?} points at cluster {} rather than {}",
+                    directory.path, name, first, expected
+                ));
+                if self.repair {
+                    set_u16(entry, 20, (expected >> 16) as u16);
+                    set_u16(entry, 26, expected as u16);
+                    changed = true;
+                }
+            }
+            return Ok(changed);
+        } else if dot || dot_dot {
+            self.problem(format_args!("{}: stray {:?} entry", directory.path, name));
+            return self.delete(entry);
+        } else if !is_root && index <= 2 {
+            // Checked as an ordinary entry, so its data is kept
+            self.problem(format_args!(
+                "{}: entry {} is not {:?}",
+                directory.path,
+                index,
+                if index == 1 { "." } else { ".." }
+            ));
+        }
+
+        if !valid_name(&entry[..11]) {
+            self.problem(format_args!(
+                "{:?} in {} is not a valid short name",
+                name, directory.path
+            ));
+        }
+
+        if attributes & ATTR_DIRECTORY != 0 {
+            if size != 0 {
+                self.problem(format_args!("directory {} has size {}", path, size));
+                if self.repair {
+                    set_u32(entry, 28, 0);
+                    changed = true;
+                }
+            }
+            if !self.volume.in_range(first) {
+                self.problem(format_args!(
+                    "directory {} has invalid first cluster {}",
+                    path, first
+                ));
+                return self.delete(entry);
+            }
+            let clusters = match self.claim(first, None, &path)? {
+                Some(clusters) => clusters,
+                None => return self.delete(entry),
+            };
+            pending.push(Directory {
+                first,
+                clusters,
+                parent: if is_root { 0 } else { directory.first },
+                path,
+            });
+            return Ok(changed);
+        }
+
+        if first == 0 {
+            if size != 0 {
+                self.problem(format_args!("{} has size {} but no clusters", path, size));
+                if self.repair {
+                    set_u32(entry, 28, 0);
+                    changed = true;
+                }
+            }
+            return Ok(changed);
+        }
+        let cluster_size = self.volume.sectors_per_cluster * self.volume.sector_size as u64;
+        let needed = ((size as u64 + cluster_size - 1) / cluster_size).max(1);
+        let length = if self.volume.in_range(first) {
+            self.claim(first, Some(needed), &path)?
+        } else {
+            self.problem(format_args!("{} has invalid first cluster {}", path, first));
+            None
+        };
+        match length {
+            None => {
+                // Keep the entry but give up its data
+                if self.repair {
+                    set_u16(entry, 20, 0);
+                    set_u16(entry, 26, 0);
+                    set_u32(entry, 28, 0);
+                    changed = true;
+                }
+            }
+            Some(length) if length < needed => {
+                self.problem(format_args!(
+                    "{} has size {} but only {} clusters",
+                    path, size, length
+                ));
+                if self.repair {
+                    set_u32(entry, 28, (length * cluster_size) as u32);
+                    changed = true;
+                }
+            }
+            Some(_) => {}
+        }
+        Ok(changed)
+    }
+
+    fn delete(&mut self, entry: &mut [u8]) -> Result<bool, FsckError> {
+        if self.repair {
+            entry[0] = ENTRY_DELETED;
+        }
+        Ok(self.repair)
+    }
+
+    fn lost_clusters(&mut self) -> Result<(), FsckError> {
+        let mut lost = 0;
+        let mut free = 0;
+        for cluster in 2..self.volume.clusters + 2 {
+            let entry = self.volume.entry(cluster)?;
+            if entry == 0 {
+                free += 1;
+            } else if entry != FAT_BAD && !self.claimed.get(cluster) {
+                lost += 1;
+                if self.repair {
+                    self.volume.set_entry(cluster, 0)?;
+                    free += 1;
+                }
+            }
+        }
+        if lost > 0 {
+            self.problem(format_args!("{} lost clusters", lost));
+        }
+        self.report.lost_clusters = lost;
+        self.report.free_clusters = free;
+        Ok(())
+    }
+
+    fn fs_info(&mut self) -> Result<(), FsckError> {
+        let sector = self.volume.fs_info;
+        if sector == 0 || sector >= self.volume.reserved_sectors {
+            return Ok(());
+        }
+        let mut buf = vec![0; self.volume.sector_size];
+        self.volume.read_sector(sector, &mut buf)?;
+        if u32_at(&buf, 0) != FSINFO_LEAD_SIGNATURE || u32_at(&buf, 484) != FSINFO_STRUCT_SIGNATURE
+        {
+            return Ok(());
+        }
+        let recorded = u32_at(&buf, FSINFO_FREE_COUNT);
+        let free = self.report.free_clusters;
+        // All ones means unknown
+        if recorded != u32::MAX && recorded != free {
+            self.problem(format_args!(
+                "FSInfo free count is {}, the FAT has {} free clusters",
+                recorded, free
+            ));
+            if self.repair {
+                set_u32(&mut buf, FSINFO_FREE_COUNT, free);
+                self.volume.write_sector(sector, &buf)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Whether the volume on `device` was not cleanly unmounted, for mount paths
+// deciding whether to run `check` first
+pub fn is_dirty(device: &dyn BlockDevice) -> Result<bool, FsckError> {
+    Volume::open(device)?.dirty()
+}
+
+pub fn check(device: &dyn BlockDevice, repair: bool) -> Result<Report, FsckError> {
+    let volume = Volume::open(device)?;
+    if volume.clusters > MAX_CLUSTERS {
+        return Err(FsckError::TooLarge);
+    }
+    let words = (volume.clusters as usize + 2 + 63) / 64;
+    let mut checker = Checker {
+        volume,
+        claimed: Claimed(vec![0; words]),
+        repair,
+        report: Report::default(),
+    };
+    checker.check()?;
+    checker.report.repaired = repair && checker.report.problems > 0;
+    Ok(checker.report)
+}
+
+pub fn run_at_boot() {
+    let requested = cmdline::value("fsck");
+    let repair = cmdline::enabled("fsck.repair");
+    for device in block::devices() {
+        let name = device.name();
+        let forced = requested == Some(name);
+        match is_dirty(&*device) {
+            Ok(true) => info!("fsck: {}: volume is dirty, checking", name),
+            Ok(false) if forced => {}
+            Ok(false) => continue,
+            Err(FsckError::NotFat32) if !forced => continue,
+            Err(err) => {
+                warn!("fsck: {}: {:?}", name, err);
+                continue;
+            }
+        }
+        match check(&*device, repair) {
+            Ok(report) if report.problems == 0 => info!(
+                "fsck: {}: clean, {} clusters free",
+                name, report.free_clusters
+            ),
+            Ok(report) => warn!(
+                "fsck: {}: {} problems{}",
+                name,
+                report.problems,
+                if report.repaired {
+                    ", repaired"
+                } else {
+                    "; boot with fsck.repair=1 to repair them"
+                }
+            ),
+            Err(err) => warn!("fsck: {}: {:?}", name, err),
+        }
+    }
+    if let Some(name) = requested {
+        if block::find(name).is_none() {
+            warn!("fsck: no block device {:?}", name);
+        }
+    }
+}
+
+// Self-test volume: 512-byte sectors, one per cluster, and just enough
+// clusters to count as FAT32
+const TEST_RESERVED: u32 = 32;
+const TEST_CLUSTERS: u32 = 65536;
+const TEST_FAT_SECTORS: u32 = (TEST_CLUSTERS + 2) * 4 / 512 + 1;
+const TEST_DATA_START: u32 = TEST_RESERVED + 2 * TEST_FAT_SECTORS;
+const TEST_SECTORS: u32 = TEST_DATA_START + TEST_CLUSTERS;
+
+// The root directory is cluster 2. A.TXT has clusters 3-4, B.TXT has cluster
+// 5 but a size needing four, C.TXT starts at 6 and links into A.TXT's chain,
+// and cluster 7 is allocated but unreachable.
+fn write_test_volume(device: &dyn BlockDevice) -> Result<(), BlockError> {
+    let end = FAT_END | 0xF;
+    let mut sector = [0u8; 512];
+    for lba in 0..=TEST_DATA_START as u64 {
+        device.write_blocks(lba, &sector)?;
+    }
+
+    sector[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    set_u16(&mut sector, 11, 512);
+    sector[13] = 1;
+    set_u16(&mut sector, 14, TEST_RESERVED as u16);
+    sector[16] = 2;
+    sector[21] = 0xF8;
+    set_u32(&mut sector, 32, TEST_SECTORS);
+    set_u32(&mut sector, 36, TEST_FAT_SECTORS);
+    set_u32(&mut sector, 44, 2);
+    set_u16(&mut sector, 48, 1);
+    sector[510..].copy_from_slice(&[0x55, 0xAA]);
+    device.write_blocks(0, &sector)?;
+
+    // A free count of all ones is not compared with the FAT
+    let mut fs_info = [0u8; 512];
+    set_u32(&mut fs_info, 0, FSINFO_LEAD_SIGNATURE);
+    set_u32(&mut fs_info, 484, FSINFO_STRUCT_SIGNATURE);
+    set_u32(&mut fs_info, FSINFO_FREE_COUNT, u32::MAX);
+    device.write_blocks(1, &fs_info)?;
+
+    let mut fat = [0u8; 512];
+    let entries = [0x0FFF_FFF8, end, end, 4, end, end, 4, end];
+    for (cluster, &value) in entries.iter().enumerate() {
+        set_u32(&mut fat, cluster * 4, value);
+    }
+    for copy in 0..2 {
+        device.write_blocks((TEST_RESERVED + copy * TEST_FAT_SECTORS) as u64, &fat)?;
+    }
+
+    let mut root = [0u8; 512];
+    let files: [(&[u8; 11], u32, u32); 3] = [
+        (b"A       TXT", 3, 1024),
+        (b"B       TXT", 5, 2048),
+        (b"C       TXT", 6, 1024),
+    ];
+    for (entry, (name, first, size)) in root.chunks_mut(ENTRY_SIZE).zip(files) {
+        entry[..11].copy_from_slice(name);
+        // Archive
+        entry[11] = 0x20;
+        set_u16(entry, 20, (first >> 16) as u16);
+        set_u16(entry, 26, first as u16);
+        set_u32(entry, 28, size);
+    }
+    device.write_blocks(TEST_DATA_START as u64, &root)
+}
+
+// Overwrites ram0
+pub fn self_test() {
+    let device = match block::find("ram0") {
+        Some(device)
+            if device.block_size() == 512 && device.block_count() >= TEST_SECTORS as u64 =>
+        {
+            device
+        }
+        _ => {
+            serial_println!(
+                "selftest: {:<20} SKIP (needs ramdisk={}M)",
+                "fsck",
+                (TEST_SECTORS as u64 * 512 + (1 << 20) - 1) >> 20
+            );
+            return;
+        }
+    };
+    let run = || -> Result<[(&str, bool); 3], FsckError> {
+        write_test_volume(&*device)?;
+        // The cross-link, the size C.TXT is left with, B.TXT's size and the
+        // lost cluster
+        let found = check(&*device, false)?;
+        let repaired = check(&*device, true)?;
+        let after = check(&*device, false)?;
+        Ok([
+            (
+                "fsck detect",
+                found.problems == 4 && found.lost_clusters == 1 && !found.repaired,
+            ),
+            ("fsck repair", repaired.problems == 4 && repaired.repaired),
+            (
+                "fsck recheck",
+                after.problems == 0
+                    && after.lost_clusters == 0
+                    && after.free_clusters == TEST_CLUSTERS - 5,
+            ),
+        ])
+    };
+    let results = match run() {
+        Ok(results) => results,
+        Err(err) => {
+            error!("fsck self-test: {:?}", err);
+            return;
+        }
+    };
+    for (name, passed) in results.iter() {
+        serial_println!(
+            "selftest: {:<20} {}",
+            name,
+            if *passed { "PASS" } else { "FAIL" }
+        );
+    }
+    let failed = results.iter().filter(|(_, passed)| !passed).count();
+    if failed == 0 {
+        info!("fsck self-test passed ({} tests)", results.len());
+    } else {
+        error!(
+            "fsck self-test: {} of {} tests failed",
+            failed,
+            results.len()
+        );
+    }
+}
@@ -0,0 +1,56 @@
+// GDT and TSS, with dedicated interrupt stacks for faults that must not run
+// on a possibly broken kernel stack
+use core::ptr::addr_of;
+use lazy_static::lazy_static;
+use x86_64::instructions::segmentation::{Segment, CS, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const PAGE_FAULT_IST_INDEX: u16 = 2;
+pub const GENERAL_PROTECTION_IST_INDEX: u16 = 3;
+
+const IST_COUNT: usize = 4;
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+static mut IST_STACKS: [[u8; IST_STACK_SIZE]; IST_COUNT] = [[0; IST_STACK_SIZE]; IST_COUNT];
+
+lazy_static! {
+    static ref TSS: TaskStateSegment = {
+        let mut tss = TaskStateSegment::new();
+        for index in 0..IST_COUNT {
+            let stack_start = VirtAddr::from_ptr(unsafe { addr_of!(IST_STACKS[index]) });
+            tss.interrupt_stack_table[index] = stack_start + IST_STACK_SIZE;
+        }
+        tss
+    };
+}
+
+struct Selectors {
+    code: SegmentSelector,
+    data: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+lazy_static! {
+    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        let mut gdt = GlobalDescriptorTable::new();
+        let code = gdt.add_entry(Descriptor::kernel_code_segment());
+        let data = gdt.add_entry(Descriptor::kernel_data_segment());
+        let tss = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        (gdt, Selectors { code, data, tss })
+    };
+}
+
+pub fn init() {
+    GDT.0.load();
+    unsafe {
+        CS::set_reg(GDT.1.code);
+        SS::set_reg(GDT.1.data);
+        load_tss(GDT.1.tss);
+    }
+    info!("GDT and TSS initialized");
+}
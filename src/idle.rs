@@ -0,0 +1,164 @@
+// Idle loop. Puts the CPU to sleep until the next interrupt, using MWAIT when
+// the CPU supports it and HLT otherwise, and tracks idle residency. A
+// governor hook picks the sleep state on every entry, which is where C-state
+// and frequency policy plugs in.
+use crate::sync::SpinLock;
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+#[derive(Clone, Copy, Debug)]
+pub enum IdleState {
+    Halt,
+    // MWAIT with the given C-state hint in EAX
+    Mwait { hint: u32 },
+    // Spin without sleeping, when there is no timer tick to wake the CPU
+    Poll,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IdleStats {
+    pub entries: u64,
+    pub idle_cycles: u64,
+    // Cycles since the idle loop started
+    pub total_cycles: u64,
+}
+
+impl IdleStats {
+    // Idle residency in tenths of a percent
+    pub fn residency_permille(&self) -> u64 {
+        match self.total_cycles {
+            0 => 0,
+            total => self.idle_cycles * 1000 / total,
+        }
+    }
+}
+
+pub type Governor = fn(&IdleStats) -> IdleState;
+
+// Callbacks run on every exit from idle, for low-rate housekeeping such as
+// sensor polling. They rate-limit themselves.
+const MAX_POLLERS: usize = 4;
+
+static GOVERNOR: SpinLock<Option<Governor>> = SpinLock::new("idle_governor", None);
+static POLLERS: SpinLock<[Option<fn()>; MAX_POLLERS]> =
+    SpinLock::new("idle_pollers", [None; MAX_POLLERS]);
+static MWAIT_SUPPORTED: AtomicBool = AtomicBool::new(false);
+static STARTED_AT: AtomicU64 = AtomicU64::new(0);
+static ENTRIES: AtomicU64 = AtomicU64::new(0);
+static IDLE_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+// Monitored by MWAIT; writing to it wakes the idle loop
+static WAKE: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_governor(governor: Governor) {
+    *GOVERNOR.lock() = Some(governor);
+}
+
+pub fn register_poll(poller: fn()) {
+    let mut pollers = POLLERS.lock();
+    match pollers.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(poller),
+        None => warn!("idle: no room for another poll callback"),
+    }
+}
+
+pub fn mwait_supported() -> bool {
+    MWAIT_SUPPORTED.load(Ordering::Relaxed)
+}
+
+pub fn stats() -> IdleStats {
+    let started = STARTED_AT.load(Ordering::Relaxed);
+    IdleStats {
+        entries: ENTRIES.load(Ordering::Relaxed),
+        idle_cycles: IDLE_CYCLES.load(Ordering::Relaxed),
+        total_cycles: if started == 0 {
+            0
+        } else {
+            unsafe { _rdtsc() }
+            -started
+        },
+    }
+}
+
+// Wakes the idle loop from MWAIT without an interrupt
+pub fn kick() {
+    WAKE.fetch_add(1, Ordering::SeqCst);
+}
+
+// Deepest state the idle loop uses without a governor
+pub fn default_state() -> IdleState {
+    if mwait_supported() {
+        IdleState::Mwait { hint: 0 }
+    } else {
+        IdleState::Halt
+    }
+}
+
+pub fn run() -> ! {
+    // CPUID.01H:ECX.MONITOR[bit 3]
+    let mwait = unsafe { __cpuid(1) }.ecx & (1 << 3) != 0;
+    MWAIT_SUPPORTED.store(mwait, Ordering::Relaxed);
+    STARTED_AT.store(unsafe { _rdtsc() }, Ordering::Relaxed);
+    info!(
+        "Idle loop started ({})",
+        if mwait { "MWAIT" } else { "HLT" }
+    );
+
+    loop {
+        let governor = *GOVERNOR.lock();
+        let state = match governor {
+            Some(governor) => governor(&stats()),
+            None => default_state(),
+        };
+        // Without the timer tick nothing may ever wake a sleeping CPU
+        let state = if crate::timer::running() {
+            state
+        } else {
+            IdleState::Poll
+        };
+
+        crate::interrupts::might_block();
+        // Interrupts go back on atomically with the wait below
+        interrupts::disable();
+        let start = unsafe { _rdtsc() };
+        match state {
+            IdleState::Halt => interrupts::enable_and_hlt(),
+            IdleState::Mwait { hint } => unsafe { mwait(hint) },
+            IdleState::Poll => {
+                interrupts::enable();
+                core::hint::spin_loop();
+            }
+        }
+        let cycles = unsafe { _rdtsc() } - start;
+
+        ENTRIES.fetch_add(1, Ordering::Relaxed);
+        IDLE_CYCLES.fetch_add(cycles, Ordering::Relaxed);
+
+        let pollers = *POLLERS.lock();
+        for poller in pollers.iter().flatten() {
+            poller();
+        }
+    }
+}
+
+// Must be entered with interrupts disabled; returns with them enabled
+unsafe fn mwait(hint: u32) {
+    asm!(
+        "monitor",
+        in("rax") WAKE.as_ptr(),
+        in("ecx") 0,
+        in("edx") 0,
+        options(nostack, preserves_flags),
+    );
+    // STI's interrupt shadow covers MWAIT, so a wakeup cannot be lost between
+    // the two
+    asm!(
+        "sti",
+        "mwait",
+        in("eax") hint,
+        in("ecx") 0,
+        options(nostack),
+    );
+}
@@ -0,0 +1,63 @@
+// Input event queue. Keyboard and mouse drivers push events as they arrive and
+// consumers drain them with `pop`. Keys are identified by their HID usage ID
+// (USB HID Usage Tables, keyboard page), which other keyboard drivers
+// translate their scancodes to; `keymap` turns them into characters.
+use crate::sync::SpinLock;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputEvent {
+    Key { usage: u8, pressed: bool },
+    MouseMove { dx: i16, dy: i16 },
+    MouseButton { button: u8, pressed: bool },
+    MouseWheel(i8),
+}
+
+pub const USAGE_LEFT_SHIFT: u8 = 0xE1;
+pub const USAGE_RIGHT_SHIFT: u8 = 0xE5;
+pub const USAGE_RIGHT_ALT: u8 = 0xE6;
+
+const QUEUE_SIZE: usize = 128;
+
+struct Queue {
+    events: [Option<InputEvent>; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+    dropped: u64,
+}
+
+static QUEUE: SpinLock<Queue> = SpinLock::new(
+    "input_queue",
+    Queue {
+        events: [None; QUEUE_SIZE],
+        head: 0,
+        len: 0,
+        dropped: 0,
+    },
+);
+
+// Newest events are dropped when nobody is draining the queue
+pub fn push(event: InputEvent) {
+    let mut queue = QUEUE.lock();
+    if queue.len == QUEUE_SIZE {
+        queue.dropped += 1;
+        return;
+    }
+    let tail = (queue.head + queue.len) % QUEUE_SIZE;
+    queue.events[tail] = Some(event);
+    queue.len += 1;
+}
+
+pub fn pop() -> Option<InputEvent> {
+    let mut queue = QUEUE.lock();
+    if queue.len == 0 {
+        return None;
+    }
+    let head = queue.head;
+    queue.head = (head + 1) % QUEUE_SIZE;
+    queue.len -= 1;
+    queue.events[head].take()
+}
+
+pub fn dropped() -> u64 {
+    QUEUE.lock().dropped
+}
@@ -0,0 +1,268 @@
+// Interrupt handling
+use crate::{gdt, irqstats, kfence, msr, selftest, serial, serial_println, timer};
+use core::marker::PhantomData;
+use core::panic::Location;
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+const VECTOR_DIVIDE_ERROR: u8 = 0;
+const VECTOR_NMI: u8 = 2;
+const VECTOR_BREAKPOINT: u8 = 3;
+const VECTOR_DOUBLE_FAULT: u8 = 8;
+const VECTOR_GENERAL_PROTECTION: u8 = 13;
+const VECTOR_PAGE_FAULT: u8 = 14;
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt[timer::VECTOR_TIMER as usize].set_handler_fn(timer_handler);
+        idt[timer::VECTOR_SPURIOUS as usize].set_handler_fn(spurious_handler);
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt
+                .set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.page_fault
+                .set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+            idt.general_protection_fault
+                .set_handler_fn(general_protection_fault_handler)
+                .set_stack_index(gdt::GENERAL_PROTECTION_IST_INDEX);
+        }
+        idt
+    };
+}
+
+pub fn init_idt() {
+    IDT.load();
+    info!("IDT initialized");
+}
+
+// Nested fault tracking. A fault raised while another exception handler is
+// still running is reported together with the outer fault before panicking,
+// since the outer handler's state is likely what caused it.
+static FAULT_DEPTH: AtomicUsize = AtomicUsize::new(0);
+static OUTER_VECTOR: AtomicU8 = AtomicU8::new(0);
+static OUTER_RIP: AtomicU64 = AtomicU64::new(0);
+
+// Whether the CPU is currently running an exception or interrupt handler
+pub fn in_interrupt() -> bool {
+    FAULT_DEPTH.load(Ordering::Relaxed) > 0
+}
+
+// Interrupt-disabled critical sections. The guard from `disable` puts
+// RFLAGS.IF back the way it found it, so sections nest and only the outermost
+// one turns interrupts back on. Use these rather than bare cli/sti.
+crate::per_cpu! {
+    // Critical sections open on this CPU
+    static IRQS_OFF_DEPTH: AtomicUsize = AtomicUsize::new(0);
+}
+
+pub struct IrqGuard {
+    enabled: bool,
+    // The saved flag belongs to this CPU
+    _not_send: PhantomData<*const ()>,
+}
+
+pub fn disable() -> IrqGuard {
+    let enabled = x86_64::instructions::interrupts::are_enabled();
+    x86_64::instructions::interrupts::disable();
+    IRQS_OFF_DEPTH.local().fetch_add(1, Ordering::Relaxed);
+    IrqGuard {
+        enabled,
+        _not_send: PhantomData,
+    }
+}
+
+impl Drop for IrqGuard {
+    fn drop(&mut self) {
+        IRQS_OFF_DEPTH.local().fetch_sub(1, Ordering::Relaxed);
+        if self.enabled {
+            x86_64::instructions::interrupts::enable();
+        }
+    }
+}
+
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let _guard = disable();
+    f()
+}
+
+// To be called before anything that waits for an interrupt to wake it (HLT,
+// MWAIT, sleeping). In debug builds, panics if that can never happen because
+// a critical section is open or this is an interrupt handler. RFLAGS.IF
+// itself is not checked: the idle loop runs with it clear until its first
+// wait, which turns interrupts on atomically with sleeping.
+#[track_caller]
+pub fn might_block() {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let depth = IRQS_OFF_DEPTH.local().load(Ordering::Relaxed);
+    if depth > 0 {
+        panic!(
+            "blocking with interrupts disabled at {} ({} critical sections open)",
+            Location::caller(),
+            depth
+        );
+    }
+    if in_interrupt() {
+        panic!("blocking in interrupt context at {}", Location::caller());
+    }
+}
+
+struct FaultGuard {
+    _sample: irqstats::Sample,
+}
+
+impl Drop for FaultGuard {
+    fn drop(&mut self) {
+        FAULT_DEPTH.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn enter_fault(vector: u8, stack_frame: &InterruptStackFrame) -> FaultGuard {
+    let sample = irqstats::enter(vector);
+    let rip = stack_frame.instruction_pointer.as_u64();
+    if FAULT_DEPTH.fetch_add(1, Ordering::SeqCst) > 0 {
+        nested_fault(vector, rip);
+    }
+    OUTER_VECTOR.store(vector, Ordering::SeqCst);
+    OUTER_RIP.store(rip, Ordering::SeqCst);
+    FaultGuard { _sample: sample }
+}
+
+fn nested_fault(vector: u8, rip: u64) -> ! {
+    // Locks may be held by the interrupted handler, so write straight to serial
+    let outer = vector_name(OUTER_VECTOR.load(Ordering::SeqCst));
+    serial::force_unlock();
+    serial_println!(
+        "NESTED FAULT: {} at {:#x} while handling {} at {:#x} (depth {})",
+        vector_name(vector),
+        rip,
+        outer,
+        OUTER_RIP.load(Ordering::SeqCst),
+        FAULT_DEPTH.load(Ordering::SeqCst),
+    );
+    panic!("nested {} inside {}", vector_name(vector), outer);
+}
+
+pub fn vector_name(vector: u8) -> &'static str {
+    match vector {
+        VECTOR_DIVIDE_ERROR => "DIVIDE ERROR",
+        VECTOR_NMI => "NMI",
+        VECTOR_BREAKPOINT => "BREAKPOINT",
+        VECTOR_DOUBLE_FAULT => "DOUBLE FAULT",
+        VECTOR_GENERAL_PROTECTION => "GENERAL PROTECTION FAULT",
+        VECTOR_PAGE_FAULT => "PAGE FAULT",
+        timer::VECTOR_TIMER => "TIMER",
+        timer::VECTOR_SPURIOUS => "SPURIOUS IRQ",
+        _ => "UNKNOWN",
+    }
+}
+
+extern "x86-interrupt" fn divide_error_handler(mut stack_frame: InterruptStackFrame) {
+    let _guard = enter_fault(VECTOR_DIVIDE_ERROR, &stack_frame);
+    if selftest::try_recover(VECTOR_DIVIDE_ERROR, &mut stack_frame) {
+        return;
+    }
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn breakpoint_handler(mut stack_frame: InterruptStackFrame) {
+    let _guard = enter_fault(VECTOR_BREAKPOINT, &stack_frame);
+    selftest::try_recover(VECTOR_BREAKPOINT, &mut stack_frame);
+    info!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    // NMIs are not faults and may legitimately arrive inside another handler,
+    // so they are reported but do not count towards the nesting depth
+    let _sample = irqstats::enter(VECTOR_NMI);
+    let reason = unsafe { Port::<u8>::new(0x61).read() };
+    let parity = if reason & 0x80 != 0 {
+        ", memory parity error"
+    } else {
+        ""
+    };
+    let io_check = if reason & 0x40 != 0 {
+        ", I/O channel check"
+    } else {
+        ""
+    };
+    serial::force_unlock();
+    serial_println!(
+        "NMI at {:#x} (system control port B {:#04x}{}{})",
+        stack_frame.instruction_pointer.as_u64(),
+        reason,
+        parity,
+        io_check,
+    );
+}
+
+extern "x86-interrupt" fn timer_handler(stack_frame: InterruptStackFrame) {
+    let _guard = enter_fault(timer::VECTOR_TIMER, &stack_frame);
+    timer::end_of_interrupt();
+}
+
+// Not a real interrupt, so there is nothing to acknowledge
+extern "x86-interrupt" fn spurious_handler(_stack_frame: InterruptStackFrame) {
+    let _sample = irqstats::enter(timer::VECTOR_SPURIOUS);
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let _guard = enter_fault(VECTOR_PAGE_FAULT, &stack_frame);
+    if selftest::try_recover(VECTOR_PAGE_FAULT, &mut stack_frame) {
+        return;
+    }
+    kfence::handle_page_fault(Cr2::read(), error_code);
+    panic!(
+        "EXCEPTION: PAGE FAULT\nAccessed address: {:?}\nError code: {:?}\n{:#?}",
+        Cr2::read(),
+        error_code,
+        stack_frame
+    );
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _guard = enter_fault(VECTOR_GENERAL_PROTECTION, &stack_frame);
+    if msr::fixup(&mut stack_frame) {
+        return;
+    }
+    if selftest::try_recover(VECTOR_GENERAL_PROTECTION, &mut stack_frame) {
+        return;
+    }
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT (error code {:#x})\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    // A double fault is by definition a fault within fault delivery, so print
+    // whatever outer fault was in flight before panicking
+    let _sample = irqstats::enter(VECTOR_DOUBLE_FAULT);
+    if FAULT_DEPTH.load(Ordering::SeqCst) > 0 {
+        nested_fault(
+            VECTOR_DOUBLE_FAULT,
+            stack_frame.instruction_pointer.as_u64(),
+        );
+    }
+    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+}
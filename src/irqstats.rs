@@ -0,0 +1,88 @@
+// Per-vector interrupt statistics: how often each vector fired and how many
+// TSC cycles its handler took in total. Counted per CPU, so handlers running
+// on different CPUs don't share cache lines, and summed when read.
+use crate::serialize::{Serialize, Serializer};
+use core::arch::x86_64::_rdtsc;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+struct VectorStats {
+    count: AtomicU64,
+    cycles: AtomicU64,
+}
+
+const EMPTY: VectorStats = VectorStats {
+    count: AtomicU64::new(0),
+    cycles: AtomicU64::new(0),
+};
+
+crate::per_cpu! {
+    static STATS: [VectorStats; 256] = [EMPTY; 256];
+}
+
+// Accounts the handler's run time to its vector when dropped
+pub struct Sample {
+    vector: u8,
+    start: u64,
+}
+
+pub fn enter(vector: u8) -> Sample {
+    STATS.local()[vector as usize]
+        .count
+        .fetch_add(1, Ordering::Relaxed);
+    Sample {
+        vector,
+        start: unsafe { _rdtsc() },
+    }
+}
+
+impl Drop for Sample {
+    fn drop(&mut self) {
+        let cycles = unsafe { _rdtsc() } - self.start;
+        STATS.local()[self.vector as usize]
+            .cycles
+            .fetch_add(cycles, Ordering::Relaxed);
+    }
+}
+
+// Calls `f` with (vector, count, total cycles) for every vector that fired,
+// summed over all CPUs
+pub fn for_each(mut f: impl FnMut(u8, u64, u64)) {
+    for vector in 0..256 {
+        let (mut count, mut cycles) = (0, 0);
+        STATS.for_each_cpu(|_, stats| {
+            count += stats[vector].count.load(Ordering::Relaxed);
+            cycles += stats[vector].cycles.load(Ordering::Relaxed);
+        });
+        if count > 0 {
+            f(vector as u8, count, cycles);
+        }
+    }
+}
+
+// Serializes as an array of {vector, name, count, cycles} for every vector
+// that fired
+pub struct Summary;
+
+fn serialize_vector(out: &mut dyn Serializer, vector: u8, count: u64, cycles: u64) -> fmt::Result {
+    crate::serialize_fields!(out,
+        "vector" => vector,
+        "name" => crate::interrupts::vector_name(vector),
+        "count" => count,
+        "cycles" => cycles,
+    )
+}
+
+impl Serialize for Summary {
+    fn serialize(&self, out: &mut dyn Serializer) -> fmt::Result {
+        out.begin_array()?;
+        let mut result = Ok(());
+        for_each(|vector, count, cycles| {
+            if result.is_ok() {
+                result = serialize_vector(out, vector, count, cycles);
+            }
+        });
+        result?;
+        out.end_array()
+    }
+}
@@ -0,0 +1,155 @@
+// KASAN-lite: shadow memory for the kernel heap, built with the `kasan`
+// feature in debug builds. One shadow byte describes eight heap bytes: 0 if
+// all of them may be accessed, 1-7 if only that many leading bytes may, or a
+// tag saying why none may. The debug heap keeps the shadow up to date on
+// every allocation and free. There is no compiler instrumentation, so
+// accesses are only checked where code goes through `check` or the
+// read/write/copy helpers here, which drivers use for raw pointer accesses
+// to buffers. Without the feature the helpers are plain accesses.
+use core::ptr;
+
+// Shadow tags
+#[cfg(all(debug_assertions, feature = "kasan"))]
+pub const REDZONE: u8 = 0xFA;
+#[cfg(all(debug_assertions, feature = "kasan"))]
+pub const FREED: u8 = 0xFB;
+#[cfg(all(debug_assertions, feature = "kasan"))]
+const UNALLOCATED: u8 = 0xFC;
+
+#[cfg(all(debug_assertions, feature = "kasan"))]
+mod shadow {
+    use super::{FREED, REDZONE, UNALLOCATED};
+    use crate::{serial_print, serial_println, HEAP_SIZE, HEAP_START};
+    use core::sync::atomic::{AtomicU8, Ordering};
+
+    const GRANULE: usize = 8;
+    const ROW: usize = 16;
+
+    const INITIAL: AtomicU8 = AtomicU8::new(UNALLOCATED);
+    static SHADOW: [AtomicU8; HEAP_SIZE / GRANULE] = [INITIAL; HEAP_SIZE / GRANULE];
+
+    fn granule(addr: usize) -> Option<usize> {
+        (HEAP_START..HEAP_START + HEAP_SIZE)
+            .contains(&addr)
+            .then(|| (addr - HEAP_START) / GRANULE)
+    }
+
+    fn set(start: usize, end: usize, value: u8) {
+        for index in start..end.min(SHADOW.len()) {
+            SHADOW[index].store(value, Ordering::Relaxed);
+        }
+    }
+
+    // `addr` is granule aligned, as every allocation is
+    pub fn unpoison(addr: usize, size: usize) {
+        if let Some(first) = granule(addr) {
+            let last = first + size / GRANULE;
+            set(first, last, 0);
+            if size % GRANULE != 0 {
+                set(last, last + 1, (size % GRANULE) as u8);
+            }
+        }
+    }
+
+    // A granule partly covered at the start belongs to the data before it and
+    // is left alone
+    pub fn poison(addr: usize, size: usize, tag: u8) {
+        if let Some(first) = granule(addr + GRANULE - 1) {
+            let end = (addr + size - HEAP_START + GRANULE - 1) / GRANULE;
+            set(first, end, tag);
+        }
+    }
+
+    #[track_caller]
+    pub fn check(addr: usize, size: usize, write: bool) {
+        let end = addr + size;
+        let mut cursor = addr;
+        while cursor < end {
+            let index = match granule(cursor) {
+                Some(index) => index,
+                None => return,
+            };
+            let value = SHADOW[index].load(Ordering::Relaxed);
+            let offset = (cursor - HEAP_START) % GRANULE;
+            if value != 0 && (value as usize > GRANULE || offset >= value as usize) {
+                report(cursor, addr, size, write, index, value);
+            }
+            cursor += 1;
+        }
+    }
+
+    #[track_caller]
+    fn report(bad: usize, addr: usize, size: usize, write: bool, index: usize, value: u8) -> ! {
+        let what = match value {
+            REDZONE => "out-of-bounds",
+            FREED => "use-after-free",
+            UNALLOCATED => "access to unallocated heap",
+            _ => "out-of-bounds",
+        };
+        serial_println!(
+            "kasan: {} {} of {} bytes at {:#x}, first bad byte {:#x}",
+            what,
+            if write { "write" } else { "read" },
+            size,
+            addr,
+            bad
+        );
+        serial_println!("kasan: shadow around it ([] marks the bad granule):");
+        let row = index / ROW;
+        for row in row.saturating_sub(2)..(row + 3).min(SHADOW.len() / ROW) {
+            serial_print!("  {:#x}:", HEAP_START + row * ROW * GRANULE);
+            for column in 0..ROW {
+                let shadow = row * ROW + column;
+                let value = SHADOW[shadow].load(Ordering::Relaxed);
+                if shadow == index {
+                    serial_print!("[{:02x}]", value);
+                } else {
+                    serial_print!(" {:02x} ", value);
+                }
+            }
+            serial_println!();
+        }
+        panic!("kasan: {} at {:#x}", what, bad);
+    }
+}
+
+// Marks a new allocation accessible
+#[cfg(all(debug_assertions, feature = "kasan"))]
+pub fn unpoison(addr: *const u8, size: usize) {
+    shadow::unpoison(addr as usize, size);
+}
+
+// Marks memory inaccessible with one of the shadow tags
+#[cfg(all(debug_assertions, feature = "kasan"))]
+pub fn poison(addr: *const u8, size: usize, tag: u8) {
+    shadow::poison(addr as usize, size, tag);
+}
+
+// Panics with a report if any of the `size` bytes at `addr` is poisoned.
+// Addresses outside the heap are not tracked and always pass.
+#[track_caller]
+pub fn check(addr: *const u8, size: usize, write: bool) {
+    #[cfg(all(debug_assertions, feature = "kasan"))]
+    shadow::check(addr as usize, size, write);
+    #[cfg(not(all(debug_assertions, feature = "kasan")))]
+    let _ = (addr, size, write);
+}
+
+#[track_caller]
+pub unsafe fn read<T: Copy>(src: *const T) -> T {
+    check(src as *const u8, core::mem::size_of::<T>(), false);
+    ptr::read(src)
+}
+
+#[track_caller]
+pub unsafe fn write<T>(dst: *mut T, value: T) {
+    check(dst as *const u8, core::mem::size_of::<T>(), true);
+    ptr::write(dst, value)
+}
+
+#[track_caller]
+pub unsafe fn copy(src: *const u8, dst: *mut u8, len: usize) {
+    check(src, len, false);
+    check(dst, len, true);
+    ptr::copy_nonoverlapping(src, dst, len)
+}
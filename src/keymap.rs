@@ -0,0 +1,127 @@
+// Keyboard layouts: translation from HID usage IDs to characters. The layout
+// is picked with the `kmap=` command line option and can be switched at
+// runtime with `set_layout`.
+use crate::cmdline;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// Printable keys are usages 0x04..=0x27 (letters and digits) and
+// 0x2D..=0x38 (punctuation); the tables below list them in that order.
+// '\0' marks keys that produce nothing.
+pub struct Layout {
+    pub name: &'static str,
+    normal: &'static str,
+    shifted: &'static str,
+    // The extra ISO key left of Z (usage 0x64)
+    iso: [char; 2],
+    altgr: &'static [(u8, char)],
+}
+
+const USAGE_ISO_BACKSLASH: u8 = 0x64;
+
+pub static LAYOUTS: [Layout; 4] = [
+    Layout {
+        name: "us",
+        normal: "abcdefghijklmnopqrstuvwxyz1234567890-=[]\\#;'`,./",
+        shifted: "ABCDEFGHIJKLMNOPQRSTUVWXYZ!@#$%^&*()_+{}|~:\"~<>?",
+        iso: ['\\', '|'],
+        altgr: &[],
+    },
+    Layout {
+        name: "de",
+        normal: "abcdefghijklmnopqrstuvwxzy1234567890ß´ü+##öä^,.-",
+        shifted: "ABCDEFGHIJKLMNOPQRSTUVWXZY!\"§$%&/()=?`Ü*''ÖÄ°;:_",
+        iso: ['<', '>'],
+        altgr: &[
+            (0x08, '€'),
+            (0x10, 'µ'),
+            (0x14, '@'),
+            (0x1F, '²'),
+            (0x20, '³'),
+            (0x24, '{'),
+            (0x25, '['),
+            (0x26, ']'),
+            (0x27, '}'),
+            (0x2D, '\\'),
+            (0x30, '~'),
+            (USAGE_ISO_BACKSLASH, '|'),
+        ],
+    },
+    Layout {
+        name: "fr",
+        normal: "qbcdefghijkl,noparstuvzxyw&é\"'(-è_çà)=^$**mù²;:!",
+        shifted: "QBCDEFGHIJKL?NOPARSTUVZXYW1234567890°+¨£µµM%\0./§",
+        iso: ['<', '>'],
+        altgr: &[
+            (0x08, '€'),
+            (0x1F, '~'),
+            (0x20, '#'),
+            (0x21, '{'),
+            (0x22, '['),
+            (0x23, '|'),
+            (0x24, '`'),
+            (0x25, '\\'),
+            (0x26, '^'),
+            (0x27, '@'),
+            (0x2D, ']'),
+            (0x2E, '}'),
+        ],
+    },
+    Layout {
+        name: "dvorak",
+        normal: "axje.uidchtnmbrl'poygk,qf;1234567890[]/=\\#s-`wvz",
+        shifted: "AXJE>UIDCHTNMBRL\"POYGK<QF:!@#$%^&*(){}?+|~S_~WVZ",
+        iso: ['\\', '|'],
+        altgr: &[],
+    },
+];
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn init() {
+    if let Some(name) = cmdline::value("kmap") {
+        if !set_layout(name) {
+            warn!("keymap: unknown layout {:?}, using {}", name, layout().name);
+        }
+    }
+}
+
+// Returns false if there is no layout with that name
+pub fn set_layout(name: &str) -> bool {
+    match LAYOUTS.iter().position(|layout| layout.name == name) {
+        Some(index) => {
+            CURRENT.store(index, Ordering::Relaxed);
+            info!("keymap: using {} layout", name);
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn layout() -> &'static Layout {
+    &LAYOUTS[CURRENT.load(Ordering::Relaxed)]
+}
+
+// Character produced by a key with the given modifiers, if any
+pub fn to_char(usage: u8, shift: bool, altgr: bool) -> Option<char> {
+    let layout = layout();
+    if altgr {
+        return layout
+            .altgr
+            .iter()
+            .find(|&&(key, _)| key == usage)
+            .map(|&(_, c)| c);
+    }
+    let index = match usage {
+        0x04..=0x27 => (usage - 0x04) as usize,
+        0x2D..=0x38 => (usage - 0x2D) as usize + 36,
+        0x28 => return Some('\n'),
+        0x29 => return Some('\x1b'),
+        0x2A => return Some('\x08'),
+        0x2B => return Some('\t'),
+        0x2C => return Some(' '),
+        USAGE_ISO_BACKSLASH => return Some(layout.iso[shift as usize]),
+        _ => return None,
+    };
+    let table = if shift { layout.shifted } else { layout.normal };
+    table.chars().nth(index).filter(|&c| c != '\0')
+}
@@ -0,0 +1,331 @@
+// KFENCE-lite: a sampling allocator that places one in every SAMPLE_INTERVAL
+// heap allocations alone on a page between two unmapped guard pages. Objects
+// are aligned to the end of their page so that overflows fault immediately on
+// the following guard page, and freed pages are made non-present so that
+// use-after-free faults as well. The page fault handler turns those faults
+// into a precise report.
+use crate::crash;
+use crate::interrupts;
+use crate::memory::layout;
+use crate::sync::SpinLock;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ops::Deref;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::tlb;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, Page, PageTableEntry, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+const POOL_START: usize = layout::KFENCE_POOL_START as usize;
+const OBJECTS: usize = 31;
+const PAGE_SIZE: usize = 4096;
+// Guard page, then object page and guard page for each object
+const POOL_SIZE: usize = (2 * OBJECTS + 1) * PAGE_SIZE;
+
+const SAMPLE_INTERVAL: usize = 64;
+const CANARY_BYTE: u8 = 0xAA;
+const BACKTRACE_DEPTH: usize = 4;
+
+#[derive(Clone, Copy, PartialEq)]
+enum SlotState {
+    Unused,
+    Allocated,
+    Freed,
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    state: SlotState,
+    addr: usize,
+    size: usize,
+    pte: *mut PageTableEntry,
+    alloc_backtrace: [u64; BACKTRACE_DEPTH],
+    free_backtrace: [u64; BACKTRACE_DEPTH],
+}
+
+const EMPTY_SLOT: Slot = Slot {
+    state: SlotState::Unused,
+    addr: 0,
+    size: 0,
+    pte: ptr::null_mut(),
+    alloc_backtrace: [0; BACKTRACE_DEPTH],
+    free_backtrace: [0; BACKTRACE_DEPTH],
+};
+
+struct Pool {
+    slots: [Slot; OBJECTS],
+    // Round-robin cursor, so freed pages stay protected as long as possible
+    next: usize,
+}
+
+unsafe impl Send for Pool {}
+
+static POOL: SpinLock<Pool> = SpinLock::new(
+    "kfence_pool",
+    Pool {
+        slots: [EMPTY_SLOT; OBJECTS],
+        next: 0,
+    },
+);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static COUNTDOWN: AtomicUsize = AtomicUsize::new(SAMPLE_INTERVAL);
+
+fn page_start(slot: usize) -> usize {
+    POOL_START + (2 * slot + 1) * PAGE_SIZE
+}
+
+fn in_pool(addr: usize) -> bool {
+    (POOL_START..POOL_START + POOL_SIZE).contains(&addr)
+}
+
+fn set_present(pte: *mut PageTableEntry, addr: usize, present: bool) {
+    unsafe {
+        let mut flags = (*pte).flags();
+        flags.set(PageTableFlags::PRESENT, present);
+        (*pte).set_flags(flags);
+    }
+    tlb::flush(VirtAddr::new(addr as u64));
+}
+
+// Maps the object pages of the pool, then leaves them non-present until used
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+) -> Result<(), MapToError<Size4KiB>> {
+    let mut pool = POOL.lock();
+    for (index, slot) in pool.slots.iter_mut().enumerate() {
+        let addr = page_start(index);
+        let page = Page::containing_address(VirtAddr::new(addr as u64));
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+            slot.pte = crate::leaf_entry(physical_memory_offset, page.start_address());
+        }
+        set_present(slot.pte, addr, false);
+    }
+    drop(pool);
+
+    ENABLED.store(true, Ordering::SeqCst);
+    info!(
+        "KFENCE pool at {:#x}: {} objects, sampling 1 in {} allocations",
+        POOL_START, OBJECTS, SAMPLE_INTERVAL
+    );
+    Ok(())
+}
+
+fn should_sample(layout: &Layout) -> bool {
+    if !ENABLED.load(Ordering::Relaxed) || layout.size() > PAGE_SIZE || layout.align() > PAGE_SIZE {
+        return false;
+    }
+    // A zero-sized object would start at the end of its page, on the guard page
+    if layout.size() == 0 {
+        return false;
+    }
+    if COUNTDOWN.fetch_sub(1, Ordering::Relaxed) > 1 {
+        return false;
+    }
+    COUNTDOWN.store(SAMPLE_INTERVAL, Ordering::Relaxed);
+    true
+}
+
+unsafe fn pool_alloc(layout: Layout) -> *mut u8 {
+    interrupts::without_interrupts(|| {
+        let mut pool = POOL.lock();
+        let start = pool.next;
+        let index = match (0..OBJECTS)
+            .map(|i| (start + i) % OBJECTS)
+            .find(|&i| pool.slots[i].state != SlotState::Allocated)
+        {
+            Some(index) => index,
+            None => return ptr::null_mut(),
+        };
+        pool.next = (index + 1) % OBJECTS;
+
+        let page = page_start(index);
+        let addr = (page + PAGE_SIZE - layout.size()) & !(layout.align() - 1);
+        let slot = &mut pool.slots[index];
+        set_present(slot.pte, page, true);
+        // Everything on the page around the object is a canary, checked on free
+        ptr::write_bytes(page as *mut u8, CANARY_BYTE, PAGE_SIZE);
+
+        slot.state = SlotState::Allocated;
+        slot.addr = addr;
+        slot.size = layout.size();
+        slot.alloc_backtrace = [0; BACKTRACE_DEPTH];
+        crash::backtrace(&mut slot.alloc_backtrace);
+        addr as *mut u8
+    })
+}
+
+unsafe fn pool_free(addr: *mut u8) {
+    interrupts::without_interrupts(|| {
+        let mut pool = POOL.lock();
+        let index = (addr as usize - POOL_START) / (2 * PAGE_SIZE);
+        // The guard page after the last object belongs to no slot
+        if index >= OBJECTS {
+            panic!("KFENCE: invalid free of {:p} (trailing guard page)", addr);
+        }
+        let slot = &mut pool.slots[index];
+        if slot.state != SlotState::Allocated || slot.addr != addr as usize {
+            let what = match slot.state {
+                SlotState::Freed => "double free",
+                _ => "invalid free",
+            };
+            panic!(
+                "KFENCE: {} of {:p} (slot {}, object at {:#x}, allocated from {:#x?}, freed from {:#x?})",
+                what, addr, index, slot.addr, slot.alloc_backtrace, slot.free_backtrace
+            );
+        }
+
+        let page = page_start(index);
+        let object_end = slot.addr + slot.size;
+        for byte in (page..slot.addr).chain(object_end..page + PAGE_SIZE) {
+            if *(byte as *const u8) != CANARY_BYTE {
+                panic!(
+                    "KFENCE: memory corruption at {:#x} ({} bytes {} {}-byte object at {:#x}, allocated from {:#x?})",
+                    byte,
+                    if byte < slot.addr { slot.addr - byte } else { byte - object_end + 1 },
+                    if byte < slot.addr { "left of" } else { "right of" },
+                    slot.size,
+                    slot.addr,
+                    slot.alloc_backtrace
+                );
+            }
+        }
+
+        slot.state = SlotState::Freed;
+        slot.free_backtrace = [0; BACKTRACE_DEPTH];
+        crash::backtrace(&mut slot.free_backtrace);
+        set_present(slot.pte, page, false);
+    })
+}
+
+// Calls `f` with the address and size of every live sampled object. The pool
+// stays locked meanwhile, so the objects cannot be freed under it.
+pub fn for_each_live(mut f: impl FnMut(usize, usize)) {
+    interrupts::without_interrupts(|| {
+        let pool = POOL.lock();
+        for slot in pool.slots.iter() {
+            if slot.state == SlotState::Allocated {
+                f(slot.addr, slot.size);
+            }
+        }
+    })
+}
+
+// Called by the page fault handler. Panics with a report if the fault hit the
+// KFENCE pool, and returns otherwise.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) {
+    let addr = addr.as_u64() as usize;
+    if !in_pool(addr) || !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let access = if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        "write"
+    } else {
+        "read"
+    };
+
+    // Pool locks may be held by the faulting code
+    unsafe { POOL.force_unlock() };
+    let pool = POOL.lock();
+    let offset = addr - POOL_START;
+    let page_index = offset / PAGE_SIZE;
+
+    if page_index % 2 == 1 {
+        let slot = &pool.slots[page_index / 2];
+        if slot.state == SlotState::Unused {
+            panic!(
+                "KFENCE: {} at {:#x} in a never-used object page",
+                access, addr
+            );
+        }
+        panic!(
+            "KFENCE: use-after-free {} at {:#x} ({} bytes into freed {}-byte object at {:#x}, allocated from {:#x?}, freed from {:#x?})",
+            access,
+            addr,
+            addr.wrapping_sub(slot.addr),
+            slot.size,
+            slot.addr,
+            slot.alloc_backtrace,
+            slot.free_backtrace
+        );
+    }
+
+    // Guard page k sits between slots k - 1 and k; blame the nearest live one
+    let guard = page_index / 2;
+    let live = |i: &usize| *i < OBJECTS && pool.slots[*i].state == SlotState::Allocated;
+    let left = guard.checked_sub(1).filter(live);
+    let right = Some(guard).filter(live);
+    let index = match (left, right) {
+        (Some(l), Some(r)) => {
+            if offset % PAGE_SIZE < PAGE_SIZE / 2 {
+                l
+            } else {
+                r
+            }
+        }
+        (Some(l), None) => l,
+        (None, Some(r)) => r,
+        (None, None) => panic!(
+            "KFENCE: out-of-bounds {} at {:#x} in a guard page with no live neighbour",
+            access, addr
+        ),
+    };
+    let slot = &pool.slots[index];
+    let (distance, side) = if addr >= slot.addr + slot.size {
+        (addr - (slot.addr + slot.size) + 1, "right of")
+    } else {
+        (slot.addr - addr, "left of")
+    };
+    panic!(
+        "KFENCE: out-of-bounds {} at {:#x} ({} bytes {} {}-byte object at {:#x}, slot {}, allocated from {:#x?})",
+        access, addr, distance, side, slot.size, slot.addr, index, slot.alloc_backtrace
+    );
+}
+
+// Wraps the kernel heap and diverts sampled allocations to the KFENCE pool
+pub struct Sampling<H> {
+    inner: H,
+}
+
+impl<H> Sampling<H> {
+    pub const fn new(inner: H) -> Self {
+        Sampling { inner }
+    }
+}
+
+impl<H> Deref for Sampling<H> {
+    type Target = H;
+
+    fn deref(&self) -> &H {
+        &self.inner
+    }
+}
+
+unsafe impl<H: GlobalAlloc> GlobalAlloc for Sampling<H> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if should_sample(&layout) {
+            let ptr = pool_alloc(layout);
+            if !ptr.is_null() {
+                return ptr;
+            }
+        }
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if in_pool(ptr as usize) {
+            pool_free(ptr);
+        } else {
+            self.inner.dealloc(ptr, layout);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+// Kernel log ring buffer, keeps the most recent log output for crash reports.
+// Readers such as the virtio console stream it elsewhere with `read_since`,
+// picking up whatever they have not seen yet.
+use core::fmt::{self, Write};
+use spin::Mutex;
+
+const KLOG_SIZE: usize = 16 * 1024;
+
+pub struct LogRing {
+    buf: [u8; KLOG_SIZE],
+    // Total bytes ever written; the write position is `head % KLOG_SIZE`
+    head: usize,
+}
+
+impl LogRing {
+    const fn new() -> Self {
+        LogRing {
+            buf: [0; KLOG_SIZE],
+            head: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.buf[self.head % KLOG_SIZE] = byte;
+            self.head += 1;
+        }
+    }
+
+    // Calls `f` with the buffered contents in order, oldest first
+    pub fn for_each_chunk(&self, mut f: impl FnMut(&[u8])) {
+        if self.head <= KLOG_SIZE {
+            f(&self.buf[..self.head]);
+        } else {
+            let start = self.head % KLOG_SIZE;
+            f(&self.buf[start..]);
+            f(&self.buf[..start]);
+        }
+    }
+
+    // Copies output written after the first `pos` bytes ever written into
+    // `buf`. Returns the bytes copied and the bytes skipped because they were
+    // overwritten before being read; the next position is `pos` plus both.
+    pub fn read_since(&self, pos: usize, buf: &mut [u8]) -> (usize, usize) {
+        let start = pos.max(self.head.saturating_sub(KLOG_SIZE));
+        let len = (self.head - start).min(buf.len());
+        for (offset, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = self.buf[(start + offset) % KLOG_SIZE];
+        }
+        (len, start - pos)
+    }
+}
+
+impl Write for LogRing {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+pub static KLOG: Mutex<LogRing> = Mutex::new(LogRing::new());
+
+pub fn record(level: log::Level, args: &fmt::Arguments) {
+    crate::interrupts::without_interrupts(|| {
+        let mut ring = KLOG.lock();
+        writeln!(ring, "[{}] {}", level, args).ok();
+    });
+}
+
+// Used on the panic path, where the lock may be held by the code that panicked
+pub fn force_unlock() {
+    unsafe { KLOG.force_unlock() };
+}
@@ -0,0 +1,197 @@
+// kmemleak-style leak scanner for the debug heap.
+//
+// Conservatively marks every live allocation referenced from the kernel's
+// data and bss sections or from live KFENCE objects, which sit outside the
+// heap, then transitively from marked allocations. Whatever is left unmarked
+// has no pointer to it and is reported as a leak, grouped by allocation call
+// site. Allocations older than a threshold are reported too, since slow leaks
+// often stay referenced from some forgotten collection.
+//
+// Kernel stacks are not scanned, so run it from a context (such as the idle
+// loop) where no heap object is referenced only from the stack. `init` scans
+// once at boot and then again from the idle loop every SCAN_CYCLES, reporting
+// only when the counts have changed since the last report.
+use crate::debug_heap::{self, Header, LiveList, BACKTRACE_DEPTH};
+use crate::sync::SpinLock;
+use crate::{idle, interrupts, kfence};
+use crate::{HEAP_SIZE, HEAP_START};
+use core::arch::x86_64::_rdtsc;
+use core::mem::size_of;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const MAX_SITES: usize = 32;
+const SCAN_CYCLES: u64 = 30_000_000_000;
+
+const UNMARKED: u8 = 0;
+const MARKED: u8 = 1;
+const SCANNED: u8 = 2;
+
+extern "C" {
+    static DATA_START: u8;
+    static KERNEL_END: u8;
+}
+
+#[derive(Clone, Copy)]
+struct Site {
+    backtrace: [u64; BACKTRACE_DEPTH],
+    objects: usize,
+    bytes: usize,
+    unreferenced: usize,
+}
+
+const EMPTY_SITE: Site = Site {
+    backtrace: [0; BACKTRACE_DEPTH],
+    objects: 0,
+    bytes: 0,
+    unreferenced: 0,
+};
+
+static MIN_AGE: AtomicU64 = AtomicU64::new(u64::MAX);
+static LAST_SCAN: AtomicU64 = AtomicU64::new(0);
+// (unreferenced, long-lived) counts from the last report
+static LAST_REPORT: SpinLock<(usize, usize)> = SpinLock::new("kmemleak_report", (0, 0));
+
+// Reports allocations that are unreferenced, or that have survived at least
+// `min_age` later allocations, now and then periodically from the idle loop
+pub fn init(min_age: u64) {
+    MIN_AGE.store(min_age, Ordering::Relaxed);
+    LAST_SCAN.store(unsafe { _rdtsc() }, Ordering::Relaxed);
+    scan(min_age, false);
+    idle::register_poll(poll);
+}
+
+fn poll() {
+    let now = unsafe { _rdtsc() };
+    if now - LAST_SCAN.load(Ordering::Relaxed) < SCAN_CYCLES {
+        return;
+    }
+    LAST_SCAN.store(now, Ordering::Relaxed);
+    scan(MIN_AGE.load(Ordering::Relaxed), true);
+}
+
+fn scan(min_age: u64, only_changes: bool) {
+    let mut sites = [EMPTY_SITE; MAX_SITES];
+    let mut dropped = 0;
+    let mut unreferenced = 0;
+    let mut old = 0;
+
+    interrupts::without_interrupts(|| {
+        let live = debug_heap::live();
+        unsafe { mark(&live) };
+
+        let now = live.next_seq();
+        let mut header = live.head();
+        while !header.is_null() {
+            let block = unsafe { &*header };
+            header = block.next;
+
+            let is_leak = block.mark == UNMARKED;
+            let is_old = now - block.seq >= min_age;
+            if !is_leak && !is_old {
+                continue;
+            }
+            if is_leak {
+                unreferenced += 1;
+            } else {
+                old += 1;
+            }
+
+            let slot = sites
+                .iter()
+                .position(|s| s.objects == 0 || s.backtrace == block.backtrace);
+            match slot {
+                Some(index) => {
+                    let site = &mut sites[index];
+                    site.backtrace = block.backtrace;
+                    site.objects += 1;
+                    site.bytes += block.size;
+                    site.unreferenced += is_leak as usize;
+                }
+                None => dropped += 1,
+            }
+        }
+    });
+
+    let last = core::mem::replace(&mut *LAST_REPORT.lock(), (unreferenced, old));
+    if only_changes && last == (unreferenced, old) {
+        return;
+    }
+    if unreferenced == 0 && old == 0 {
+        info!("kmemleak: no unreferenced or long-lived objects");
+        return;
+    }
+    warn!(
+        "kmemleak: {} unreferenced object(s), {} long-lived object(s)",
+        unreferenced, old
+    );
+    for site in sites.iter().take_while(|s| s.objects > 0) {
+        warn!(
+            "kmemleak:   {} object(s) ({} bytes, {} unreferenced) allocated from {:#x?}",
+            site.objects, site.bytes, site.unreferenced, site.backtrace
+        );
+    }
+    if dropped > 0 {
+        warn!(
+            "kmemleak:   {} more object(s) from other call sites",
+            dropped
+        );
+    }
+}
+
+unsafe fn mark(live: &LiveList) {
+    let mut header = live.head();
+    while !header.is_null() {
+        (*header).mark = UNMARKED;
+        header = (*header).next;
+    }
+
+    let roots_start = addr_of!(DATA_START) as usize;
+    let roots_end = addr_of!(KERNEL_END) as usize;
+    scan_range(live, roots_start, roots_end);
+    kfence::for_each_live(|addr, size| scan_range(live, addr, addr + size));
+
+    // Scan newly marked blocks until nothing changes
+    loop {
+        let mut progress = false;
+        let mut header = live.head();
+        while !header.is_null() {
+            if (*header).mark == MARKED {
+                (*header).mark = SCANNED;
+                let start = (*header).start();
+                scan_range(live, start, start + (*header).size);
+                progress = true;
+            }
+            header = (*header).next;
+        }
+        if !progress {
+            break;
+        }
+    }
+}
+
+unsafe fn scan_range(live: &LiveList, start: usize, end: usize) {
+    let heap_end = HEAP_START + HEAP_SIZE;
+    let mut addr = (start + size_of::<usize>() - 1) & !(size_of::<usize>() - 1);
+    while addr + size_of::<usize>() <= end {
+        let value = *(addr as *const usize);
+        if (HEAP_START..heap_end).contains(&value) {
+            mark_block(live, value);
+        }
+        addr += size_of::<usize>();
+    }
+}
+
+unsafe fn mark_block(live: &LiveList, value: usize) {
+    let mut header: *mut Header = live.head();
+    while !header.is_null() {
+        let start = (*header).start();
+        if (start..start + (*header).size).contains(&value) {
+            if (*header).mark == UNMARKED {
+                (*header).mark = MARKED;
+            }
+            return;
+        }
+        header = (*header).next;
+    }
+}
@@ -8,45 +8,183 @@ extern crate log;
 
 extern crate alloc;
 
+#[cfg(feature = "audio")]
+mod audio;
+mod bench;
+mod block;
+mod bootmem;
+mod cmdline;
+#[cfg(feature = "power")]
+mod cpufreq;
+mod crash;
+mod crypto;
+#[cfg(debug_assertions)]
+mod debug_heap;
+mod device;
+#[cfg(feature = "graphics")]
+mod font;
+mod fsck;
+mod gdt;
+mod idle;
+mod input;
+mod interrupts;
+mod irqstats;
+mod kasan;
+mod keymap;
+mod kfence;
+mod klog;
+#[cfg(debug_assertions)]
+mod kmemleak;
+mod memory;
+mod memtest;
+mod msr;
+mod page_info;
+#[cfg(feature = "graphics")]
+mod panic_screen;
+mod pci;
+mod percpu;
+mod physmap;
+mod protection;
+mod ramdisk;
+mod selftest;
+mod serial;
+mod serialize;
+mod smbios;
+mod sync;
+mod subsystems;
+#[cfg(feature = "power")]
+mod thermal;
+mod timer;
+#[cfg(feature = "usb")]
+mod usb;
+#[cfg(feature = "virtio")]
+mod virtio;
+
+use bootloader_api::config::{BootloaderConfig, Mapping};
 use bootloader_api::{entry_point, BootInfo};
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use memory::cache::CacheMode;
+use memory::layout;
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
-        PhysFrame, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTable,
+        PageTableEntry, PageTableFlags, PhysFrame, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
+// Keep everything the bootloader maps in the higher half (see memory::layout)
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(layout::PHYSICAL_MEMORY_OFFSET));
+    config.mappings.boot_info = Mapping::FixedAddress(layout::BOOT_INFO);
+    config.mappings.kernel_stack = Mapping::FixedAddress(layout::KERNEL_STACK);
+    config.mappings.dynamic_range_start = Some(layout::BOOTLOADER_DYNAMIC_START);
+    config.mappings.dynamic_range_end = Some(layout::BOOTLOADER_DYNAMIC_END);
+    config
+};
+
 // Bootloader entry point
-entry_point!(kernel_main);
+entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
 
 // Kernel entry point
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    // Per-CPU area first: interrupt handlers and the serial port use it
+    percpu::init();
+    
     // Initialize logging
     init_logger();
     
     info!("Booting Rust OS...");
+    info!("Command line: {:?}", cmdline::get());
     
-    // Initialize IDT
-    init_idt();
+    // Initialize GDT/TSS and IDT
+    gdt::init();
+    interrupts::init_idt();
+    timer::init();
+    protection::init();
+    memory::cache::init();
     
     // Initialize memory management
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
-    let mut mapper = unsafe { init_memory(phys_mem_offset) };
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    let phys_mem_offset = match boot_info.physical_memory_offset.into_option() {
+        Some(offset) => VirtAddr::new(offset),
+        None => map_physical_memory(boot_info.recursive_index.into_option(), &mut frame_allocator, &boot_info.memory_regions),
+    };
+    let mut mapper = unsafe { init_memory(phys_mem_offset) };
+    if cmdline::enabled("memtest") {
+        if frame_allocator.allocated_frames().next().is_some() {
+            warn!("memtest: skipped, memory is already in use for the physical memory window");
+        } else {
+            memtest::run(phys_mem_offset, &boot_info.memory_regions);
+        }
+    }
+    physmap::init(phys_mem_offset, &mut frame_allocator, &boot_info.memory_regions);
     
     // Initialize heap
     init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    bootmem::finish();
+    kfence::init(&mut mapper, &mut frame_allocator, phys_mem_offset)
+        .expect("KFENCE pool initialization failed");
+    page_info::init(&mut mapper, &mut frame_allocator, &boot_info.memory_regions)
+        .expect("Page info initialization failed");
+    ramdisk::init(&mut mapper, &mut frame_allocator);
     
     // Test heap allocation
     test_heap_allocation();
+
+    if cmdline::enabled("selftest") {
+        selftest::run(&mut mapper, &mut frame_allocator).expect("Self-test setup failed");
+        crypto::self_test();
+        fsck::self_test();
+    }
+    if cmdline::enabled("bench") {
+        bench::run(&mut mapper, &mut frame_allocator).expect("Benchmark setup failed");
+    }
+
+    #[cfg(debug_assertions)]
+    kmemleak::init(LEAK_AGE_THRESHOLD);
     
+    match smbios::find(phys_mem_offset) {
+        Some(table) => table.log_summary(),
+        None => info!("No SMBIOS entry point found"),
+    }
+
+    keymap::init();
+    pci::scan();
+    subsystems::init(&mut subsystems::Context {
+        mapper: &mut mapper,
+        frame_allocator: &mut frame_allocator,
+        physical_memory_offset: phys_mem_offset,
+    });
+    fsck::run_at_boot();
+
     info!("Kernel initialized successfully!");
     
     // Main kernel loop
-    loop {
-        x86_64::instructions::hlt();
+    idle::run()
+}
+
+// For bootloader configurations without the physical memory window, which
+// everything here reaches memory through. Builds it from the recursive page
+// table mapping when there is one; otherwise there is no way at the page
+// tables and nothing to do but say so.
+fn map_physical_memory(
+    recursive_index: Option<u16>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    memory_regions: &[MemoryRegion],
+) -> VirtAddr {
+    let index = recursive_index.unwrap_or_else(|| {
+        panic!(
+            "The bootloader mapped neither physical memory nor a recursive page table; \
+             set mappings.physical_memory in BOOTLOADER_CONFIG"
+        )
+    });
+    warn!("Bootloader did not map physical memory, building the window from the recursive mapping");
+    match physmap::map_recursive(index, frame_allocator, memory_regions) {
+        Ok(offset) => offset,
+        Err(err) => panic!("Mapping physical memory failed: {:?}", err),
     }
 }
 
@@ -68,15 +206,81 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr
 }
 
-// Heap allocation
-use linked_list_allocator::LockedHeap;
+// Returns the level 1 entry mapping `addr`, which must be mapped with 4 KiB pages
+pub unsafe fn leaf_entry(physical_memory_offset: VirtAddr, addr: VirtAddr) -> *mut PageTableEntry {
+    let mut table = active_level_4_table(physical_memory_offset);
+    for index in [addr.p4_index(), addr.p3_index(), addr.p2_index()] {
+        let virt = physical_memory_offset + table[index].addr().as_u64();
+        table = &mut *virt.as_mut_ptr::<PageTable>();
+    }
+    &mut table[addr.p1_index()]
+}
+
+// Device MMIO mappings are handed out from this window. Registers want
+// CacheMode::Uncached; framebuffers and other memory-like BARs go faster
+// with CacheMode::WriteCombining.
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(layout::MMIO_START);
+
+pub fn map_mmio(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    phys: PhysAddr,
+    size: u64,
+    cache: CacheMode,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::<Size4KiB>::containing_address(phys + (size - 1));
+    let pages = (last.start_address() - first.start_address()) / 4096 + 1;
+    let virt = MMIO_NEXT.fetch_add(pages * 4096, Ordering::Relaxed);
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | cache.flags();
+    for (index, frame) in PhysFrame::range_inclusive(first, last).enumerate() {
+        let page = Page::containing_address(VirtAddr::new(virt + index as u64 * 4096));
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+        }
+    }
+    Ok(VirtAddr::new(virt + (phys - first.start_address())))
+}
+
+// Allocates a zeroed frame for device DMA, returning its physical address and
+// its address in the physical memory mapping
+pub fn alloc_dma_frame(
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+) -> Option<(PhysAddr, VirtAddr)> {
+    let frame = frame_allocator.allocate_frame()?;
+    if let Some(info) = page_info::get(frame) {
+        info.set_kind(page_info::Kind::Dma);
+    }
+    let phys = frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, 4096) };
+    Some((phys, virt))
+}
+
+// Heap allocation, with a sample of allocations diverted to the KFENCE pool
+#[cfg(not(debug_assertions))]
+#[global_allocator]
+static ALLOCATOR: kfence::Sampling<linked_list_allocator::LockedHeap> =
+    kfence::Sampling::new(linked_list_allocator::LockedHeap::empty());
 
+// Debug builds add redzones and poison freed memory
+#[cfg(debug_assertions)]
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: kfence::Sampling<debug_heap::DebugHeap> =
+    kfence::Sampling::new(debug_heap::DebugHeap::empty());
 
-const HEAP_START: usize = 0x4444_4444_0000;
+const HEAP_START: usize = layout::HEAP_START as usize;
 const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 
+// Allocations that survive this many later allocations are reported by kmemleak
+#[cfg(debug_assertions)]
+const LEAK_AGE_THRESHOLD: u64 = 10_000;
+
 fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
@@ -120,35 +324,6 @@ fn test_heap_allocation() {
     info!("Vector at {:p} with values: {:?}", vec.as_ptr(), vec);
 }
 
-// Interrupt handling
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
-use lazy_static::lazy_static;
-
-lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
-        idt
-    };
-}
-
-fn init_idt() {
-    IDT.load();
-    info!("IDT initialized");
-}
-
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    info!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
-
 // Frame allocator
 use bootloader_api::bootinfo::{MemoryRegion, MemoryRegionKind};
 use x86_64::structures::paging::FrameAllocator as FrameAllocatorTrait;
@@ -173,6 +348,12 @@ impl BootInfoFrameAllocator {
             .flat_map(|r| r.range.start_addr()..r.range.end_addr())
             .step_by(4096)
             .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+            .filter(|&frame| !memtest::is_bad(frame))
+    }
+
+    // Frames handed out so far
+    pub fn allocated_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
+        self.usable_frames().take(self.next)
     }
 }
 
@@ -180,6 +361,9 @@ unsafe impl FrameAllocatorTrait<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
+        if let Some(frame) = frame {
+            page_info::allocated(frame);
+        }
         frame
     }
 }
@@ -201,6 +385,8 @@ impl log::Log for SimpleLogger {
             return;
         }
 
+        klog::record(record.level(), record.args());
+
         let color_code = match record.level() {
             Level::Error => 31, // Red
             Level::Warn => 33,  // Yellow
@@ -227,7 +413,11 @@ pub fn init_logger() -> Result<(), SetLoggerError> {
 // Panic handler
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    crash::report(info);
     error!("KERNEL PANIC: {}", info);
+    // The idle loop that streams the log will not run again
+    #[cfg(feature = "console")]
+    virtio::console::flush();
     loop {
         x86_64::instructions::hlt();
     }
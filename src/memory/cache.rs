@@ -0,0 +1,65 @@
+// Page attribute table setup and the cache modes mappings can ask for. The
+// PAT is programmed so that the PWT and PCD bits of a page table entry pick:
+//
+//   neither   write-back
+//   PWT       write-combining (write-through at reset)
+//   PCD       uncached-minus
+//   both      uncached
+//
+// The upper four entries, selected by the PAT bit, are set to match and
+// left unused. Without a PAT, write-combining falls back to uncached.
+use crate::msr::{self, MemoryType};
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::PageTableFlags;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    // Writes are buffered and merged; for framebuffers, not for registers
+    WriteCombining,
+    Uncached,
+}
+
+static WRITE_COMBINING: AtomicBool = AtomicBool::new(false);
+
+const PAT: [MemoryType; 8] = [
+    MemoryType::WriteBack,
+    MemoryType::WriteCombining,
+    MemoryType::UncachedMinus,
+    MemoryType::Uncacheable,
+    MemoryType::WriteBack,
+    MemoryType::WriteCombining,
+    MemoryType::UncachedMinus,
+    MemoryType::Uncacheable,
+];
+
+impl CacheMode {
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteCombining if WRITE_COMBINING.load(Ordering::Relaxed) => {
+                PageTableFlags::WRITE_THROUGH
+            }
+            CacheMode::WriteCombining | CacheMode::Uncached => {
+                PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+            }
+        }
+    }
+}
+
+// Must run before anything is mapped with PWT alone, which means
+// write-through until the PAT is changed
+pub fn init() {
+    // Caches and TLBs may hold lines typed under the old PAT
+    unsafe { asm!("wbinvd", options(nostack, preserves_flags)) };
+    match unsafe { msr::set_pat(PAT) } {
+        Ok(()) => {
+            WRITE_COMBINING.store(true, Ordering::Relaxed);
+            info!("PAT programmed, write-combining available");
+        }
+        Err(_) => info!("No PAT, write-combining mappings will be uncached"),
+    }
+    tlb::flush_all();
+}
@@ -0,0 +1,37 @@
+// Virtual address space layout. The lower half is left entirely to user
+// space; everything the kernel maps lives in the higher half:
+//
+//   0000_0000_0000_0000 - 0000_7FFF_FFFF_FFFF  user space
+//   FFFF_8000_0000_0000 - FFFF_BFFF_FFFF_FFFF  physical memory window (64 TiB)
+//   FFFF_C000_0000_0000 - FFFF_C07F_FFFF_FFFF  kernel windows, below
+//   FFFF_FE00_0000_0000 - FFFF_FEFF_FFFF_FFFF  bootloader's own mappings
+//   FFFF_FF00_0000_0000 - ...                  boot info, kernel stack
+//   FFFF_FFFF_8000_0000 - FFFF_FFFF_FFFF_FFFF  kernel image (linker.ld)
+//
+// The kernel windows share one level 4 entry, so their page tables are set
+// up once and would be shared by every address space.
+
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+// 512 GiB aligned, which physmap needs to use 1 GiB pages
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xFFFF_8000_0000_0000;
+
+// Kernel windows, each 1 GiB aligned
+pub const HEAP_START: u64 = 0xFFFF_C000_0000_0000;
+pub const KFENCE_POOL_START: u64 = 0xFFFF_C000_4000_0000;
+pub const SELFTEST_SCRATCH_START: u64 = 0xFFFF_C000_8000_0000;
+pub const BENCH_SCRATCH_START: u64 = 0xFFFF_C000_C000_0000;
+pub const FRAMEBUFFER_START: u64 = 0xFFFF_C001_0000_0000;
+// 8 bytes per frame, so 64 GiB covers 32 TiB of memory
+pub const PAGE_INFO_START: u64 = 0xFFFF_C002_0000_0000;
+pub const RAMDISK_START: u64 = 0xFFFF_C003_0000_0000;
+// Grows up to the end of the level 4 entry
+pub const MMIO_START: u64 = 0xFFFF_C010_0000_0000;
+
+// Handed to the bootloader
+pub const BOOTLOADER_DYNAMIC_START: u64 = 0xFFFF_FE00_0000_0000;
+pub const BOOTLOADER_DYNAMIC_END: u64 = 0xFFFF_FF00_0000_0000;
+pub const BOOT_INFO: u64 = 0xFFFF_FF00_0000_0000;
+pub const KERNEL_STACK: u64 = 0xFFFF_FF00_4000_0000;
+
+pub const KERNEL_IMAGE_START: u64 = 0xFFFF_FFFF_8000_0000;
@@ -0,0 +1,4 @@
+// Memory management. Most of it still lives in the crate root and in
+// bootmem, page_info and physmap; this is where it is to move as it grows.
+pub mod cache;
+pub mod layout;
@@ -0,0 +1,143 @@
+// Boot-time memory test, run with `memtest=1` before any allocator is set
+// up. Every usable frame is written and read back through the physical
+// memory window with four patterns: each word's own address and its
+// complement (which catch address lines that alias one location to another),
+// then alternating bits both ways round. Frames that fail are kept as ranges,
+// skipped by the frame allocator and marked reserved in the page info array.
+use bootloader_api::bootinfo::{MemoryRegion, MemoryRegionKind};
+use spin::Once;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+const FRAME_SIZE: u64 = 4096;
+const MAX_BAD_RANGES: usize = 64;
+
+#[derive(Clone, Copy)]
+enum Pattern {
+    Address,
+    NotAddress,
+    Fixed(u64),
+}
+
+const PATTERNS: [Pattern; 4] = [
+    Pattern::Address,
+    Pattern::NotAddress,
+    Pattern::Fixed(0xAAAA_AAAA_AAAA_AAAA),
+    Pattern::Fixed(0x5555_5555_5555_5555),
+];
+
+impl Pattern {
+    fn value(self, addr: u64) -> u64 {
+        match self {
+            Pattern::Address => addr,
+            Pattern::NotAddress => !addr,
+            Pattern::Fixed(value) => value,
+        }
+    }
+}
+
+// Physical frame ranges that failed, as [start, end)
+struct BadRanges {
+    ranges: [(u64, u64); MAX_BAD_RANGES],
+    count: usize,
+}
+
+const NO_BAD_RANGES: BadRanges = BadRanges {
+    ranges: [(0, 0); MAX_BAD_RANGES],
+    count: 0,
+};
+
+// Set once the test has run; read for every frame the allocator considers,
+// so it takes no lock
+static BAD: Once<BadRanges> = Once::new();
+
+impl BadRanges {
+    // Once the table is full, further bad frames widen the last range, giving
+    // up the good frames in between rather than using a bad one
+    fn add(&mut self, frame: u64) {
+        if self.ranges[..self.count]
+            .iter()
+            .any(|&(start, end)| (start..end).contains(&frame))
+        {
+            return;
+        }
+        if self.count > 0 {
+            let last = &mut self.ranges[self.count - 1];
+            if last.1 == frame || self.count == MAX_BAD_RANGES {
+                last.1 = last.1.max(frame + FRAME_SIZE);
+                return;
+            }
+        }
+        self.ranges[self.count] = (frame, frame + FRAME_SIZE);
+        self.count += 1;
+    }
+}
+
+fn usable(regions: &[MemoryRegion]) -> impl Iterator<Item = &MemoryRegion> {
+    regions
+        .iter()
+        .filter(|r| r.kind == MemoryRegionKind::Usable)
+}
+
+pub fn run(physical_memory_offset: VirtAddr, regions: &[MemoryRegion]) {
+    let bytes: u64 = usable(regions)
+        .map(|r| r.range.end_addr() - r.range.start_addr())
+        .sum();
+    info!("memtest: testing {} MiB", bytes >> 20);
+
+    let mut bad = NO_BAD_RANGES;
+    for (pass, pattern) in PATTERNS.iter().enumerate() {
+        // Write everything before reading anything back, so a write that
+        // lands somewhere else shows up
+        for region in usable(regions) {
+            for addr in (region.range.start_addr()..region.range.end_addr()).step_by(8) {
+                let word = (physical_memory_offset + addr).as_mut_ptr::<u64>();
+                unsafe { word.write_volatile(pattern.value(addr)) };
+            }
+        }
+        for region in usable(regions) {
+            for addr in (region.range.start_addr()..region.range.end_addr()).step_by(8) {
+                let word = (physical_memory_offset + addr).as_ptr::<u64>();
+                if unsafe { word.read_volatile() } != pattern.value(addr) {
+                    bad.add(addr & !(FRAME_SIZE - 1));
+                }
+            }
+        }
+        info!("memtest: pass {} of {} done", pass + 1, PATTERNS.len());
+    }
+
+    let bad = BAD.call_once(|| bad);
+    if bad.count == 0 {
+        info!("memtest: no errors found");
+        return;
+    }
+    let mut frames = 0;
+    for &(start, end) in bad.ranges[..bad.count].iter() {
+        warn!("memtest: bad memory at {:#x}-{:#x}", start, end - 1);
+        frames += (end - start) / FRAME_SIZE;
+    }
+    warn!(
+        "memtest: {} bad frames ({} KiB) reserved",
+        frames,
+        frames * FRAME_SIZE / 1024
+    );
+}
+
+// Whether the frame failed the test
+pub fn is_bad(frame: PhysFrame) -> bool {
+    let addr = frame.start_address().as_u64();
+    BAD.get().map_or(false, |bad| {
+        bad.ranges[..bad.count]
+            .iter()
+            .any(|&(start, end)| (start..end).contains(&addr))
+    })
+}
+
+// Calls `f` with every bad frame range
+pub fn for_each_bad(mut f: impl FnMut(u64, u64)) {
+    if let Some(bad) = BAD.get() {
+        for &(start, end) in bad.ranges[..bad.count].iter() {
+            f(start, end);
+        }
+    }
+}
@@ -0,0 +1,271 @@
+// Model-specific registers. The architectural registers the kernel uses get
+// typed wrappers that check CPUID for the register before touching it;
+// everything else goes through `read`/`write`, which only check that the CPU
+// has MSRs at all, so their callers check for the feature behind the
+// register themselves. Register numbers live here rather than in the
+// subsystems using them.
+use core::arch::global_asm;
+use core::arch::x86_64::__cpuid;
+use core::ptr::addr_of;
+use x86_64::registers::model_specific::{EferFlags, Msr};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const IA32_APIC_BASE: u32 = 0x1B;
+pub const MSR_PLATFORM_INFO: u32 = 0xCE;
+pub const IA32_PERF_STATUS: u32 = 0x198;
+pub const IA32_PERF_CTL: u32 = 0x199;
+pub const IA32_THERM_STATUS: u32 = 0x19C;
+pub const IA32_MISC_ENABLE: u32 = 0x1A0;
+pub const MSR_TEMPERATURE_TARGET: u32 = 0x1A2;
+pub const IA32_PACKAGE_THERM_STATUS: u32 = 0x1B1;
+pub const IA32_PAT: u32 = 0x277;
+pub const IA32_EFER: u32 = 0xC000_0080;
+pub const IA32_STAR: u32 = 0xC000_0081;
+pub const IA32_LSTAR: u32 = 0xC000_0082;
+pub const IA32_TSC_AUX: u32 = 0xC000_0103;
+pub const AMD_PSTATE_LIMIT: u32 = 0xC001_0061;
+pub const AMD_PSTATE_CTL: u32 = 0xC001_0062;
+pub const AMD_PSTATE_STATUS: u32 = 0xC001_0063;
+pub const AMD_PSTATE_DEF_BASE: u32 = 0xC001_0064;
+
+// IA32_MISC_ENABLE bits
+pub const MISC_FAST_STRINGS: u64 = 1 << 0;
+pub const MISC_ENHANCED_SPEEDSTEP: u64 = 1 << 16;
+pub const MISC_MONITOR: u64 = 1 << 18;
+pub const MISC_TURBO_DISABLE: u64 = 1 << 38;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsrError {
+    // The CPU does not have the register
+    Unsupported,
+}
+
+// CPUID.01H:EDX, 80000001H:EDX
+const EDX_MSR: u32 = 1 << 5;
+const EDX_APIC: u32 = 1 << 9;
+const EDX_PAT: u32 = 1 << 16;
+const EXT_EDX_SYSCALL: u32 = 1 << 11;
+const EXT_EDX_RDTSCP: u32 = 1 << 27;
+
+fn has(leaf: u32, edx_bit: u32) -> bool {
+    unsafe { __cpuid(leaf & 0x8000_0000) }.eax >= leaf
+        && unsafe { __cpuid(leaf) }.edx & edx_bit != 0
+}
+
+fn check(supported: bool) -> Result<(), MsrError> {
+    if supported && has(1, EDX_MSR) {
+        Ok(())
+    } else {
+        Err(MsrError::Unsupported)
+    }
+}
+
+fn is_intel() -> bool {
+    let leaf = unsafe { __cpuid(0) };
+    // "GenuineIntel" in EBX, EDX, ECX
+    (leaf.ebx, leaf.edx, leaf.ecx) == (0x756E_6547, 0x4965_6E69, 0x6C65_746E)
+}
+
+// Reading some registers has side effects, and reading one the CPU does not
+// have faults; see `probe`
+pub unsafe fn read(msr: u32) -> Result<u64, MsrError> {
+    check(true)?;
+    Ok(Msr::new(msr).read())
+}
+
+// RDMSR that returns false instead of faulting when the register does not
+// exist. The #GP handler sends a fault at `msr_probe_rdmsr` to
+// `msr_probe_fault`; nothing has been pushed by then, so only RIP changes.
+global_asm!(
+    ".global msr_probe, msr_probe_rdmsr, msr_probe_fault",
+    "msr_probe:",
+    "    mov ecx, edi",
+    "msr_probe_rdmsr:",
+    "    rdmsr",
+    "    shl rdx, 32",
+    "    or rax, rdx",
+    "    mov [rsi], rax",
+    "    mov eax, 1",
+    "    ret",
+    "msr_probe_fault:",
+    "    xor eax, eax",
+    "    ret",
+);
+
+extern "sysv64" {
+    fn msr_probe(msr: u32, value: *mut u64) -> bool;
+}
+
+extern "C" {
+    static msr_probe_rdmsr: u8;
+    static msr_probe_fault: u8;
+}
+
+// For registers that only some models have, where CPUID does not say whether
+// they are there
+pub unsafe fn probe(msr: u32) -> Result<u64, MsrError> {
+    check(true)?;
+    let mut value = 0;
+    if msr_probe(msr, &mut value) {
+        Ok(value)
+    } else {
+        Err(MsrError::Unsupported)
+    }
+}
+
+// Called by the #GP handler; resumes a faulting `probe` with its error path
+pub fn fixup(stack_frame: &mut InterruptStackFrame) -> bool {
+    let (rdmsr, fault) = unsafe { (addr_of!(msr_probe_rdmsr), addr_of!(msr_probe_fault)) };
+    if stack_frame.instruction_pointer.as_ptr() != rdmsr {
+        return false;
+    }
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::from_ptr(fault);
+        });
+    }
+    true
+}
+
+pub unsafe fn write(msr: u32, value: u64) -> Result<(), MsrError> {
+    check(true)?;
+    Msr::new(msr).write(value);
+    Ok(())
+}
+
+pub fn efer() -> Result<EferFlags, MsrError> {
+    check(true)?;
+    Ok(EferFlags::from_bits_truncate(unsafe {
+        Msr::new(IA32_EFER).read()
+    }))
+}
+
+pub unsafe fn set_efer(flags: EferFlags) -> Result<(), MsrError> {
+    write(IA32_EFER, flags.bits())
+}
+
+// SYSCALL/SYSRET segment selector bases: (SYSCALL CS, SYSRET CS)
+pub fn star() -> Result<(u16, u16), MsrError> {
+    check(has(0x8000_0001, EXT_EDX_SYSCALL))?;
+    let star = unsafe { Msr::new(IA32_STAR).read() };
+    Ok(((star >> 32) as u16, (star >> 48) as u16))
+}
+
+pub unsafe fn set_star(syscall_cs: u16, sysret_cs: u16) -> Result<(), MsrError> {
+    check(has(0x8000_0001, EXT_EDX_SYSCALL))?;
+    let low = Msr::new(IA32_STAR).read() & 0xFFFF_FFFF;
+    Msr::new(IA32_STAR).write(low | (syscall_cs as u64) << 32 | (sysret_cs as u64) << 48);
+    Ok(())
+}
+
+// 64-bit SYSCALL entry point
+pub fn lstar() -> Result<VirtAddr, MsrError> {
+    check(has(0x8000_0001, EXT_EDX_SYSCALL))?;
+    Ok(VirtAddr::new_truncate(unsafe {
+        Msr::new(IA32_LSTAR).read()
+    }))
+}
+
+pub unsafe fn set_lstar(entry: VirtAddr) -> Result<(), MsrError> {
+    check(has(0x8000_0001, EXT_EDX_SYSCALL))?;
+    Msr::new(IA32_LSTAR).write(entry.as_u64());
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ApicBase {
+    pub base: PhysAddr,
+    pub bsp: bool,
+    pub x2apic: bool,
+    pub enabled: bool,
+}
+
+pub fn apic_base() -> Result<ApicBase, MsrError> {
+    check(has(1, EDX_APIC))?;
+    let value = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    Ok(ApicBase {
+        base: PhysAddr::new_truncate(value & !0xFFF),
+        bsp: value & (1 << 8) != 0,
+        x2apic: value & (1 << 10) != 0,
+        enabled: value & (1 << 11) != 0,
+    })
+}
+
+pub unsafe fn set_apic_base(apic: ApicBase) -> Result<(), MsrError> {
+    check(has(1, EDX_APIC))?;
+    let mut value = apic.base.as_u64() & !0xFFF;
+    value |= (apic.bsp as u64) << 8 | (apic.x2apic as u64) << 10 | (apic.enabled as u64) << 11;
+    Msr::new(IA32_APIC_BASE).write(value);
+    Ok(())
+}
+
+// Intel only; see the MISC_* bits
+pub fn misc_enable() -> Result<u64, MsrError> {
+    check(is_intel())?;
+    Ok(unsafe { Msr::new(IA32_MISC_ENABLE).read() })
+}
+
+pub unsafe fn set_misc_enable(value: u64) -> Result<(), MsrError> {
+    check(is_intel())?;
+    Msr::new(IA32_MISC_ENABLE).write(value);
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemoryType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtected = 5,
+    WriteBack = 6,
+    UncachedMinus = 7,
+}
+
+impl MemoryType {
+    fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0 => Some(MemoryType::Uncacheable),
+            1 => Some(MemoryType::WriteCombining),
+            4 => Some(MemoryType::WriteThrough),
+            5 => Some(MemoryType::WriteProtected),
+            6 => Some(MemoryType::WriteBack),
+            7 => Some(MemoryType::UncachedMinus),
+            _ => None,
+        }
+    }
+}
+
+// The eight PAT entries; None for reserved encodings
+pub fn pat() -> Result<[Option<MemoryType>; 8], MsrError> {
+    check(has(1, EDX_PAT))?;
+    let value = unsafe { Msr::new(IA32_PAT).read() };
+    let mut entries = [None; 8];
+    for (index, entry) in entries.iter_mut().enumerate() {
+        *entry = MemoryType::from_bits((value >> (index * 8)) as u8 & 0x7);
+    }
+    Ok(entries)
+}
+
+// Changing an entry changes the caching of every mapping using it
+pub unsafe fn set_pat(entries: [MemoryType; 8]) -> Result<(), MsrError> {
+    check(has(1, EDX_PAT))?;
+    let value = entries.iter().enumerate().fold(0, |value, (index, &kind)| {
+        value | (kind as u64) << (index * 8)
+    });
+    Msr::new(IA32_PAT).write(value);
+    Ok(())
+}
+
+// Value RDTSCP and RDPID return, conventionally the CPU number
+pub fn tsc_aux() -> Result<u32, MsrError> {
+    check(has(0x8000_0001, EXT_EDX_RDTSCP))?;
+    Ok(unsafe { Msr::new(IA32_TSC_AUX).read() } as u32)
+}
+
+pub unsafe fn set_tsc_aux(value: u32) -> Result<(), MsrError> {
+    check(has(0x8000_0001, EXT_EDX_RDTSCP))?;
+    Msr::new(IA32_TSC_AUX).write(value as u64);
+    Ok(())
+}
@@ -0,0 +1,154 @@
+// Per-frame metadata: one PageInfo for every physical frame below the end of
+// the highest usable memory region, holding a reference count and what the
+// frame is used for. The array sits in frames taken from the frame allocator
+// and mapped at ARRAY_START; frames allocated after `init` are recorded as
+// they are handed out. This is the base for sharing frames (copy-on-write,
+// shared memory) and for accounting memory by use.
+use crate::memory::layout;
+use crate::BootInfoFrameAllocator;
+use bootloader_api::bootinfo::{MemoryRegion, MemoryRegionKind};
+use core::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+const ARRAY_START: u64 = layout::PAGE_INFO_START;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Kind {
+    // Not usable RAM, or a hole between regions. Zero, so the array starts
+    // out this way.
+    Reserved = 0,
+    Free,
+    // Allocated for the kernel: heap, page tables, KFENCE pool and so on
+    Kernel,
+    // Handed to a device for DMA
+    Dma,
+}
+
+const KINDS: [Kind; 4] = [Kind::Reserved, Kind::Free, Kind::Kernel, Kind::Dma];
+
+#[repr(C)]
+pub struct PageInfo {
+    refcount: AtomicU32,
+    kind: AtomicU8,
+}
+
+// Number of entries in the array; zero until `init` has run
+static FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+impl PageInfo {
+    pub fn kind(&self) -> Kind {
+        KINDS[self.kind.load(Ordering::Relaxed) as usize]
+    }
+
+    pub fn set_kind(&self, kind: Kind) {
+        self.kind.store(kind as u8, Ordering::Relaxed);
+    }
+
+    pub fn refcount(&self) -> u32 {
+        self.refcount.load(Ordering::Acquire)
+    }
+
+    // Takes another reference and returns the new count
+    pub fn get(&self) -> u32 {
+        self.refcount.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    // Drops a reference and returns the new count; at zero the frame is
+    // unused
+    pub fn put(&self) -> u32 {
+        let old = self.refcount.fetch_sub(1, Ordering::AcqRel);
+        assert!(old != 0, "page_info: reference count underflow");
+        old - 1
+    }
+}
+
+pub fn get(frame: PhysFrame) -> Option<&'static PageInfo> {
+    let index = (frame.start_address().as_u64() / 4096) as usize;
+    if index >= FRAMES.load(Ordering::Acquire) {
+        return None;
+    }
+    let array = ARRAY_START as *const PageInfo;
+    Some(unsafe { &*array.add(index) })
+}
+
+// Called by the frame allocator for every frame it hands out
+pub fn allocated(frame: PhysFrame) {
+    if let Some(info) = get(frame) {
+        info.set_kind(Kind::Kernel);
+        info.refcount.store(1, Ordering::Release);
+    }
+}
+
+pub fn count(kind: Kind) -> usize {
+    let frames = FRAMES.load(Ordering::Acquire);
+    (0..frames as u64)
+        .filter_map(|index| get(PhysFrame::containing_address(PhysAddr::new(index * 4096))))
+        .filter(|info| info.kind() == kind)
+        .count()
+}
+
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    memory_regions: &[MemoryRegion],
+) -> Result<(), MapToError<Size4KiB>> {
+    let end = memory_regions
+        .iter()
+        .filter(|r| r.kind == MemoryRegionKind::Usable)
+        .map(|r| r.range.end_addr())
+        .max()
+        .unwrap_or(0);
+    let frames = (end / 4096) as usize;
+    let bytes = frames * core::mem::size_of::<PageInfo>();
+    let pages = (bytes + 4095) / 4096;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for index in 0..pages as u64 {
+        let page = Page::containing_address(VirtAddr::new(ARRAY_START + index * 4096));
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+            core::ptr::write_bytes(page.start_address().as_mut_ptr::<u8>(), 0, 4096);
+        }
+    }
+    FRAMES.store(frames, Ordering::Release);
+
+    // Everything usable is free, except frames that failed the memory test
+    // and what the allocator has already handed out, including the array
+    // itself
+    for region in memory_regions
+        .iter()
+        .filter(|r| r.kind == MemoryRegionKind::Usable)
+    {
+        for addr in (region.range.start_addr()..region.range.end_addr()).step_by(4096) {
+            if let Some(info) = get(PhysFrame::containing_address(PhysAddr::new(addr))) {
+                info.set_kind(Kind::Free);
+            }
+        }
+    }
+    crate::memtest::for_each_bad(|start, end| {
+        for addr in (start..end).step_by(4096) {
+            if let Some(info) = get(PhysFrame::containing_address(PhysAddr::new(addr))) {
+                info.set_kind(Kind::Reserved);
+            }
+        }
+    });
+    for frame in frame_allocator.allocated_frames() {
+        allocated(frame);
+    }
+
+    info!(
+        "Page info: {} frames ({} KiB of metadata), {} free, {} kernel",
+        frames,
+        pages * 4,
+        count(Kind::Free),
+        count(Kind::Kernel)
+    );
+    Ok(())
+}
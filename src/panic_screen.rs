@@ -0,0 +1,134 @@
+// Full-screen panic view on the virtio-gpu display. Drawn from scratch into
+// the back buffer and flipped, so it does not depend on whatever was on
+// screen before. Shows the panic message, registers and backtrace, and points
+// at the serial port for the full report.
+use crate::crash::Registers;
+use crate::font;
+use crate::virtio::gpu;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+const BACKGROUND: u32 = 0x0000_3C8C;
+const FOREGROUND: u32 = 0x00FF_FFFF;
+const MARGIN: u32 = 16;
+
+struct Screen<'a> {
+    pixels: &'a mut [u32],
+    width: u32,
+    height: u32,
+    // Pixels per font pixel
+    scale: u32,
+    x: u32,
+    y: u32,
+    foreground: u32,
+    background: u32,
+}
+
+impl Screen<'_> {
+    fn line_height(&self) -> u32 {
+        (font::HEIGHT + 2) * self.scale
+    }
+
+    fn newline(&mut self) {
+        self.x = MARGIN;
+        self.y += self.line_height();
+    }
+
+    fn put(&mut self, c: char) {
+        let advance = font::WIDTH * self.scale;
+        if self.x + advance > self.width - MARGIN {
+            self.newline();
+        }
+        // Whatever does not fit below is dropped; the serial report has it
+        if self.y + self.line_height() > self.height {
+            return;
+        }
+        for (row, &bits) in font::glyph(c).iter().enumerate() {
+            for column in 0..font::WIDTH {
+                let color = if bits >> column & 1 != 0 {
+                    self.foreground
+                } else {
+                    self.background
+                };
+                let x = self.x + column * self.scale;
+                let y = self.y + row as u32 * self.scale;
+                for dy in 0..self.scale {
+                    let start = ((y + dy) * self.width + x) as usize;
+                    self.pixels[start..start + self.scale as usize].fill(color);
+                }
+            }
+        }
+        self.x += advance;
+    }
+
+    // A full-width band in inverted colors
+    fn banner(&mut self, text: &str) {
+        let top = self.y.saturating_sub(self.scale);
+        let bottom = (self.y + self.line_height()).min(self.height);
+        self.pixels[(top * self.width) as usize..(bottom * self.width) as usize].fill(FOREGROUND);
+        self.foreground = BACKGROUND;
+        self.background = FOREGROUND;
+        self.write_str(text).ok();
+        self.foreground = FOREGROUND;
+        self.background = BACKGROUND;
+        self.newline();
+    }
+}
+
+impl Write for Screen<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '\n' => self.newline(),
+                c => self.put(c),
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn show(info: &PanicInfo, registers: &Registers, frames: &[u64]) {
+    gpu::force_unlock();
+    let drawn = gpu::draw(|pixels, width, height| {
+        pixels.fill(BACKGROUND);
+        let mut screen = Screen {
+            pixels,
+            width,
+            height,
+            scale: if width >= 1024 { 2 } else { 1 },
+            x: MARGIN,
+            y: MARGIN,
+            foreground: FOREGROUND,
+            background: BACKGROUND,
+        };
+        screen.banner(" KERNEL PANIC ");
+        screen.newline();
+        writeln!(screen, "{}", info).ok();
+        screen.newline();
+
+        writeln!(
+            screen,
+            "RSP    {:#018x}  RBP {:#018x}\nRFLAGS {:#018x}  CR2 {:#018x}\nCR3    {:#018x}",
+            registers.rsp, registers.rbp, registers.rflags, registers.cr2, registers.cr3
+        )
+        .ok();
+        screen.newline();
+
+        writeln!(screen, "Backtrace:").ok();
+        for (index, ret) in frames.iter().enumerate() {
+            writeln!(screen, "  #{:<2} {:#018x}", index, ret).ok();
+        }
+        screen.newline();
+
+        writeln!(
+            screen,
+            "The full crash report, with memory and interrupt statistics and the\n\
+             recent log, was written to the serial port (COM1).\n\
+             The system is halted; restart the machine to continue."
+        )
+        .ok();
+    });
+    if drawn.is_ok() {
+        gpu::flip().ok();
+    }
+}
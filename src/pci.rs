@@ -0,0 +1,271 @@
+// PCI configuration space access (mechanism #1, ports 0xCF8/0xCFC) and bus
+// enumeration
+use crate::device::{self, Bus, DeviceId};
+use crate::sync::SpinLock;
+use alloc::format;
+use alloc::vec::Vec;
+use x86_64::instructions::port::Port;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+static CONFIG_LOCK: SpinLock<()> = SpinLock::new("pci_config", ());
+static DEVICES: SpinLock<Vec<PciDevice>> = SpinLock::new("pci_devices", Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub header_type: u8,
+    // Node in the device model, assigned by `scan`
+    pub node: DeviceId,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Bar {
+    Memory {
+        base: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+impl PciAddress {
+    fn config_address(&self, offset: u8) -> u32 {
+        1 << 31
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset as u32 & 0xFC)
+    }
+
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        let _guard = CONFIG_LOCK.lock();
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).read()
+        }
+    }
+
+    pub fn write_u32(&self, offset: u8, value: u32) {
+        let _guard = CONFIG_LOCK.lock();
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).write(value);
+        }
+    }
+
+    pub fn read_u16(&self, offset: u8) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(&self, offset: u8) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn write_u16(&self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        // Status shares a dword with Command and its error bits are cleared by
+        // writing ones, so it is written as zero rather than read back
+        let other = if offset & 0xFC == 0x04 && shift == 0 {
+            0
+        } else {
+            self.read_u32(offset) & !(0xFFFF << shift)
+        };
+        self.write_u32(offset, other | (value as u32) << shift);
+    }
+}
+
+impl PciDevice {
+    // Decodes BAR `index`, sizing it by writing all ones
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        if self.header_type & 0x7F != 0 || index > 5 {
+            return None;
+        }
+        // While a BAR holds all ones the device would decode a bogus window,
+        // so decoding is off until every BAR written is restored
+        let command = self.address.read_u16(0x04);
+        self.address
+            .write_u16(0x04, command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+        let bar = self.size_bar(0x10 + index * 4);
+        self.address.write_u16(0x04, command);
+        bar
+    }
+
+    fn size_bar(&self, offset: u8) -> Option<Bar> {
+        let addr = self.address;
+        let original = addr.read_u32(offset);
+
+        if original & 1 == 1 {
+            addr.write_u32(offset, 0xFFFF_FFFF);
+            let mask = addr.read_u32(offset);
+            addr.write_u32(offset, original);
+            return Some(Bar::Io {
+                port: (original & !0x3) as u16,
+                size: (!(mask & !0x3)).wrapping_add(1) & 0xFFFF,
+            });
+        }
+
+        let is_64bit = (original >> 1) & 0x3 == 0x2;
+        let prefetchable = original & (1 << 3) != 0;
+        addr.write_u32(offset, 0xFFFF_FFFF);
+        let mask_low = addr.read_u32(offset);
+        addr.write_u32(offset, original);
+
+        let (base, mask) = if is_64bit {
+            let original_high = addr.read_u32(offset + 4);
+            addr.write_u32(offset + 4, 0xFFFF_FFFF);
+            let mask_high = addr.read_u32(offset + 4);
+            addr.write_u32(offset + 4, original_high);
+            (
+                (original_high as u64) << 32 | (original & !0xF) as u64,
+                (mask_high as u64) << 32 | (mask_low & !0xF) as u64,
+            )
+        } else {
+            (
+                (original & !0xF) as u64,
+                0xFFFF_FFFF_0000_0000 | (mask_low & !0xF) as u64,
+            )
+        };
+        if mask == 0xFFFF_FFFF_0000_0000 {
+            return None;
+        }
+        Some(Bar::Memory {
+            base,
+            size: !mask + 1,
+            prefetchable,
+        })
+    }
+
+    // Lets the device decode I/O and memory accesses and master DMA
+    pub fn enable_bus_master(&self) {
+        let command = self.address.read_u16(0x04);
+        self.address.write_u16(
+            0x04,
+            command | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER,
+        );
+    }
+
+    // Capability list as (capability ID, config space offset) pairs
+    pub fn capabilities(&self) -> Vec<(u8, u8)> {
+        let mut capabilities = Vec::new();
+        if self.address.read_u16(0x06) & STATUS_CAPABILITIES == 0 {
+            return capabilities;
+        }
+        let mut offset = self.address.read_u8(0x34) & !0x3;
+        // Bounded in case of a malformed, looping list
+        while offset != 0 && capabilities.len() < 48 {
+            capabilities.push((self.address.read_u8(offset), offset));
+            offset = self.address.read_u8(offset + 1) & !0x3;
+        }
+        capabilities
+    }
+}
+
+fn probe(address: PciAddress) -> Option<PciDevice> {
+    let id = address.read_u32(0x00);
+    if id & 0xFFFF == 0xFFFF {
+        return None;
+    }
+    let class = address.read_u32(0x08);
+    Some(PciDevice {
+        address,
+        vendor_id: id as u16,
+        device_id: (id >> 16) as u16,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+        header_type: address.read_u8(0x0E),
+        node: 0,
+    })
+}
+
+// Brute-force scan of every bus, device and function
+pub fn scan() {
+    let mut found = Vec::new();
+    for bus in 0..=255u8 {
+        for device in 0..32u8 {
+            let first = PciAddress {
+                bus,
+                device,
+                function: 0,
+            };
+            let header = match probe(first) {
+                Some(header) => header,
+                None => continue,
+            };
+            found.push(header);
+            if header.header_type & 0x80 == 0 {
+                continue;
+            }
+            for function in 1..8u8 {
+                let address = PciAddress {
+                    bus,
+                    device,
+                    function,
+                };
+                if let Some(header) = probe(address) {
+                    found.push(header);
+                }
+            }
+        }
+    }
+
+    for device in found.iter_mut() {
+        let name = format!(
+            "{:02x}:{:02x}.{}",
+            device.address.bus, device.address.device, device.address.function
+        );
+        device.node = device::add(name, Bus::Pci, None).id;
+        info!(
+            "PCI {:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}{:02x}{:02x}",
+            device.address.bus,
+            device.address.device,
+            device.address.function,
+            device.vendor_id,
+            device.device_id,
+            device.class,
+            device.subclass,
+            device.prog_if
+        );
+    }
+    *DEVICES.lock() = found;
+}
+
+pub fn find_by_class(class: u8, subclass: u8, prog_if: u8) -> Vec<PciDevice> {
+    DEVICES
+        .lock()
+        .iter()
+        .filter(|d| d.class == class && d.subclass == subclass && d.prog_if == prog_if)
+        .copied()
+        .collect()
+}
+
+pub fn find_by_id(vendor_id: u16, device_id: u16) -> Vec<PciDevice> {
+    DEVICES
+        .lock()
+        .iter()
+        .filter(|d| d.vendor_id == vendor_id && d.device_id == device_id)
+        .copied()
+        .collect()
+}
@@ -0,0 +1,124 @@
+// Per-CPU variables. `per_cpu!` statics go into the .percpu section, and each
+// CPU gets its own copy of that section; GS holds the base of the running
+// CPU's copy, whose first word points back at itself so one `mov` from gs:0
+// finds it. A variable is reached at the same offset from that base as the
+// static is from the start of the section. The boot CPU uses the section
+// itself as its copy; application processors will get theirs copied from it
+// when they are brought up.
+//
+// Values are only ever touched from their own CPU, so `with` only has to keep
+// this CPU's interrupt handlers out, which it does by disabling interrupts.
+// Types that are Sync on their own, such as atomic counters, can be used
+// through `local` without that, and read across all CPUs with `for_each_cpu`.
+use crate::interrupts;
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::registers::model_specific::GsBase;
+use x86_64::VirtAddr;
+
+pub const MAX_CPUS: usize = 16;
+
+extern "C" {
+    static __percpu_start: u8;
+}
+
+#[repr(C)]
+struct Header {
+    // Base of this copy, for reading with a single instruction
+    base: usize,
+    cpu: usize,
+}
+
+#[used]
+#[link_section = ".percpu.header"]
+static HEADER: PerCpu<Header> = PerCpu::new(Header { base: 0, cpu: 0 });
+
+// Base of each CPU's copy, zero where the CPU is not up
+static AREAS: [AtomicUsize; MAX_CPUS] = {
+    const NONE: AtomicUsize = AtomicUsize::new(0);
+    [NONE; MAX_CPUS]
+};
+
+pub struct PerCpu<T> {
+    value: UnsafeCell<T>,
+}
+
+// Every CPU has its own copy, and `with` keeps handlers on the same CPU out
+unsafe impl<T> Sync for PerCpu<T> {}
+
+#[macro_export]
+macro_rules! per_cpu {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;) => {
+        $(#[$attr])*
+        #[link_section = ".percpu"]
+        $vis static $name: $crate::percpu::PerCpu<$ty> = $crate::percpu::PerCpu::new($init);
+    };
+}
+
+fn section_start() -> usize {
+    unsafe { addr_of!(__percpu_start) as usize }
+}
+
+fn base() -> usize {
+    let base: usize;
+    unsafe { asm!("mov {}, gs:[0]", out(reg) base, options(nostack, readonly, preserves_flags)) };
+    base
+}
+
+impl<T> PerCpu<T> {
+    pub const fn new(value: T) -> Self {
+        PerCpu {
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    fn offset(&'static self) -> usize {
+        self as *const Self as usize - section_start()
+    }
+
+    // Runs `f` on this CPU's copy with interrupts disabled
+    pub fn with<R>(&'static self, f: impl FnOnce(&mut T) -> R) -> R {
+        interrupts::without_interrupts(|| {
+            let value = (base() + self.offset()) as *mut T;
+            f(unsafe { &mut *value })
+        })
+    }
+}
+
+impl<T: Sync> PerCpu<T> {
+    // This CPU's copy
+    pub fn local(&'static self) -> &'static T {
+        unsafe { &*((base() + self.offset()) as *const T) }
+    }
+
+    // Calls `f` with every CPU's copy
+    pub fn for_each_cpu(&'static self, mut f: impl FnMut(usize, &T)) {
+        for (cpu, area) in AREAS.iter().enumerate() {
+            let area = area.load(Ordering::Acquire);
+            if area != 0 {
+                f(cpu, unsafe { &*((area + self.offset()) as *const T) });
+            }
+        }
+    }
+}
+
+// Number of the CPU this runs on
+pub fn cpu() -> usize {
+    HEADER.with(|header| header.cpu)
+}
+
+// Sets up the boot CPU's copy. Has to run before anything touches a per-CPU
+// variable, exception handlers and serial output included, so it runs before
+// the logger is up and logs nothing.
+pub fn init() {
+    let start = section_start();
+    let header = start as *mut Header;
+    unsafe {
+        (*header).base = start;
+        (*header).cpu = 0;
+    }
+    GsBase::write(VirtAddr::new(start as u64));
+    AREAS[0].store(start, Ordering::Release);
+}
@@ -0,0 +1,173 @@
+// Rebuilds the bootloader's physical memory window with 1 GiB pages when the
+// CPU has them, and 2 MiB pages otherwise. The bootloader maps it with 4 KiB
+// pages, which costs a page table per 2 MiB of memory and a TLB entry per
+// 4 KiB touched. New tables are filled in through the old mapping and then
+// swapped in a level 4 entry at a time; both map the same addresses, so the
+// switch is safe while the old tables are still in use. The old tables are
+// left behind, unreferenced.
+//
+// With bootloader configurations that have no window at all, `map_recursive`
+// builds one through the recursive level 4 entry instead.
+use crate::memory::layout;
+use bootloader_api::bootinfo::MemoryRegion;
+use core::arch::x86_64::__cpuid;
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, Page, PageTable, PageTableFlags, PageTableIndex,
+    PhysFrame, RecursivePageTable, Size2MiB, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+const SIZE_2MIB: u64 = 1 << 21;
+const SIZE_1GIB: u64 = 1 << 30;
+// Span of one level 4 entry
+const SIZE_512GIB: u64 = 1 << 39;
+
+// CPUID.80000001H:EDX.Page1GB[bit 26]
+fn gigantic_pages_supported() -> bool {
+    unsafe { __cpuid(0x8000_0000) }.eax >= 0x8000_0001
+        && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0
+}
+
+unsafe fn table(physical_memory_offset: VirtAddr, phys: PhysAddr) -> &'static mut PageTable {
+    &mut *(physical_memory_offset + phys.as_u64()).as_mut_ptr::<PageTable>()
+}
+
+fn new_table(
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+) -> Option<(PhysAddr, &'static mut PageTable)> {
+    let phys = frame_allocator.allocate_frame()?.start_address();
+    let table = unsafe { table(physical_memory_offset, phys) };
+    table.zero();
+    Some((phys, table))
+}
+
+// Builds the level 3 table for the 512 GiB of the window starting at
+// physical address `base`
+fn build(
+    base: u64,
+    end: u64,
+    gigantic: bool,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+) -> Option<PhysAddr> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let huge = flags | PageTableFlags::HUGE_PAGE;
+    let (p3_phys, p3) = new_table(frame_allocator, physical_memory_offset)?;
+    for (slot, start) in (base..end)
+        .step_by(SIZE_1GIB as usize)
+        .take(512)
+        .enumerate()
+    {
+        if gigantic {
+            p3[slot].set_addr(PhysAddr::new(start), huge);
+            continue;
+        }
+        let (p2_phys, p2) = new_table(frame_allocator, physical_memory_offset)?;
+        for (slot, start) in (start..end)
+            .step_by(SIZE_2MIB as usize)
+            .take(512)
+            .enumerate()
+        {
+            p2[slot].set_addr(PhysAddr::new(start), huge);
+        }
+        p3[slot].set_addr(p2_phys, flags);
+    }
+    Some(p3_phys)
+}
+
+pub fn init(
+    physical_memory_offset: VirtAddr,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    memory_regions: &[MemoryRegion],
+) {
+    let end = memory_regions
+        .iter()
+        .map(|r| r.range.end_addr())
+        .max()
+        .unwrap_or(0);
+    if physical_memory_offset.as_u64() % SIZE_512GIB != 0 {
+        info!("Physical memory window is not 512 GiB aligned, keeping its pages");
+        return;
+    }
+
+    // Nothing to do if the bootloader already used large enough pages
+    let gigantic = gigantic_pages_supported();
+    let p4 = unsafe { crate::active_level_4_table(physical_memory_offset) };
+    let first = usize::from(physical_memory_offset.p4_index());
+    let p3 = unsafe { table(physical_memory_offset, p4[first].addr()) };
+    if p3[0].flags().contains(PageTableFlags::HUGE_PAGE) {
+        return;
+    }
+    let p2 = unsafe { table(physical_memory_offset, p3[0].addr()) };
+    if !gigantic && p2[0].flags().contains(PageTableFlags::HUGE_PAGE) {
+        return;
+    }
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for (index, base) in (0..end).step_by(SIZE_512GIB as usize).enumerate() {
+        match build(base, end, gigantic, frame_allocator, physical_memory_offset) {
+            Some(p3) => p4[first + index].set_addr(p3, flags),
+            None => {
+                warn!("Physical memory window: out of frames, keeping the rest of its pages");
+                break;
+            }
+        }
+    }
+    tlb::flush_all();
+
+    info!(
+        "Physical memory window: {} MiB remapped with {} pages",
+        end >> 20,
+        if gigantic { "1 GiB" } else { "2 MiB" }
+    );
+}
+
+#[derive(Debug)]
+pub enum WindowError {
+    // The recursive entry does not point back at the level 4 table
+    InvalidRecursiveEntry,
+    Map(MapToError<Size2MiB>),
+}
+
+// Maps all of physical memory at layout::PHYSICAL_MEMORY_OFFSET with 2 MiB
+// pages, reaching the page tables through the recursive entry at `index`.
+// Returns the window's offset.
+pub fn map_recursive(
+    index: u16,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    memory_regions: &[MemoryRegion],
+) -> Result<VirtAddr, WindowError> {
+    let end = memory_regions
+        .iter()
+        .map(|r| r.range.end_addr())
+        .max()
+        .unwrap_or(0);
+    let index = PageTableIndex::new(index);
+    let p4 = Page::from_page_table_indices(index, index, index, index).start_address();
+    let p4 = unsafe { &mut *p4.as_mut_ptr::<PageTable>() };
+    let mut mapper = RecursivePageTable::new(p4).map_err(|_| WindowError::InvalidRecursiveEntry)?;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for start in (0..end).step_by(SIZE_2MIB as usize) {
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(
+            layout::PHYSICAL_MEMORY_OFFSET + start,
+        ));
+        let frame = PhysFrame::containing_address(PhysAddr::new(start));
+        // Nothing was mapped here, so there is nothing to flush
+        unsafe {
+            mapper
+                .map_to(page, frame, flags, frame_allocator)
+                .map_err(WindowError::Map)?
+                .ignore()
+        };
+    }
+
+    info!(
+        "Physical memory window: {} MiB mapped at {:#x}",
+        end >> 20,
+        layout::PHYSICAL_MEMORY_OFFSET
+    );
+    Ok(VirtAddr::new(layout::PHYSICAL_MEMORY_OFFSET))
+}
@@ -0,0 +1,72 @@
+// CPU protection features, turned on when the CPU has them:
+// - SMEP: the kernel faults on executing user-accessible pages
+// - SMAP: the kernel faults on touching user-accessible pages, except between
+//   STAC and CLAC (`with_user_access`)
+// - UMIP: SGDT, SIDT, SLDT, SMSW and STR fault in user mode
+// The kernel never maps user-accessible pages yet, so none of this changes
+// its behaviour today; it is in place for when user mode arrives.
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+
+// CPUID.(EAX=7, ECX=0)
+const EBX_SMEP: u32 = 1 << 7;
+const EBX_SMAP: u32 = 1 << 20;
+const ECX_UMIP: u32 = 1 << 2;
+
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn init() {
+    if unsafe { __cpuid(0) }.eax < 7 {
+        info!("CPU protection: none supported");
+        return;
+    }
+    let leaf = unsafe { __cpuid_count(7, 0) };
+
+    let mut flags = Cr4Flags::empty();
+    if leaf.ebx & EBX_SMEP != 0 {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if leaf.ebx & EBX_SMAP != 0 {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+    }
+    if leaf.ecx & ECX_UMIP != 0 {
+        flags |= Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION;
+    }
+    unsafe { Cr4::update(|cr4| *cr4 |= flags) };
+    SMAP_ENABLED.store(
+        flags.contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION),
+        Ordering::Relaxed,
+    );
+
+    info!(
+        "CPU protection: SMEP {}, SMAP {}, UMIP {}",
+        on_off(flags.contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION)),
+        on_off(flags.contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION)),
+        on_off(flags.contains(Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION))
+    );
+}
+
+fn on_off(enabled: bool) -> &'static str {
+    if enabled {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+// Runs `f` with user pages accessible. The user copy helpers are to be built
+// on this once there are processes whose mappings they can check ranges
+// against.
+pub fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let smap = SMAP_ENABLED.load(Ordering::Relaxed);
+    if smap {
+        unsafe { asm!("stac", options(nomem, nostack)) };
+    }
+    let result = f();
+    if smap {
+        unsafe { asm!("clac", options(nomem, nostack)) };
+    }
+    result
+}
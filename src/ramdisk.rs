@@ -0,0 +1,115 @@
+// RAM-backed block device, `ram0`, so block users can be exercised and
+// benchmarked without disk emulation in the loop. `ramdisk=<size>` on the
+// command line creates it, with the size in bytes or with a K, M or G suffix.
+// Its frames come straight from the frame allocator rather than the heap and
+// are mapped contiguously in their own window; they are never freed.
+use crate::block::{self, BlockDevice, BlockError};
+use crate::cmdline;
+use crate::memory::layout::RAMDISK_START;
+use crate::sync::SpinLock;
+use alloc::sync::Arc;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+const BLOCK_SIZE: usize = 512;
+const PAGE_SIZE: u64 = 4096;
+// Well short of the MMIO window above
+const MAX_SIZE: u64 = 16 << 30;
+
+struct RamDisk {
+    data: SpinLock<&'static mut [u8]>,
+    blocks: u64,
+}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        "ram0"
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buffer.len())?;
+        let start = lba as usize * BLOCK_SIZE;
+        buffer.copy_from_slice(&self.data.lock()[start..start + buffer.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buffer.len())?;
+        let start = lba as usize * BLOCK_SIZE;
+        self.data.lock()[start..start + buffer.len()].copy_from_slice(buffer);
+        Ok(())
+    }
+}
+
+// `<n>`, `<n>K`, `<n>M` or `<n>G`
+fn parse_size(value: &str) -> Option<u64> {
+    let (digits, shift) = match value.as_bytes().last()? {
+        b'K' | b'k' => (&value[..value.len() - 1], 10),
+        b'M' | b'm' => (&value[..value.len() - 1], 20),
+        b'G' | b'g' => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    let value = match cmdline::value("ramdisk") {
+        Some(value) => value,
+        None => return,
+    };
+    let size = match parse_size(value) {
+        Some(size) if size > 0 && size <= MAX_SIZE => size,
+        _ => {
+            warn!("ramdisk: invalid size {:?}", value);
+            return;
+        }
+    };
+
+    let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mut mapped = 0;
+    while mapped < pages {
+        let frame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => break,
+        };
+        let page = Page::containing_address(VirtAddr::new(RAMDISK_START + mapped * PAGE_SIZE));
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                warn!("ramdisk: {:?}", err);
+                break;
+            }
+        }
+        mapped += 1;
+    }
+    if mapped < pages {
+        warn!(
+            "ramdisk: only {} of {} KiB available",
+            mapped * PAGE_SIZE / 1024,
+            pages * PAGE_SIZE / 1024
+        );
+    }
+    let bytes = (mapped * PAGE_SIZE).min(size) as usize / BLOCK_SIZE * BLOCK_SIZE;
+    if bytes == 0 {
+        return;
+    }
+
+    let data = unsafe { core::slice::from_raw_parts_mut(RAMDISK_START as *mut u8, bytes) };
+    data.fill(0);
+    block::register(Arc::new(RamDisk {
+        data: SpinLock::new("ramdisk", data),
+        blocks: (bytes / BLOCK_SIZE) as u64,
+    }));
+}
@@ -0,0 +1,95 @@
+// Serial port (COM1) output
+use core::fmt::{self, Write};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+const COM1: u16 = 0x3F8;
+
+pub struct SerialPort {
+    data: Port<u8>,
+    int_enable: Port<u8>,
+    fifo_ctrl: Port<u8>,
+    line_ctrl: Port<u8>,
+    modem_ctrl: Port<u8>,
+    line_status: Port<u8>,
+}
+
+impl SerialPort {
+    pub const unsafe fn new(base: u16) -> Self {
+        SerialPort {
+            data: Port::new(base),
+            int_enable: Port::new(base + 1),
+            fifo_ctrl: Port::new(base + 2),
+            line_ctrl: Port::new(base + 3),
+            modem_ctrl: Port::new(base + 4),
+            line_status: Port::new(base + 5),
+        }
+    }
+
+    pub fn init(&mut self) {
+        unsafe {
+            self.int_enable.write(0x00);
+            // 38400 baud: DLAB on, divisor 3
+            self.line_ctrl.write(0x80);
+            self.data.write(0x03);
+            self.int_enable.write(0x00);
+            // 8N1, DLAB off
+            self.line_ctrl.write(0x03);
+            self.fifo_ctrl.write(0xC7);
+            self.modem_ctrl.write(0x0B);
+        }
+    }
+
+    fn send(&mut self, byte: u8) {
+        unsafe {
+            while self.line_status.read() & 0x20 == 0 {
+                core::hint::spin_loop();
+            }
+            self.data.write(byte);
+        }
+    }
+}
+
+impl Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.send(b'\r');
+            }
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    pub static ref SERIAL1: Mutex<SerialPort> = {
+        let mut port = unsafe { SerialPort::new(COM1) };
+        port.init();
+        Mutex::new(port)
+    };
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    crate::interrupts::without_interrupts(|| {
+        SERIAL1.lock().write_fmt(args).ok();
+    });
+}
+
+// Used on the panic path, where the lock may be held by the code that panicked
+pub fn force_unlock() {
+    unsafe { SERIAL1.force_unlock() };
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
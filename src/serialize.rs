@@ -0,0 +1,369 @@
+// Serialization of kernel data for host-side tools, as JSON or a compact
+// binary encoding. Types implement `Serialize` by hand, describing themselves
+// to a `Serializer` as objects, arrays and scalars; the two serializers here
+// turn that into output. Neither allocates, so both work on the panic path.
+//
+// The binary encoding is self-describing: every value starts with a tag byte.
+// Integers follow as LEB128 varints (signed ones zigzag encoded), strings as
+// their UTF-8 bytes and a terminating zero, and objects and arrays as their
+// contents up to an END tag, with every object field a string key followed
+// by its value.
+use core::fmt::{self, Display, Write};
+
+pub trait Serializer {
+    fn null(&mut self) -> fmt::Result;
+    fn bool(&mut self, value: bool) -> fmt::Result;
+    fn u64(&mut self, value: u64) -> fmt::Result;
+    fn i64(&mut self, value: i64) -> fmt::Result;
+    // An address or bit pattern; JSON numbers cannot hold all 64 bits
+    fn hex(&mut self, value: u64) -> fmt::Result;
+    fn str(&mut self, value: &str) -> fmt::Result;
+    // A string produced by formatting, such as a panic message
+    fn display(&mut self, value: &dyn Display) -> fmt::Result;
+    fn begin_object(&mut self) -> fmt::Result;
+    // Comes before each field's value
+    fn field(&mut self, name: &str) -> fmt::Result;
+    fn end_object(&mut self) -> fmt::Result;
+    fn begin_array(&mut self) -> fmt::Result;
+    fn end_array(&mut self) -> fmt::Result;
+}
+
+pub trait Serialize {
+    fn serialize(&self, out: &mut dyn Serializer) -> fmt::Result;
+}
+
+// Serialized with `Serializer::hex`
+#[derive(Clone, Copy)]
+pub struct Hex(pub u64);
+
+impl Serialize for Hex {
+    fn serialize(&self, out: &mut dyn Serializer) -> fmt::Result {
+        out.hex(self.0)
+    }
+}
+
+// Serialized with `Serializer::display`
+pub struct Displayed<'a>(pub &'a dyn Display);
+
+impl Serialize for Displayed<'_> {
+    fn serialize(&self, out: &mut dyn Serializer) -> fmt::Result {
+        out.display(self.0)
+    }
+}
+
+impl Serialize for bool {
+    fn serialize(&self, out: &mut dyn Serializer) -> fmt::Result {
+        out.bool(*self)
+    }
+}
+
+macro_rules! serialize_unsigned {
+    ($($ty:ty),*) => {
+        $(impl Serialize for $ty {
+            fn serialize(&self, out: &mut dyn Serializer) -> fmt::Result {
+                out.u64(*self as u64)
+            }
+        })*
+    };
+}
+
+macro_rules! serialize_signed {
+    ($($ty:ty),*) => {
+        $(impl Serialize for $ty {
+            fn serialize(&self, out: &mut dyn Serializer) -> fmt::Result {
+                out.i64(*self as i64)
+            }
+        })*
+    };
+}
+
+serialize_unsigned!(u8, u16, u32, u64, usize);
+serialize_signed!(i8, i16, i32, i64, isize);
+
+impl Serialize for str {
+    fn serialize(&self, out: &mut dyn Serializer) -> fmt::Result {
+        out.str(self)
+    }
+}
+
+impl<T: Serialize + ?Sized> Serialize for &T {
+    fn serialize(&self, out: &mut dyn Serializer) -> fmt::Result {
+        (**self).serialize(out)
+    }
+}
+
+impl<T: Serialize> Serialize for Option<T> {
+    fn serialize(&self, out: &mut dyn Serializer) -> fmt::Result {
+        match self {
+            Some(value) => value.serialize(out),
+            None => out.null(),
+        }
+    }
+}
+
+impl<T: Serialize> Serialize for [T] {
+    fn serialize(&self, out: &mut dyn Serializer) -> fmt::Result {
+        out.begin_array()?;
+        for value in self {
+            value.serialize(out)?;
+        }
+        out.end_array()
+    }
+}
+
+impl<T: Serialize, const N: usize> Serialize for [T; N] {
+    fn serialize(&self, out: &mut dyn Serializer) -> fmt::Result {
+        self[..].serialize(out)
+    }
+}
+
+// Writes an object's fields from `impl Serialize` blocks:
+//
+//   serialize_fields!(out, "rsp" => Hex(self.rsp), "depth" => self.depth)
+#[macro_export]
+macro_rules! serialize_fields {
+    ($out:expr, $($name:literal => $value:expr),* $(,)?) => {{
+        let out: &mut dyn $crate::serialize::Serializer = $out;
+        out.begin_object()?;
+        $(
+            out.field($name)?;
+            $crate::serialize::Serialize::serialize(&$value, out)?;
+        )*
+        out.end_object()
+    }};
+}
+
+// Writes JSON to any `fmt::Write`, such as the serial port
+pub struct Json<W: Write> {
+    out: W,
+    // Whether the next value or field follows another in the same container
+    comma: bool,
+}
+
+impl<W: Write> Json<W> {
+    pub fn new(out: W) -> Self {
+        Json { out, comma: false }
+    }
+
+    fn separate(&mut self) -> fmt::Result {
+        if self.comma {
+            self.out.write_char(',')?;
+        }
+        self.comma = true;
+        Ok(())
+    }
+
+    fn open(&mut self, bracket: char) -> fmt::Result {
+        self.separate()?;
+        self.comma = false;
+        self.out.write_char(bracket)
+    }
+
+    fn close(&mut self, bracket: char) -> fmt::Result {
+        self.comma = true;
+        self.out.write_char(bracket)
+    }
+}
+
+// Escapes what passes through into the body of a JSON string
+struct Escape<'a, W: Write>(&'a mut W);
+
+impl<W: Write> Write for Escape<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '"' => self.0.write_str("\\\"")?,
+                '\\' => self.0.write_str("\\\\")?,
+                '\n' => self.0.write_str("\\n")?,
+                '\r' => self.0.write_str("\\r")?,
+                '\t' => self.0.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(self.0, "\\u{:04x}", c as u32)?,
+                c => self.0.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Serializer for Json<W> {
+    fn null(&mut self) -> fmt::Result {
+        self.separate()?;
+        self.out.write_str("null")
+    }
+
+    fn bool(&mut self, value: bool) -> fmt::Result {
+        self.separate()?;
+        write!(self.out, "{}", value)
+    }
+
+    fn u64(&mut self, value: u64) -> fmt::Result {
+        self.separate()?;
+        write!(self.out, "{}", value)
+    }
+
+    fn i64(&mut self, value: i64) -> fmt::Result {
+        self.separate()?;
+        write!(self.out, "{}", value)
+    }
+
+    fn hex(&mut self, value: u64) -> fmt::Result {
+        self.separate()?;
+        write!(self.out, "\"{:#x}\"", value)
+    }
+
+    fn str(&mut self, value: &str) -> fmt::Result {
+        self.display(&value)
+    }
+
+    fn display(&mut self, value: &dyn Display) -> fmt::Result {
+        self.separate()?;
+        self.out.write_char('"')?;
+        write!(Escape(&mut self.out), "{}", value)?;
+        self.out.write_char('"')
+    }
+
+    fn begin_object(&mut self) -> fmt::Result {
+        self.open('{')
+    }
+
+    fn field(&mut self, name: &str) -> fmt::Result {
+        self.str(name)?;
+        self.comma = false;
+        self.out.write_char(':')
+    }
+
+    fn end_object(&mut self) -> fmt::Result {
+        self.close('}')
+    }
+
+    fn begin_array(&mut self) -> fmt::Result {
+        self.open('[')
+    }
+
+    fn end_array(&mut self) -> fmt::Result {
+        self.close(']')
+    }
+}
+
+// Binary encoding tags
+const TAG_NULL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_UNSIGNED: u8 = 3;
+const TAG_SIGNED: u8 = 4;
+const TAG_STRING: u8 = 5;
+const TAG_OBJECT: u8 = 6;
+const TAG_ARRAY: u8 = 7;
+const TAG_END: u8 = 8;
+
+// Writes the binary encoding, a byte at a time, to `out`
+pub struct Binary<F: FnMut(u8)> {
+    out: F,
+}
+
+impl<F: FnMut(u8)> Binary<F> {
+    pub fn new(out: F) -> Self {
+        Binary { out }
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                (self.out)(byte);
+                return;
+            }
+            (self.out)(byte | 0x80);
+        }
+    }
+}
+
+// Zero bytes would end the string early, so they are dropped
+impl<F: FnMut(u8)> Write for Binary<F> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if byte != 0 {
+                (self.out)(byte);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<F: FnMut(u8)> Serializer for Binary<F> {
+    fn null(&mut self) -> fmt::Result {
+        (self.out)(TAG_NULL);
+        Ok(())
+    }
+
+    fn bool(&mut self, value: bool) -> fmt::Result {
+        (self.out)(if value { TAG_TRUE } else { TAG_FALSE });
+        Ok(())
+    }
+
+    fn u64(&mut self, value: u64) -> fmt::Result {
+        (self.out)(TAG_UNSIGNED);
+        self.varint(value);
+        Ok(())
+    }
+
+    fn i64(&mut self, value: i64) -> fmt::Result {
+        (self.out)(TAG_SIGNED);
+        self.varint(((value << 1) ^ (value >> 63)) as u64);
+        Ok(())
+    }
+
+    fn hex(&mut self, value: u64) -> fmt::Result {
+        self.u64(value)
+    }
+
+    fn str(&mut self, value: &str) -> fmt::Result {
+        self.display(&value)
+    }
+
+    fn display(&mut self, value: &dyn Display) -> fmt::Result {
+        (self.out)(TAG_STRING);
+        write!(self, "{}", value)?;
+        (self.out)(0);
+        Ok(())
+    }
+
+    fn begin_object(&mut self) -> fmt::Result {
+        (self.out)(TAG_OBJECT);
+        Ok(())
+    }
+
+    fn field(&mut self, name: &str) -> fmt::Result {
+        self.str(name)
+    }
+
+    fn end_object(&mut self) -> fmt::Result {
+        (self.out)(TAG_END);
+        Ok(())
+    }
+
+    fn begin_array(&mut self) -> fmt::Result {
+        (self.out)(TAG_ARRAY);
+        Ok(())
+    }
+
+    fn end_array(&mut self) -> fmt::Result {
+        (self.out)(TAG_END);
+        Ok(())
+    }
+}
+
+// Formats as JSON, for use with print macros:
+// `serial_println!("{}", serialize::json(&value))`
+pub fn json<T: Serialize + ?Sized>(value: &T) -> impl Display + '_ {
+    struct Adapter<'a, T: ?Sized>(&'a T);
+
+    impl<T: Serialize + ?Sized> Display for Adapter<'_, T> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            self.0.serialize(&mut Json::new(f))
+        }
+    }
+
+    Adapter(value)
+}
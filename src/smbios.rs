@@ -0,0 +1,184 @@
+// SMBIOS/DMI tables: locates the entry point in the BIOS area, then decodes
+// the structures used to identify the machine in boot reports (BIOS, system,
+// baseboard and memory devices)
+use core::slice;
+use core::str;
+use x86_64::VirtAddr;
+
+const SCAN_START: u64 = 0xF0000;
+const SCAN_END: u64 = 0x100000;
+
+const TYPE_BIOS: u8 = 0;
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_BASEBOARD: u8 = 2;
+const TYPE_MEMORY_DEVICE: u8 = 17;
+const TYPE_END: u8 = 127;
+
+pub struct Table {
+    pub version: (u8, u8),
+    data: &'static [u8],
+}
+
+pub struct Structure<'a> {
+    pub kind: u8,
+    pub handle: u16,
+    formatted: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> Structure<'a> {
+    pub fn byte(&self, offset: usize) -> Option<u8> {
+        self.formatted.get(offset).copied()
+    }
+
+    pub fn word(&self, offset: usize) -> Option<u16> {
+        let bytes = self.formatted.get(offset..offset + 2)?;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn dword(&self, offset: usize) -> Option<u32> {
+        let bytes = self.formatted.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // The string referenced by the index byte at `offset`
+    pub fn string(&self, offset: usize) -> Option<&'a str> {
+        let index = self.byte(offset)? as usize;
+        if index == 0 {
+            return None;
+        }
+        let raw = self.strings.split(|&b| b == 0).nth(index - 1)?;
+        str::from_utf8(raw).ok().map(str::trim)
+    }
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+unsafe fn phys_slice(offset: VirtAddr, phys: u64, len: usize) -> &'static [u8] {
+    slice::from_raw_parts((offset + phys).as_ptr(), len)
+}
+
+// Scans the BIOS area for an SMBIOS 3.x or 2.x entry point
+pub fn find(physical_memory_offset: VirtAddr) -> Option<Table> {
+    let mut addr = SCAN_START;
+    while addr < SCAN_END {
+        let anchor = unsafe { phys_slice(physical_memory_offset, addr, 32) };
+        if anchor.starts_with(b"_SM3_") {
+            // The length comes from firmware and may not fit what was read
+            let len = anchor[6] as usize;
+            if (0x18..=anchor.len()).contains(&len) && checksum_ok(&anchor[..len]) {
+                let size = u32::from_le_bytes(anchor[0x0C..0x10].try_into().unwrap());
+                let table = u64::from_le_bytes(anchor[0x10..0x18].try_into().unwrap());
+                let data = unsafe { phys_slice(physical_memory_offset, table, size as usize) };
+                return Some(Table {
+                    version: (anchor[7], anchor[8]),
+                    data,
+                });
+            }
+        } else if anchor.starts_with(b"_SM_") {
+            let len = anchor[5] as usize;
+            if (0x1F..=anchor.len()).contains(&len)
+                && checksum_ok(&anchor[..len])
+                && &anchor[0x10..0x15] == b"_DMI_"
+            {
+                let size = u16::from_le_bytes([anchor[0x16], anchor[0x17]]);
+                let table = u32::from_le_bytes(anchor[0x18..0x1C].try_into().unwrap());
+                let data =
+                    unsafe { phys_slice(physical_memory_offset, table as u64, size as usize) };
+                return Some(Table {
+                    version: (anchor[6], anchor[7]),
+                    data,
+                });
+            }
+        }
+        addr += 16;
+    }
+    None
+}
+
+impl Table {
+    pub fn structures(&self) -> impl Iterator<Item = Structure<'static>> {
+        let mut rest = self.data;
+        core::iter::from_fn(move || {
+            if rest.len() < 4 {
+                return None;
+            }
+            let kind = rest[0];
+            let len = rest[1] as usize;
+            if len < 4 || len > rest.len() {
+                return None;
+            }
+            let handle = u16::from_le_bytes([rest[2], rest[3]]);
+            // The string set ends with two NUL bytes
+            let strings_len = rest[len..].windows(2).position(|w| w == [0, 0])?;
+            let structure = Structure {
+                kind,
+                handle,
+                formatted: &rest[..len],
+                strings: &rest[len..len + strings_len],
+            };
+            rest = &rest[len + strings_len + 2..];
+            if kind == TYPE_END {
+                rest = &[];
+            }
+            Some(structure)
+        })
+    }
+
+    pub fn find(&self, kind: u8) -> Option<Structure<'static>> {
+        self.structures().find(|s| s.kind == kind)
+    }
+
+    pub fn log_summary(&self) {
+        info!("SMBIOS {}.{}", self.version.0, self.version.1);
+        if let Some(bios) = self.find(TYPE_BIOS) {
+            info!(
+                "  BIOS: {} {} ({})",
+                bios.string(0x04).unwrap_or("?"),
+                bios.string(0x05).unwrap_or("?"),
+                bios.string(0x08).unwrap_or("?")
+            );
+        }
+        if let Some(system) = self.find(TYPE_SYSTEM) {
+            info!(
+                "  System: {} {} {}",
+                system.string(0x04).unwrap_or("?"),
+                system.string(0x05).unwrap_or("?"),
+                system.string(0x06).unwrap_or("")
+            );
+        }
+        if let Some(board) = self.find(TYPE_BASEBOARD) {
+            info!(
+                "  Baseboard: {} {}",
+                board.string(0x04).unwrap_or("?"),
+                board.string(0x05).unwrap_or("?")
+            );
+        }
+        for device in self.structures().filter(|s| s.kind == TYPE_MEMORY_DEVICE) {
+            let size = match memory_device_size_mib(&device) {
+                Some(0) | None => continue,
+                Some(size) => size,
+            };
+            info!(
+                "  Memory: {} MiB in {} ({} {}, {} MT/s)",
+                size,
+                device.string(0x10).unwrap_or("?"),
+                device.string(0x17).unwrap_or("?"),
+                device.string(0x1A).unwrap_or("?"),
+                device.word(0x15).unwrap_or(0)
+            );
+        }
+    }
+}
+
+// Size of a type 17 memory device, None if unknown
+fn memory_device_size_mib(device: &Structure) -> Option<u32> {
+    match device.word(0x0C)? {
+        0xFFFF => None,
+        0x7FFF => device.dword(0x1C).map(|size| size & 0x7FFF_FFFF),
+        size if size & 0x8000 != 0 => Some((size & 0x7FFF) as u32 / 1024),
+        size => Some(size as u32),
+    }
+}
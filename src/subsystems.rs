@@ -0,0 +1,98 @@
+// Optional subsystems. Each one is behind a Cargo feature (see Cargo.toml),
+// and only compiled-in ones appear in the registry, so kernel_main brings up
+// whatever the build contains. Minimal builds and bisecting use
+// `cargo bootimage --no-default-features --features <list>`.
+use crate::BootInfoFrameAllocator;
+use x86_64::structures::paging::OffsetPageTable;
+use x86_64::VirtAddr;
+
+// What subsystem init functions may use from early boot
+pub struct Context<'a> {
+    pub mapper: &'a mut OffsetPageTable<'static>,
+    pub frame_allocator: &'a mut BootInfoFrameAllocator,
+    pub physical_memory_offset: VirtAddr,
+}
+
+struct Subsystem {
+    name: &'static str,
+    init: fn(&mut Context),
+}
+
+// In initialization order
+static SUBSYSTEMS: &[Subsystem] = &[
+    // First, so everything after it is logged to the host
+    #[cfg(feature = "console")]
+    Subsystem {
+        name: "console",
+        init: init_console,
+    },
+    #[cfg(feature = "power")]
+    Subsystem {
+        name: "power",
+        init: init_power,
+    },
+    #[cfg(feature = "usb")]
+    Subsystem {
+        name: "usb",
+        init: init_usb,
+    },
+    #[cfg(feature = "audio")]
+    Subsystem {
+        name: "audio",
+        init: init_audio,
+    },
+    #[cfg(feature = "graphics")]
+    Subsystem {
+        name: "graphics",
+        init: init_graphics,
+    },
+];
+
+pub fn init(context: &mut Context) {
+    let mut names = alloc::vec::Vec::new();
+    for subsystem in SUBSYSTEMS.iter() {
+        (subsystem.init)(context);
+        names.push(subsystem.name);
+    }
+    info!("Subsystems: {:?}", names);
+}
+
+#[cfg(feature = "console")]
+fn init_console(context: &mut Context) {
+    crate::virtio::console::init(
+        context.mapper,
+        context.frame_allocator,
+        context.physical_memory_offset,
+    );
+}
+
+#[cfg(feature = "power")]
+fn init_power(_context: &mut Context) {
+    crate::cpufreq::init();
+    crate::thermal::init();
+}
+
+#[cfg(feature = "usb")]
+fn init_usb(context: &mut Context) {
+    crate::usb::hid::init();
+    crate::usb::msc::init();
+    crate::usb::xhci::init(
+        context.mapper,
+        context.frame_allocator,
+        context.physical_memory_offset,
+    );
+}
+
+#[cfg(feature = "audio")]
+fn init_audio(context: &mut Context) {
+    crate::audio::init(context.frame_allocator, context.physical_memory_offset);
+}
+
+#[cfg(feature = "graphics")]
+fn init_graphics(context: &mut Context) {
+    crate::virtio::gpu::init(
+        context.mapper,
+        context.frame_allocator,
+        context.physical_memory_offset,
+    );
+}
@@ -0,0 +1,323 @@
+// Spinlock used by kernel subsystems. In debug builds every lock tracks its
+// owner and hold time, and a small lock validator (lockdep-lite) records the
+// order in which locks are taken, reporting:
+//
+// - recursive locking and locks that spin for suspiciously long
+// - locks held for too long
+// - locks taken both in interrupt context and with interrupts enabled
+// - lock ordering inversions (A then B in one place, B then A in another)
+//
+// Lock classes are per lock instance, which matches how the kernel uses
+// locks today (all of them are statics). The held-lock stack assumes a
+// single CPU.
+use core::ops::{Deref, DerefMut};
+
+#[cfg(debug_assertions)]
+use core::panic::Location;
+
+pub struct SpinLock<T> {
+    inner: spin::Mutex<T>,
+    #[cfg(debug_assertions)]
+    debug: lockdep::LockDebug,
+}
+
+pub struct SpinLockGuard<'a, T> {
+    guard: spin::MutexGuard<'a, T>,
+    #[cfg(debug_assertions)]
+    debug: &'a lockdep::LockDebug,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(name: &'static str, value: T) -> Self {
+        #[cfg(not(debug_assertions))]
+        let _ = name;
+        SpinLock {
+            inner: spin::Mutex::new(value),
+            #[cfg(debug_assertions)]
+            debug: lockdep::LockDebug::new(name),
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        let caller = Location::caller();
+        self.debug.before_acquire(caller);
+        let guard = self.debug.spin(&self.inner, caller);
+        self.debug.acquired(caller);
+        SpinLockGuard {
+            guard,
+            debug: &self.debug,
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        SpinLockGuard {
+            guard: self.inner.lock(),
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        let guard = self.inner.try_lock()?;
+        let caller = Location::caller();
+        self.debug.before_acquire(caller);
+        self.debug.acquired(caller);
+        Some(SpinLockGuard {
+            guard,
+            debug: &self.debug,
+        })
+    }
+
+    #[cfg(not(debug_assertions))]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        self.inner.try_lock().map(|guard| SpinLockGuard { guard })
+    }
+
+    // Used on panic and fault paths, where the lock may be held by the code
+    // that was interrupted
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(debug_assertions)]
+        self.debug.released();
+        self.inner.force_unlock();
+    }
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        self.debug.released();
+    }
+}
+
+#[cfg(debug_assertions)]
+mod lockdep {
+    use crate::interrupts;
+    use core::arch::x86_64::_rdtsc;
+    use core::panic::Location;
+    use core::ptr;
+    use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+    const MAX_CLASSES: usize = 32;
+    const MAX_HELD: usize = 16;
+
+    // Roughly 20 ms at common TSC frequencies
+    const HOLD_WARN_CYCLES: u64 = 50_000_000;
+    // Roughly 1 s of spinning before suspecting a deadlock
+    const SPIN_WARN_CYCLES: u64 = 2_500_000_000;
+
+    const NO_CLASS: usize = usize::MAX;
+
+    struct State {
+        names: [&'static str; MAX_CLASSES],
+        classes: usize,
+        // Bit b of after[a] is set once b was taken while holding a
+        after: [u32; MAX_CLASSES],
+        used_in_irq: u32,
+        used_irqs_enabled: u32,
+        // Classes already reported, to keep the log readable
+        reported: u32,
+        held: [usize; MAX_HELD],
+        depth: usize,
+    }
+
+    static STATE: spin::Mutex<State> = spin::Mutex::new(State {
+        names: [""; MAX_CLASSES],
+        classes: 0,
+        after: [0; MAX_CLASSES],
+        used_in_irq: 0,
+        used_irqs_enabled: 0,
+        reported: 0,
+        held: [NO_CLASS; MAX_HELD],
+        depth: 0,
+    });
+
+    pub struct LockDebug {
+        name: &'static str,
+        class: AtomicUsize,
+        owner: AtomicPtr<Location<'static>>,
+        acquired_at: AtomicU64,
+    }
+
+    fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut STATE.lock()))
+    }
+
+    impl LockDebug {
+        pub const fn new(name: &'static str) -> Self {
+            LockDebug {
+                name,
+                class: AtomicUsize::new(NO_CLASS),
+                owner: AtomicPtr::new(ptr::null_mut()),
+                acquired_at: AtomicU64::new(0),
+            }
+        }
+
+        fn class(&self, state: &mut State) -> Option<usize> {
+            let class = self.class.load(Ordering::Relaxed);
+            if class != NO_CLASS {
+                return Some(class);
+            }
+            if state.classes == MAX_CLASSES {
+                return None;
+            }
+            let class = state.classes;
+            state.names[class] = self.name;
+            state.classes += 1;
+            self.class.store(class, Ordering::Relaxed);
+            Some(class)
+        }
+
+        pub fn before_acquire(&self, caller: &'static Location<'static>) {
+            let in_irq = interrupts::in_interrupt();
+            let irqs_enabled = x86_64::instructions::interrupts::are_enabled();
+
+            with_state(|state| {
+                let class = match self.class(state) {
+                    Some(class) => class,
+                    None => return,
+                };
+                let bit = 1 << class;
+
+                if state.held[..state.depth].contains(&class) {
+                    let owner = self.owner.load(Ordering::Relaxed);
+                    panic!(
+                        "lockdep: recursive locking of {} at {} (already held from {})",
+                        self.name,
+                        caller,
+                        describe(owner)
+                    );
+                }
+
+                if in_irq {
+                    state.used_in_irq |= bit;
+                }
+                if irqs_enabled {
+                    state.used_irqs_enabled |= bit;
+                }
+                if state.used_in_irq & state.used_irqs_enabled & bit != 0
+                    && state.reported & bit == 0
+                {
+                    state.reported |= bit;
+                    warn!(
+                        "lockdep: {} is taken in interrupt context and with interrupts enabled (at {}), which can deadlock",
+                        self.name, caller
+                    );
+                }
+
+                for index in 0..state.depth {
+                    let held = state.held[index];
+                    if state.after[held] & bit != 0 {
+                        continue;
+                    }
+                    if reaches(state, class, held) && state.reported & bit == 0 {
+                        state.reported |= bit;
+                        warn!(
+                            "lockdep: lock order inversion: taking {} at {} while holding {}, but {} was previously taken before {}",
+                            self.name, caller, state.names[held], self.name, state.names[held]
+                        );
+                    }
+                    state.after[held] |= bit;
+                }
+            });
+        }
+
+        pub fn spin<'a, T>(
+            &self,
+            lock: &'a spin::Mutex<T>,
+            caller: &'static Location<'static>,
+        ) -> spin::MutexGuard<'a, T> {
+            let start = unsafe { _rdtsc() };
+            let mut warned = false;
+            loop {
+                if let Some(guard) = lock.try_lock() {
+                    return guard;
+                }
+                if !warned && unsafe { _rdtsc() } - start > SPIN_WARN_CYCLES {
+                    warned = true;
+                    warn!(
+                        "lockdep: possible deadlock: {} at {} has been spinning, lock held from {}",
+                        self.name,
+                        caller,
+                        describe(self.owner.load(Ordering::Relaxed))
+                    );
+                }
+                core::hint::spin_loop();
+            }
+        }
+
+        pub fn acquired(&self, caller: &'static Location<'static>) {
+            self.owner
+                .store(caller as *const _ as *mut _, Ordering::Relaxed);
+            self.acquired_at
+                .store(unsafe { _rdtsc() }, Ordering::Relaxed);
+            with_state(|state| {
+                let class = self.class.load(Ordering::Relaxed);
+                if class != NO_CLASS && state.depth < MAX_HELD {
+                    state.held[state.depth] = class;
+                    state.depth += 1;
+                }
+            });
+        }
+
+        pub fn released(&self) {
+            let held_for = unsafe { _rdtsc() } - self.acquired_at.load(Ordering::Relaxed);
+            let owner = self.owner.swap(ptr::null_mut(), Ordering::Relaxed);
+            if held_for > HOLD_WARN_CYCLES {
+                warn!(
+                    "lockdep: {} held for {} cycles (taken at {})",
+                    self.name,
+                    held_for,
+                    describe(owner)
+                );
+            }
+            with_state(|state| {
+                let class = self.class.load(Ordering::Relaxed);
+                let depth = state.depth;
+                if let Some(index) = state.held[..depth].iter().rposition(|&c| c == class) {
+                    state.held.copy_within(index + 1..depth, index);
+                    state.depth -= 1;
+                }
+            });
+        }
+    }
+
+    // Whether `to` was ever taken, directly or transitively, after `from`
+    fn reaches(state: &State, from: usize, to: usize) -> bool {
+        let mut seen = 1u32 << from;
+        let mut frontier = state.after[from];
+        while frontier & !seen != 0 {
+            let next = (frontier & !seen).trailing_zeros() as usize;
+            if next == to {
+                return true;
+            }
+            seen |= 1 << next;
+            frontier |= state.after[next];
+        }
+        false
+    }
+
+    fn describe(owner: *mut Location<'static>) -> &'static dyn core::fmt::Display {
+        if owner.is_null() {
+            &"<unknown>"
+        } else {
+            unsafe { &*owner }
+        }
+    }
+}
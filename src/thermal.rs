@@ -0,0 +1,124 @@
+// CPU temperature reporting from the Intel digital thermal sensor, with an
+// emergency path: close to TjMax the cpufreq governor is capped at the
+// slowest frequency, and at TjMax the kernel halts rather than risk the
+// hardware.
+use crate::msr::{self, IA32_PACKAGE_THERM_STATUS, IA32_THERM_STATUS, MSR_TEMPERATURE_TARGET};
+use crate::{cpufreq, idle, interrupts};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+// Used when MSR_TEMPERATURE_TARGET reports nothing useful
+const DEFAULT_TJMAX: u32 = 100;
+// Throttle this many degrees below TjMax
+const THROTTLE_MARGIN: u32 = 5;
+const POLL_CYCLES: u64 = 1_000_000_000;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Sensor {
+    Core,
+    Package,
+}
+
+static SUPPORTED: AtomicBool = AtomicBool::new(false);
+static PACKAGE_SUPPORTED: AtomicBool = AtomicBool::new(false);
+static TJMAX: AtomicU32 = AtomicU32::new(DEFAULT_TJMAX);
+static THROTTLED: AtomicBool = AtomicBool::new(false);
+static LAST_POLL: AtomicU64 = AtomicU64::new(0);
+
+pub fn init() {
+    if unsafe { __cpuid(0) }.eax < 6 {
+        return;
+    }
+    // CPUID.06H:EAX.DTS[bit 0] and .PTM[bit 6]
+    let leaf = unsafe { __cpuid(6) };
+    if leaf.eax & 1 == 0 {
+        info!("thermal: no digital thermal sensor");
+        return;
+    }
+    SUPPORTED.store(true, Ordering::Relaxed);
+    PACKAGE_SUPPORTED.store(leaf.eax & (1 << 6) != 0, Ordering::Relaxed);
+
+    // Not on every CPU with a sensor; faults where it is missing
+    let target = unsafe { msr::probe(MSR_TEMPERATURE_TARGET) }.unwrap_or(0);
+    let tjmax = ((target >> 16) & 0xFF) as u32;
+    if tjmax != 0 {
+        TJMAX.store(tjmax, Ordering::Relaxed);
+    }
+
+    idle::register_poll(poll);
+    match temperature(Sensor::Core) {
+        Some(celsius) => info!(
+            "thermal: core at {} C (TjMax {} C)",
+            celsius,
+            TJMAX.load(Ordering::Relaxed)
+        ),
+        None => info!("thermal: sensor reading not valid yet"),
+    }
+}
+
+pub fn tjmax() -> u32 {
+    TJMAX.load(Ordering::Relaxed)
+}
+
+// Temperature in degrees Celsius, if the sensor exists and has a valid reading
+pub fn temperature(sensor: Sensor) -> Option<u32> {
+    let register = match sensor {
+        Sensor::Core if SUPPORTED.load(Ordering::Relaxed) => IA32_THERM_STATUS,
+        Sensor::Package if PACKAGE_SUPPORTED.load(Ordering::Relaxed) => IA32_PACKAGE_THERM_STATUS,
+        _ => return None,
+    };
+    let status = unsafe { msr::read(register) }.ok()?;
+    // The package register has no valid bit; the core one has it in bit 31
+    if matches!(sensor, Sensor::Core) && status & (1 << 31) == 0 {
+        return None;
+    }
+    // Digital readout, bits 22:16, counts degrees below TjMax
+    let below = ((status >> 16) & 0x7F) as u32;
+    Some(tjmax().saturating_sub(below))
+}
+
+// Called from the idle loop; checks the sensor about once every POLL_CYCLES
+fn poll() {
+    let now = unsafe { _rdtsc() };
+    if now - LAST_POLL.load(Ordering::Relaxed) < POLL_CYCLES {
+        return;
+    }
+    LAST_POLL.store(now, Ordering::Relaxed);
+
+    let celsius = match temperature(Sensor::Package).or_else(|| temperature(Sensor::Core)) {
+        Some(celsius) => celsius,
+        None => return,
+    };
+    let tjmax = tjmax();
+
+    if celsius >= tjmax {
+        critical(celsius);
+    } else if celsius + THROTTLE_MARGIN >= tjmax {
+        if !THROTTLED.swap(true, Ordering::Relaxed) {
+            warn!(
+                "thermal: {} C is within {} C of TjMax, throttling to {} MHz",
+                celsius,
+                THROTTLE_MARGIN,
+                cpufreq::level_mhz(0)
+            );
+            cpufreq::set_max_level(Some(0));
+        }
+    } else if THROTTLED.swap(false, Ordering::Relaxed) {
+        info!("thermal: {} C, throttling lifted", celsius);
+        cpufreq::set_max_level(None);
+    }
+}
+
+fn critical(celsius: u32) -> ! {
+    error!(
+        "thermal: critical temperature {} C (TjMax {} C), halting",
+        celsius,
+        tjmax()
+    );
+    // Held until the end, so the interrupts-off depth stays accurate for
+    // anything that still checks it
+    let _irqs_off = interrupts::disable();
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
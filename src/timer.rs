@@ -0,0 +1,93 @@
+// Periodic tick from the legacy PIT on IRQ 0, which wakes the idle loop so its
+// poll callbacks keep running. The 8259 PICs are remapped past the exception
+// vectors first: after a BIOS boot they deliver IRQ 0 on vector 8, where it
+// would look like a double fault. Every line but the timer stays masked.
+// Without a legacy PIC there is no tick, and the idle loop polls rather than
+// sleeping.
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::port::Port;
+
+pub const HZ: u32 = 100;
+
+// PIC1 delivers IRQs 0-7 from here, PIC2 IRQs 8-15 after it
+const VECTOR_BASE: u8 = 32;
+pub const VECTOR_TIMER: u8 = VECTOR_BASE;
+// PIC1 raises IRQ 7 for a line that went away before it was acknowledged,
+// even while that line is masked
+pub const VECTOR_SPURIOUS: u8 = VECTOR_BASE + 7;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+// Edge triggered, cascaded, ICW4 follows
+const ICW1_INIT: u8 = 0x11;
+const ICW4_8086: u8 = 0x01;
+// PIC2 is wired to IRQ 2
+const CASCADE_IRQ: u8 = 2;
+const EOI: u8 = 0x20;
+const TIMER_ONLY: u8 = !1;
+
+const PIT_FREQUENCY: u32 = 1_193_182;
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+// Channel 0, low then high byte, mode 2 (rate generator)
+const PIT_RATE_GENERATOR: u8 = 0x34;
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+// Old PICs need a moment between initialization words
+fn io_wait() {
+    unsafe { Port::<u8>::new(0x80).write(0) };
+}
+
+// Sends the four initialization words to one PIC
+unsafe fn init_pic(command: u16, data: u16, offset: u8, wiring: u8) {
+    for (port, value) in [
+        (command, ICW1_INIT),
+        (data, offset),
+        (data, wiring),
+        (data, ICW4_8086),
+    ] {
+        Port::<u8>::new(port).write(value);
+        io_wait();
+    }
+}
+
+// Must run before interrupts are first enabled
+pub fn init() {
+    let mut pic1_data = Port::<u8>::new(PIC1_DATA);
+    let mut pic2_data = Port::<u8>::new(PIC2_DATA);
+    unsafe {
+        pic1_data.write(0xFF);
+        pic2_data.write(0xFF);
+        init_pic(PIC1_COMMAND, PIC1_DATA, VECTOR_BASE, 1 << CASCADE_IRQ);
+        init_pic(PIC2_COMMAND, PIC2_DATA, VECTOR_BASE + 8, CASCADE_IRQ);
+        pic2_data.write(0xFF);
+        pic1_data.write(TIMER_ONLY);
+        // Reads back as all ones when there is nothing at the port
+        if pic1_data.read() != TIMER_ONLY {
+            pic1_data.write(0xFF);
+            warn!("timer: no 8259 PIC, the idle loop will poll");
+            return;
+        }
+
+        let divisor = (PIT_FREQUENCY / HZ) as u16;
+        let mut channel = Port::<u8>::new(PIT_CHANNEL_0);
+        Port::<u8>::new(PIT_COMMAND).write(PIT_RATE_GENERATOR);
+        channel.write(divisor as u8);
+        channel.write((divisor >> 8) as u8);
+    }
+    RUNNING.store(true, Ordering::Relaxed);
+    info!("timer: PIT at {} Hz on vector {}", HZ, VECTOR_TIMER);
+}
+
+// Whether there is a tick to wake the CPU from HLT or MWAIT
+pub fn running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+// Called by the IRQ 0 handler
+pub fn end_of_interrupt() {
+    unsafe { Port::<u8>::new(PIC1_COMMAND).write(EOI) };
+}
@@ -0,0 +1,207 @@
+// HID class drivers for boot protocol keyboards and mice. Devices are switched
+// to the boot protocol, whose reports have a fixed layout, so no report
+// descriptor parsing is needed. Reports are turned into input events.
+use super::{xhci, SetupPacket, UsbDevice, UsbDriver, TRANSFER_INTERRUPT};
+use crate::input::{self, InputEvent};
+use crate::sync::SpinLock;
+use alloc::vec::Vec;
+
+const CLASS_HID: u8 = 3;
+const SUBCLASS_BOOT: u8 = 1;
+const PROTOCOL_KEYBOARD: u8 = 1;
+const PROTOCOL_MOUSE: u8 = 2;
+
+const REQUEST_SET_IDLE: u8 = 0x0A;
+const REQUEST_SET_PROTOCOL: u8 = 0x0B;
+const BOOT_PROTOCOL: u16 = 0;
+
+// Class request, host to device, addressed to an interface
+const REQUEST_TYPE_CLASS_INTERFACE: u8 = 0x21;
+
+// Keyboard usage reported in every slot when too many keys are down
+const USAGE_ERROR_ROLL_OVER: u8 = 0x01;
+const USAGE_LEFT_CONTROL: u8 = 0xE0;
+
+struct HidDriver;
+
+static DRIVER: HidDriver = HidDriver;
+
+// Last boot report of each keyboard, to turn reports into key transitions
+struct Keyboard {
+    controller: usize,
+    slot: u8,
+    last: [u8; 8],
+}
+
+static KEYBOARDS: SpinLock<Vec<Keyboard>> = SpinLock::new("usb_hid_keyboards", Vec::new());
+static MOUSE_BUTTONS: SpinLock<u8> = SpinLock::new("usb_hid_mouse_buttons", 0);
+
+pub fn init() {
+    super::register_driver(&DRIVER);
+}
+
+impl UsbDriver for HidDriver {
+    fn name(&self) -> &'static str {
+        "usb-hid"
+    }
+
+    fn probe(&self, device: &UsbDevice) -> bool {
+        match bind(device) {
+            Ok(bound) => bound,
+            Err(err) => {
+                warn!("usb-hid: slot {}: {:?}", device.slot, err);
+                false
+            }
+        }
+    }
+
+    // Releases any keys still held on an unplugged keyboard
+    fn disconnect(&self, device: &UsbDevice) {
+        keyboard_report(device, &[0; 8]);
+        KEYBOARDS
+            .lock()
+            .retain(|k| k.controller != device.controller || k.slot != device.slot);
+    }
+}
+
+fn bind(device: &UsbDevice) -> Result<bool, xhci::XhciError> {
+    let configuration = super::read_configuration(device)?;
+
+    let mut interfaces = Vec::new();
+    for interface in configuration.interfaces.iter() {
+        let d = &interface.descriptor;
+        if d.class != CLASS_HID || d.subclass != SUBCLASS_BOOT || d.alternate != 0 {
+            continue;
+        }
+        let handler: xhci::ReportHandler = match d.protocol {
+            PROTOCOL_KEYBOARD => keyboard_report,
+            PROTOCOL_MOUSE => mouse_report,
+            _ => continue,
+        };
+        let endpoint = interface
+            .endpoints
+            .iter()
+            .find(|e| e.is_in() && e.transfer_type() == TRANSFER_INTERRUPT);
+        if let Some(endpoint) = endpoint {
+            interfaces.push((d.number, d.protocol, *endpoint, handler));
+        }
+    }
+    if interfaces.is_empty() {
+        return Ok(false);
+    }
+
+    super::set_configuration(device, configuration.value)?;
+    for (number, protocol, endpoint, handler) in interfaces {
+        class_request(device, REQUEST_SET_PROTOCOL, BOOT_PROTOCOL, number)?;
+        // Report only on change. Optional for mice, so failures are ignored.
+        let _ = class_request(device, REQUEST_SET_IDLE, 0, number);
+
+        if protocol == PROTOCOL_KEYBOARD {
+            KEYBOARDS.lock().push(Keyboard {
+                controller: device.controller,
+                slot: device.slot,
+                last: [0; 8],
+            });
+        }
+        xhci::interrupt_in(device, &endpoint, handler)?;
+        info!(
+            "usb-hid: slot {}: boot protocol {}",
+            device.slot,
+            if protocol == PROTOCOL_KEYBOARD {
+                "keyboard"
+            } else {
+                "mouse"
+            }
+        );
+    }
+    Ok(true)
+}
+
+fn class_request(
+    device: &UsbDevice,
+    request: u8,
+    value: u16,
+    interface: u8,
+) -> Result<(), xhci::XhciError> {
+    let setup = SetupPacket::new(
+        REQUEST_TYPE_CLASS_INTERFACE,
+        request,
+        value,
+        interface as u16,
+        0,
+    );
+    xhci::control_transfer(device, setup).map(|_| ())
+}
+
+// Boot keyboard report: modifier bits, a reserved byte, then up to six
+// pressed key usages
+fn keyboard_report(device: &UsbDevice, report: &[u8]) {
+    if report.len() < 8 || report[2] == USAGE_ERROR_ROLL_OVER {
+        return;
+    }
+    let mut keyboards = KEYBOARDS.lock();
+    let keyboard = match keyboards
+        .iter_mut()
+        .find(|k| k.controller == device.controller && k.slot == device.slot)
+    {
+        Some(keyboard) => keyboard,
+        None => return,
+    };
+    let last = keyboard.last;
+
+    let changed = last[0] ^ report[0];
+    for bit in 0..8 {
+        if changed & (1 << bit) != 0 {
+            input::push(InputEvent::Key {
+                usage: USAGE_LEFT_CONTROL + bit,
+                pressed: report[0] & (1 << bit) != 0,
+            });
+        }
+    }
+    for &usage in last[2..].iter().filter(|&&u| u != 0) {
+        if !report[2..8].contains(&usage) {
+            input::push(InputEvent::Key {
+                usage,
+                pressed: false,
+            });
+        }
+    }
+    for &usage in report[2..8].iter().filter(|&&u| u != 0) {
+        if !last[2..].contains(&usage) {
+            input::push(InputEvent::Key {
+                usage,
+                pressed: true,
+            });
+        }
+    }
+    keyboard.last.copy_from_slice(&report[..8]);
+}
+
+// Boot mouse report: button bits, X and Y deltas, and an optional wheel
+fn mouse_report(_device: &UsbDevice, report: &[u8]) {
+    if report.len() < 3 {
+        return;
+    }
+    let mut buttons = MOUSE_BUTTONS.lock();
+    let changed = *buttons ^ report[0];
+    for button in 0..3 {
+        if changed & (1 << button) != 0 {
+            input::push(InputEvent::MouseButton {
+                button,
+                pressed: report[0] & (1 << button) != 0,
+            });
+        }
+    }
+    *buttons = report[0];
+
+    let dx = report[1] as i8 as i16;
+    let dy = report[2] as i8 as i16;
+    if dx != 0 || dy != 0 {
+        input::push(InputEvent::MouseMove { dx, dy });
+    }
+    if let Some(&wheel) = report.get(3) {
+        if wheel != 0 {
+            input::push(InputEvent::MouseWheel(wheel as i8));
+        }
+    }
+}
@@ -0,0 +1,323 @@
+// USB core: device and driver model shared by host controller drivers
+use crate::device::{self, DeviceId};
+use crate::sync::SpinLock;
+use alloc::vec::Vec;
+use xhci::XhciError;
+
+pub mod hid;
+pub mod msc;
+pub mod xhci;
+
+pub const DESCRIPTOR_DEVICE: u8 = 1;
+pub const DESCRIPTOR_CONFIGURATION: u8 = 2;
+pub const DESCRIPTOR_INTERFACE: u8 = 4;
+pub const DESCRIPTOR_ENDPOINT: u8 = 5;
+pub const REQUEST_GET_DESCRIPTOR: u8 = 6;
+pub const REQUEST_SET_CONFIGURATION: u8 = 9;
+
+pub const TRANSFER_CONTROL: u8 = 0;
+pub const TRANSFER_ISOCHRONOUS: u8 = 1;
+pub const TRANSFER_BULK: u8 = 2;
+pub const TRANSFER_INTERRUPT: u8 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Speed {
+    Low,
+    Full,
+    High,
+    Super,
+}
+
+impl Speed {
+    // Default control endpoint packet size before the descriptor is read
+    pub fn default_max_packet_size(self) -> u16 {
+        match self {
+            Speed::Low | Speed::Full => 8,
+            Speed::High => 64,
+            Speed::Super => 512,
+        }
+    }
+}
+
+// Standard 8-byte SETUP packet
+#[derive(Clone, Copy, Debug)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    pub fn new(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> Self {
+        SetupPacket {
+            request_type,
+            request,
+            value,
+            index,
+            length,
+        }
+    }
+
+    pub fn get_descriptor(kind: u8, index: u8, length: u16) -> Self {
+        SetupPacket {
+            request_type: 0x80,
+            request: REQUEST_GET_DESCRIPTOR,
+            value: (kind as u16) << 8 | index as u16,
+            index: 0,
+            length,
+        }
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.request_type as u64
+            | (self.request as u64) << 8
+            | (self.value as u64) << 16
+            | (self.index as u64) << 32
+            | (self.length as u64) << 48
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeviceDescriptor {
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub max_packet_size0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_version: u16,
+    pub num_configurations: u8,
+}
+
+impl DeviceDescriptor {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 18 || bytes[1] != DESCRIPTOR_DEVICE {
+            return None;
+        }
+        let word = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        Some(DeviceDescriptor {
+            usb_version: word(2),
+            class: bytes[4],
+            subclass: bytes[5],
+            protocol: bytes[6],
+            max_packet_size0: bytes[7],
+            vendor_id: word(8),
+            product_id: word(10),
+            device_version: word(12),
+            num_configurations: bytes[17],
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub alternate: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct EndpointDescriptor {
+    // Endpoint number in bits 3:0, IN direction in bit 7
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    pub fn number(&self) -> u8 {
+        self.address & 0x0F
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.address & 0x80 != 0
+    }
+
+    pub fn transfer_type(&self) -> u8 {
+        self.attributes & 0x3
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Interface {
+    pub descriptor: InterfaceDescriptor,
+    pub endpoints: Vec<EndpointDescriptor>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Configuration {
+    pub value: u8,
+    pub interfaces: Vec<Interface>,
+}
+
+impl Configuration {
+    // Parses a full configuration descriptor set (wTotalLength bytes).
+    // Class-specific descriptors are skipped.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 9 || bytes[1] != DESCRIPTOR_CONFIGURATION {
+            return None;
+        }
+        let mut configuration = Configuration {
+            value: bytes[5],
+            interfaces: Vec::new(),
+        };
+        let mut offset = bytes[0] as usize;
+        while offset + 2 <= bytes.len() {
+            let length = bytes[offset] as usize;
+            if length < 2 || offset + length > bytes.len() {
+                break;
+            }
+            let d = &bytes[offset..offset + length];
+            match d[1] {
+                DESCRIPTOR_INTERFACE if length >= 9 => configuration.interfaces.push(Interface {
+                    descriptor: InterfaceDescriptor {
+                        number: d[2],
+                        alternate: d[3],
+                        class: d[5],
+                        subclass: d[6],
+                        protocol: d[7],
+                    },
+                    endpoints: Vec::new(),
+                }),
+                DESCRIPTOR_ENDPOINT if length >= 7 => {
+                    if let Some(interface) = configuration.interfaces.last_mut() {
+                        interface.endpoints.push(EndpointDescriptor {
+                            address: d[2],
+                            attributes: d[3],
+                            max_packet_size: u16::from_le_bytes([d[4], d[5]]) & 0x7FF,
+                            interval: d[6],
+                        });
+                    }
+                }
+                _ => {}
+            }
+            offset += length;
+        }
+        Some(configuration)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct UsbDevice {
+    // Index of the host controller in registration order
+    pub controller: usize,
+    pub slot: u8,
+    pub port: u8,
+    pub speed: Speed,
+    pub descriptor: DeviceDescriptor,
+    pub node: DeviceId,
+}
+
+// A class or vendor driver. `probe` is called for every enumerated device
+// and returns whether the driver took it. `disconnect` is called when a bound
+// device is unplugged, before the controller frees its endpoints; transfers
+// to it fail from then on.
+pub trait UsbDriver: Sync {
+    fn name(&self) -> &'static str;
+    fn probe(&self, device: &UsbDevice) -> bool;
+    fn disconnect(&self, _device: &UsbDevice) {}
+}
+
+static DRIVERS: SpinLock<Vec<&'static dyn UsbDriver>> = SpinLock::new("usb_drivers", Vec::new());
+static DEVICES: SpinLock<Vec<UsbDevice>> = SpinLock::new("usb_devices", Vec::new());
+
+pub fn register_driver(driver: &'static dyn UsbDriver) {
+    DRIVERS.lock().push(driver);
+    let devices = DEVICES.lock().clone();
+    for device in devices.iter() {
+        let bound = device::find(device.node).and_then(|node| node.driver());
+        if bound.is_none() && driver.probe(device) {
+            info!("usb: {} bound to slot {}", driver.name(), device.slot);
+            device::bind(device.node, driver.name());
+        }
+    }
+}
+
+// Called by host controller drivers once a device is addressed and its
+// device descriptor is known
+pub fn add_device(device: UsbDevice) {
+    let d = &device.descriptor;
+    info!(
+        "usb: port {} slot {}: {:04x}:{:04x} class {:02x}/{:02x}/{:02x} USB {:x}.{:02x} ({:?} speed)",
+        device.port,
+        device.slot,
+        d.vendor_id,
+        d.product_id,
+        d.class,
+        d.subclass,
+        d.protocol,
+        d.usb_version >> 8,
+        d.usb_version & 0xFF,
+        device.speed
+    );
+    DEVICES.lock().push(device);
+
+    let drivers = DRIVERS.lock().clone();
+    for driver in drivers.iter() {
+        if driver.probe(&device) {
+            info!("usb: {} bound to slot {}", driver.name(), device.slot);
+            device::bind(device.node, driver.name());
+            break;
+        }
+    }
+}
+
+// Called by host controller drivers when a device is unplugged. Its driver
+// detaches here; the controller quiesces and frees the slot afterwards.
+pub fn remove_device(controller: usize, slot: u8) {
+    let device = {
+        let mut devices = DEVICES.lock();
+        match devices
+            .iter()
+            .position(|d| d.controller == controller && d.slot == slot)
+        {
+            Some(index) => devices.remove(index),
+            None => return,
+        }
+    };
+    info!(
+        "usb: port {} slot {}: disconnected",
+        device.port, device.slot
+    );
+
+    let bound = device::find(device.node).and_then(|node| node.driver());
+    if let Some(name) = bound {
+        let driver = DRIVERS.lock().iter().find(|d| d.name() == name).copied();
+        if let Some(driver) = driver {
+            driver.disconnect(&device);
+        }
+    }
+    device::remove(device.node);
+}
+
+pub fn devices() -> Vec<UsbDevice> {
+    DEVICES.lock().clone()
+}
+
+// Reads the first configuration descriptor set of a device
+pub fn read_configuration(device: &UsbDevice) -> Result<Configuration, XhciError> {
+    let head = xhci::control_transfer(
+        device,
+        SetupPacket::get_descriptor(DESCRIPTOR_CONFIGURATION, 0, 9),
+    )?;
+    if head.len() < 4 {
+        return Err(XhciError::BadDescriptor);
+    }
+    let total = u16::from_le_bytes([head[2], head[3]]);
+    let bytes = xhci::control_transfer(
+        device,
+        SetupPacket::get_descriptor(DESCRIPTOR_CONFIGURATION, 0, total),
+    )?;
+    Configuration::parse(&bytes).ok_or(XhciError::BadDescriptor)
+}
+
+pub fn set_configuration(device: &UsbDevice, value: u8) -> Result<(), XhciError> {
+    let setup = SetupPacket::new(0x00, REQUEST_SET_CONFIGURATION, value as u16, 0, 0);
+    xhci::control_transfer(device, setup).map(|_| ())
+}
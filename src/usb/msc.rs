@@ -0,0 +1,299 @@
+// USB mass storage class driver, bulk-only transport with the SCSI
+// transparent command set. Each logical unit 0 becomes a block device named
+// "usbN". Commands are synchronous; stalls are reported as I/O errors, as
+// there is no reset recovery yet.
+use super::{xhci, EndpointDescriptor, UsbDevice, UsbDriver, TRANSFER_BULK};
+use crate::block::{self, BlockDevice, BlockError};
+use crate::sync::SpinLock;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+const CLASS_MASS_STORAGE: u8 = 8;
+const SUBCLASS_SCSI: u8 = 6;
+const PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CBW_LENGTH: usize = 31;
+const CSW_LENGTH: usize = 13;
+const CBW_DATA_IN: u8 = 0x80;
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2A;
+
+// Media may need a moment to spin up after configuration
+const READY_ATTEMPTS: usize = 10;
+
+struct MscDriver;
+
+static DRIVER: MscDriver = MscDriver;
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+static DISKS: SpinLock<Vec<Arc<MassStorage>>> = SpinLock::new("usb_storage_disks", Vec::new());
+
+// Data phase of a command
+enum Data<'a> {
+    None,
+    In(&'a mut [u8]),
+    Out(&'a [u8]),
+}
+
+struct MassStorage {
+    name: String,
+    device: UsbDevice,
+    bulk_in: EndpointDescriptor,
+    bulk_out: EndpointDescriptor,
+    block_size: usize,
+    block_count: u64,
+    // Command tag; the lock also keeps command phases from interleaving
+    tag: SpinLock<u32>,
+    // Set once the device is unplugged
+    gone: AtomicBool,
+}
+
+pub fn init() {
+    super::register_driver(&DRIVER);
+}
+
+impl UsbDriver for MscDriver {
+    fn name(&self) -> &'static str {
+        "usb-storage"
+    }
+
+    fn probe(&self, device: &UsbDevice) -> bool {
+        match bind(device) {
+            Ok(bound) => bound,
+            Err(err) => {
+                warn!("usb-storage: slot {}: {:?}", device.slot, err);
+                false
+            }
+        }
+    }
+
+    fn disconnect(&self, device: &UsbDevice) {
+        let mut disks = DISKS.lock();
+        disks.retain(|disk| {
+            let unplugged =
+                disk.device.controller == device.controller && disk.device.slot == device.slot;
+            if unplugged {
+                disk.gone.store(true, Ordering::Release);
+                block::unregister(&disk.name);
+            }
+            !unplugged
+        });
+    }
+}
+
+fn bind(device: &UsbDevice) -> Result<bool, xhci::XhciError> {
+    let configuration = super::read_configuration(device)?;
+    let interface = configuration.interfaces.iter().find(|interface| {
+        let d = &interface.descriptor;
+        d.class == CLASS_MASS_STORAGE
+            && d.subclass == SUBCLASS_SCSI
+            && d.protocol == PROTOCOL_BULK_ONLY
+            && d.alternate == 0
+    });
+    let interface = match interface {
+        Some(interface) => interface,
+        None => return Ok(false),
+    };
+    let bulk = |is_in: bool| {
+        interface
+            .endpoints
+            .iter()
+            .find(|e| e.transfer_type() == TRANSFER_BULK && e.is_in() == is_in)
+            .copied()
+    };
+    let (bulk_in, bulk_out) = match (bulk(true), bulk(false)) {
+        (Some(bulk_in), Some(bulk_out)) => (bulk_in, bulk_out),
+        _ => return Ok(false),
+    };
+
+    super::set_configuration(device, configuration.value)?;
+    xhci::bulk_endpoint(device, &bulk_in)?;
+    xhci::bulk_endpoint(device, &bulk_out)?;
+
+    let mut storage = MassStorage {
+        name: format!("usb{}", NEXT_INDEX.fetch_add(1, Ordering::Relaxed)),
+        device: *device,
+        bulk_in,
+        bulk_out,
+        block_size: 0,
+        block_count: 0,
+        tag: SpinLock::new("usb_storage", 0),
+        gone: AtomicBool::new(false),
+    };
+
+    let mut inquiry = [0u8; 36];
+    if storage
+        .command(&[SCSI_INQUIRY, 0, 0, 0, 36, 0], Data::In(&mut inquiry))
+        .is_ok()
+    {
+        info!(
+            "usb-storage: {}: {} {}",
+            storage.name,
+            ascii(&inquiry[8..16]),
+            ascii(&inquiry[16..32])
+        );
+    }
+
+    let ready = (0..READY_ATTEMPTS).any(|_| {
+        storage
+            .command(&[SCSI_TEST_UNIT_READY; 6], Data::None)
+            .is_ok()
+    });
+    if !ready {
+        warn!("usb-storage: {}: no medium", storage.name);
+        return Ok(true);
+    }
+
+    let mut capacity = [0u8; 8];
+    if storage
+        .command(
+            &[SCSI_READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            Data::In(&mut capacity),
+        )
+        .is_err()
+    {
+        warn!("usb-storage: {}: READ CAPACITY failed", storage.name);
+        return Ok(true);
+    }
+    let last_lba = u32::from_be_bytes([capacity[0], capacity[1], capacity[2], capacity[3]]);
+    if last_lba == u32::MAX {
+        warn!(
+            "usb-storage: {}: over 2 TiB, only the first 2^32 blocks are usable",
+            storage.name
+        );
+    }
+    let block_size = u32::from_be_bytes([capacity[4], capacity[5], capacity[6], capacity[7]]);
+    // Zero would make the block layer divide by zero, and real sector sizes
+    // are always powers of two
+    if !block_size.is_power_of_two() {
+        warn!(
+            "usb-storage: {}: unusable block size {}",
+            storage.name, block_size
+        );
+        return Ok(true);
+    }
+    storage.block_count = last_lba as u64 + 1;
+    storage.block_size = block_size as usize;
+
+    let storage = Arc::new(storage);
+    DISKS.lock().push(storage.clone());
+    block::register(storage);
+    Ok(true)
+}
+
+fn ascii(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("?").trim()
+}
+
+impl MassStorage {
+    // Runs one command: command block wrapper, optional data phase, and
+    // command status wrapper
+    fn command(&self, block: &[u8], data: Data) -> Result<(), BlockError> {
+        let mut tag = self.tag.lock();
+        if self.gone.load(Ordering::Acquire) {
+            return Err(BlockError::Io);
+        }
+        *tag = tag.wrapping_add(1);
+
+        let (length, flags) = match &data {
+            Data::None => (0, 0),
+            Data::In(buffer) => (buffer.len(), CBW_DATA_IN),
+            Data::Out(buffer) => (buffer.len(), 0),
+        };
+        let mut cbw = [0u8; CBW_LENGTH];
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&(length as u32).to_le_bytes());
+        cbw[12] = flags;
+        cbw[14] = block.len() as u8;
+        cbw[15..15 + block.len()].copy_from_slice(block);
+        self.check(xhci::bulk_out(&self.device, &self.bulk_out, &cbw))?;
+
+        match data {
+            Data::None => {}
+            Data::In(buffer) => {
+                self.check(xhci::bulk_in(&self.device, &self.bulk_in, buffer))?;
+            }
+            Data::Out(buffer) => {
+                self.check(xhci::bulk_out(&self.device, &self.bulk_out, buffer))?;
+            }
+        }
+
+        let mut csw = [0u8; CSW_LENGTH];
+        let received = self.check(xhci::bulk_in(&self.device, &self.bulk_in, &mut csw))?;
+        let signature = u32::from_le_bytes([csw[0], csw[1], csw[2], csw[3]]);
+        let csw_tag = u32::from_le_bytes([csw[4], csw[5], csw[6], csw[7]]);
+        if received != CSW_LENGTH || signature != CSW_SIGNATURE || csw_tag != *tag {
+            warn!("usb-storage: {}: bad command status wrapper", self.name);
+            return Err(BlockError::Io);
+        }
+        match csw[12] {
+            0 => Ok(()),
+            _ => Err(BlockError::Io),
+        }
+    }
+
+    fn check<T>(&self, result: Result<T, xhci::XhciError>) -> Result<T, BlockError> {
+        result.map_err(|err| {
+            warn!("usb-storage: {}: {:?}", self.name, err);
+            BlockError::Io
+        })
+    }
+
+    fn read_write_10(opcode: u8, lba: u64, blocks: u64) -> [u8; 10] {
+        let lba = (lba as u32).to_be_bytes();
+        let count = (blocks as u16).to_be_bytes();
+        [
+            opcode, 0, lba[0], lba[1], lba[2], lba[3], 0, count[0], count[1], 0,
+        ]
+    }
+}
+
+// Blocks moved per READ(10)/WRITE(10) command
+const MAX_BLOCKS_PER_COMMAND: u64 = 128;
+
+impl BlockDevice for MassStorage {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buffer.len())?;
+        let chunk = MAX_BLOCKS_PER_COMMAND as usize * self.block_size;
+        for (index, part) in buffer.chunks_mut(chunk).enumerate() {
+            let start = lba + index as u64 * MAX_BLOCKS_PER_COMMAND;
+            let blocks = (part.len() / self.block_size) as u64;
+            let command = Self::read_write_10(SCSI_READ_10, start, blocks);
+            self.command(&command, Data::In(part))?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buffer.len())?;
+        let chunk = MAX_BLOCKS_PER_COMMAND as usize * self.block_size;
+        for (index, part) in buffer.chunks(chunk).enumerate() {
+            let start = lba + index as u64 * MAX_BLOCKS_PER_COMMAND;
+            let blocks = (part.len() / self.block_size) as u64;
+            let command = Self::read_write_10(SCSI_WRITE_10, start, blocks);
+            self.command(&command, Data::Out(part))?;
+        }
+        Ok(())
+    }
+}
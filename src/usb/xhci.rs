@@ -0,0 +1,1121 @@
+// xHCI host controller driver. Brings each controller up with polled command
+// and event rings (no interrupts yet), then enumerates devices on connected
+// root hub ports: port reset, Enable Slot, Address Device and GET_DESCRIPTOR
+// on the default control endpoint, handing each device to the USB core.
+// Devices plugged in or out later are picked up from port status change
+// events.
+// Interrupt IN endpoints are serviced from an idle loop poll callback; bulk
+// transfers are synchronous, through a per-endpoint bounce page.
+// Targets QEMU's qemu-xhci first.
+use super::{
+    DeviceDescriptor, EndpointDescriptor, SetupPacket, Speed, UsbDevice, DESCRIPTOR_DEVICE,
+    TRANSFER_BULK, TRANSFER_INTERRUPT,
+};
+use crate::device::{self, Bus, DeviceId};
+use crate::idle;
+use crate::kasan;
+use crate::memory::cache::CacheMode;
+use crate::pci::{self, Bar, PciDevice};
+use crate::sync::SpinLock;
+use alloc::format;
+use alloc::vec::Vec;
+use core::ptr::{addr_of_mut, read_volatile, write_volatile};
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+// Capability registers
+const CAP_HCSPARAMS1: usize = 0x04;
+const CAP_HCSPARAMS2: usize = 0x08;
+const CAP_HCCPARAMS1: usize = 0x10;
+const CAP_DBOFF: usize = 0x14;
+const CAP_RTSOFF: usize = 0x18;
+
+// Operational registers
+const OP_USBCMD: usize = 0x00;
+const OP_USBSTS: usize = 0x04;
+const OP_CRCR: usize = 0x18;
+const OP_DCBAAP: usize = 0x30;
+const OP_CONFIG: usize = 0x38;
+const OP_PORTSC_BASE: usize = 0x400;
+
+const USBCMD_RUN: u32 = 1 << 0;
+const USBCMD_RESET: u32 = 1 << 1;
+const USBSTS_HALTED: u32 = 1 << 0;
+const USBSTS_NOT_READY: u32 = 1 << 11;
+
+const PORTSC_CCS: u32 = 1 << 0;
+const PORTSC_PED: u32 = 1 << 1;
+const PORTSC_PR: u32 = 1 << 4;
+const PORTSC_PP: u32 = 1 << 9;
+const PORTSC_CSC: u32 = 1 << 17;
+const PORTSC_PRC: u32 = 1 << 21;
+// Change bits are write-1-to-clear, and writing 1 to PED disables the port,
+// so none of them may be written back unchanged
+const PORTSC_RW1C: u32 = PORTSC_PED | 0x00FE_0000;
+
+// Interrupter 0, relative to the runtime register base
+const IR0_ERSTSZ: usize = 0x28;
+const IR0_ERSTBA: usize = 0x30;
+const IR0_ERDP: usize = 0x38;
+const ERDP_BUSY: u64 = 1 << 3;
+
+// Extended capabilities
+const XCAP_LEGACY_SUPPORT: u32 = 1;
+const LEGACY_BIOS_OWNED: u32 = 1 << 16;
+const LEGACY_OS_OWNED: u32 = 1 << 24;
+
+const TRB_NORMAL: u32 = 1;
+const TRB_SETUP: u32 = 2;
+const TRB_DATA: u32 = 3;
+const TRB_STATUS: u32 = 4;
+const TRB_LINK: u32 = 6;
+const TRB_ENABLE_SLOT: u32 = 9;
+const TRB_DISABLE_SLOT: u32 = 10;
+const TRB_ADDRESS_DEVICE: u32 = 11;
+const TRB_CONFIGURE_ENDPOINT: u32 = 12;
+const TRB_EVALUATE_CONTEXT: u32 = 13;
+const TRB_TRANSFER_EVENT: u32 = 32;
+const TRB_COMMAND_COMPLETION: u32 = 33;
+const TRB_PORT_STATUS_CHANGE: u32 = 34;
+
+const TRB_CYCLE: u32 = 1 << 0;
+const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+const TRB_ISP: u32 = 1 << 2;
+const TRB_IOC: u32 = 1 << 5;
+const TRB_IDT: u32 = 1 << 6;
+const TRB_DIR_IN: u32 = 1 << 16;
+const TRB_TRANSFER_TYPE_IN: u32 = 3 << 16;
+
+const COMPLETION_SUCCESS: u8 = 1;
+const COMPLETION_SHORT_PACKET: u8 = 13;
+
+const TRBS_PER_RING: usize = 4096 / 16;
+const MAX_SLOTS: u32 = 16;
+const MAX_SCRATCHPADS: usize = 4096 / 8;
+// Frames reserved at startup for device contexts, rings and buffers. Devices
+// are set up after init too, when the frame allocator is no longer
+// available, and give their frames back when unplugged.
+const DMA_POOL_FRAMES: usize = 64;
+const POLL_LIMIT: usize = 10_000_000;
+
+const ENDPOINT_TYPE_BULK_OUT: u32 = 2;
+const ENDPOINT_TYPE_INTERRUPT_OUT: u32 = 3;
+const ENDPOINT_TYPE_CONTROL: u32 = 4;
+const ENDPOINT_TYPE_BULK_IN: u32 = 6;
+const ENDPOINT_TYPE_INTERRUPT_IN: u32 = 7;
+// Average TRB length hint for bulk endpoints
+const BULK_AVERAGE_TRB_LENGTH: u32 = 3072;
+
+// Called with each completed interrupt IN transfer
+pub type ReportHandler = fn(&UsbDevice, &[u8]);
+
+#[derive(Debug)]
+pub enum XhciError {
+    NoMmioBar,
+    OutOfMemory,
+    Map(MapToError<Size4KiB>),
+    Timeout(&'static str),
+    Completion(u8),
+    PortDisabled,
+    BadDescriptor,
+    NoDevice,
+    TransferTooLarge,
+    UnsupportedEndpoint,
+}
+
+#[derive(Clone, Copy, Default)]
+#[repr(C, align(16))]
+struct Trb {
+    parameter: u64,
+    status: u32,
+    control: u32,
+}
+
+impl Trb {
+    fn new(kind: u32, parameter: u64, status: u32, flags: u32) -> Self {
+        Trb {
+            parameter,
+            status,
+            control: kind << 10 | flags,
+        }
+    }
+
+    fn kind(&self) -> u32 {
+        (self.control >> 10) & 0x3F
+    }
+
+    fn completion_code(&self) -> u8 {
+        (self.status >> 24) as u8
+    }
+
+    fn slot_id(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+}
+
+// Producer ring (command or transfer), one page long with a link TRB back to
+// the start
+struct Ring {
+    phys: PhysAddr,
+    trbs: *mut Trb,
+    index: usize,
+    cycle: bool,
+}
+
+impl Ring {
+    fn new(
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        offset: VirtAddr,
+    ) -> Result<Self, XhciError> {
+        Ok(Ring::from_frame(dma_frame(frame_allocator, offset)?))
+    }
+
+    fn frame(&self) -> (PhysAddr, VirtAddr) {
+        (self.phys, VirtAddr::from_ptr(self.trbs))
+    }
+
+    fn from_frame((phys, virt): (PhysAddr, VirtAddr)) -> Self {
+        Ring {
+            phys,
+            trbs: virt.as_mut_ptr(),
+            index: 0,
+            cycle: true,
+        }
+    }
+
+    // Queues a TRB and returns its physical address
+    fn push(&mut self, trb: Trb) -> u64 {
+        let addr = self.phys.as_u64() + (self.index * 16) as u64;
+        self.write(self.index, trb);
+        self.index += 1;
+        if self.index == TRBS_PER_RING - 1 {
+            let link = Trb::new(TRB_LINK, self.phys.as_u64(), 0, TRB_TOGGLE_CYCLE);
+            self.write(self.index, link);
+            self.index = 0;
+            self.cycle = !self.cycle;
+        }
+        addr
+    }
+
+    fn write(&mut self, index: usize, mut trb: Trb) {
+        if self.cycle {
+            trb.control |= TRB_CYCLE;
+        } else {
+            trb.control &= !TRB_CYCLE;
+        }
+        unsafe {
+            let slot = self.trbs.add(index);
+            write_volatile(addr_of_mut!((*slot).parameter), trb.parameter);
+            write_volatile(addr_of_mut!((*slot).status), trb.status);
+            // The cycle bit hands the TRB to the controller, so it goes last
+            write_volatile(addr_of_mut!((*slot).control), trb.control);
+        }
+    }
+}
+
+struct EventRing {
+    phys: PhysAddr,
+    trbs: *const Trb,
+    index: usize,
+    cycle: bool,
+}
+
+impl EventRing {
+    fn pop(&mut self) -> Option<Trb> {
+        let trb = unsafe { read_volatile(self.trbs.add(self.index)) };
+        if (trb.control & TRB_CYCLE != 0) != self.cycle {
+            return None;
+        }
+        self.index += 1;
+        if self.index == TRBS_PER_RING {
+            self.index = 0;
+            self.cycle = !self.cycle;
+        }
+        Some(trb)
+    }
+
+    fn dequeue_pointer(&self) -> u64 {
+        self.phys.as_u64() + (self.index * 16) as u64
+    }
+}
+
+// Per-device state: the output device context, the default control endpoint
+// ring, the input context and a page for control transfer data
+struct Slot {
+    id: u8,
+    port: u8,
+    output: (PhysAddr, VirtAddr),
+    ep0: Ring,
+    input: VirtAddr,
+    input_phys: PhysAddr,
+    buffer: VirtAddr,
+    buffer_phys: PhysAddr,
+}
+
+// A configured non-control endpoint. Interrupt IN endpoints with a handler
+// always have one transfer queued; others are driven synchronously.
+struct Endpoint {
+    device: UsbDevice,
+    dci: u8,
+    ring: Ring,
+    buffer: VirtAddr,
+    buffer_phys: PhysAddr,
+    length: u32,
+    handler: Option<ReportHandler>,
+}
+
+pub struct Xhci {
+    node: DeviceId,
+    op: usize,
+    runtime: usize,
+    doorbells: usize,
+    ports: u8,
+    context_size: usize,
+    dcbaa: *mut u64,
+    commands: Ring,
+    events: EventRing,
+    slots: Vec<Slot>,
+    endpoints: Vec<Endpoint>,
+    pool: Vec<(PhysAddr, VirtAddr)>,
+    // Ports with a status change event not yet looked at
+    changed_ports: Vec<u8>,
+}
+
+// Only ever touched with CONTROLLERS locked
+unsafe impl Send for Xhci {}
+
+static CONTROLLERS: SpinLock<Vec<Xhci>> = SpinLock::new("xhci_controllers", Vec::new());
+
+fn read32(addr: usize) -> u32 {
+    unsafe { read_volatile(addr as *const u32) }
+}
+
+fn write32(addr: usize, value: u32) {
+    unsafe { write_volatile(addr as *mut u32, value) }
+}
+
+// 64-bit registers are written low half first, which works whether or not the
+// controller supports 64-bit accesses
+fn write64(addr: usize, value: u64) {
+    write32(addr, value as u32);
+    write32(addr + 4, (value >> 32) as u32);
+}
+
+fn dma_frame(
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    offset: VirtAddr,
+) -> Result<(PhysAddr, VirtAddr), XhciError> {
+    crate::alloc_dma_frame(frame_allocator, offset).ok_or(XhciError::OutOfMemory)
+}
+
+fn wait(what: &'static str, mut done: impl FnMut() -> bool) -> Result<(), XhciError> {
+    for _ in 0..POLL_LIMIT {
+        if done() {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(XhciError::Timeout(what))
+}
+
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+) {
+    for device in pci::find_by_class(0x0C, 0x03, 0x30) {
+        let address = device.address;
+        match Xhci::start(&device, mapper, frame_allocator, physical_memory_offset) {
+            Ok(mut controller) => {
+                device::bind(device.node, "xhci");
+                let index = CONTROLLERS.lock().len();
+                let devices = controller.enumerate(index);
+                CONTROLLERS.lock().push(controller);
+                // Drivers call back into the controller while probing, so
+                // devices are added with CONTROLLERS unlocked
+                for device in devices {
+                    super::add_device(device);
+                }
+            }
+            Err(err) => warn!(
+                "xhci {:02x}:{:02x}.{}: {:?}",
+                address.bus, address.device, address.function, err
+            ),
+        }
+    }
+    if !CONTROLLERS.lock().is_empty() {
+        idle::register_poll(poll);
+    }
+}
+
+// Runs a control transfer on a device's default endpoint and returns the data
+// stage for IN requests. OUT requests with a data stage are not supported.
+pub fn control_transfer(device: &UsbDevice, setup: SetupPacket) -> Result<Vec<u8>, XhciError> {
+    let mut controllers = CONTROLLERS.lock();
+    let controller = controllers
+        .get_mut(device.controller)
+        .ok_or(XhciError::NoDevice)?;
+    controller
+        .control(device.slot, setup)
+        .map(|data| data.to_vec())
+}
+
+// Configures an interrupt IN endpoint and keeps a transfer queued on it.
+// `handler` runs from the idle loop with each report.
+pub fn interrupt_in(
+    device: &UsbDevice,
+    endpoint: &EndpointDescriptor,
+    handler: ReportHandler,
+) -> Result<(), XhciError> {
+    let mut controllers = CONTROLLERS.lock();
+    let controller = controllers
+        .get_mut(device.controller)
+        .ok_or(XhciError::NoDevice)?;
+    // Reports are picked up by `poll`, which the timer tick runs from the idle
+    // loop at least every 1/timer::HZ seconds
+    controller.configure_endpoint(device, endpoint, Some(handler))
+}
+
+// Configures a bulk endpoint for use with `bulk_in` and `bulk_out`
+pub fn bulk_endpoint(device: &UsbDevice, endpoint: &EndpointDescriptor) -> Result<(), XhciError> {
+    let mut controllers = CONTROLLERS.lock();
+    let controller = controllers
+        .get_mut(device.controller)
+        .ok_or(XhciError::NoDevice)?;
+    controller.configure_endpoint(device, endpoint, None)
+}
+
+// Reads from a bulk IN endpoint until `data` is full or the device sends a
+// short packet, returning the number of bytes received
+pub fn bulk_in(
+    device: &UsbDevice,
+    endpoint: &EndpointDescriptor,
+    data: &mut [u8],
+) -> Result<usize, XhciError> {
+    let mut controllers = CONTROLLERS.lock();
+    let controller = controllers
+        .get_mut(device.controller)
+        .ok_or(XhciError::NoDevice)?;
+    let dci = dci(endpoint);
+    let mut done = 0;
+    while done < data.len() {
+        let length = (data.len() - done).min(4096);
+        let received = controller.transfer(device.slot, dci, length as u32)?;
+        let buffer = controller.endpoint(device.slot, dci)?.buffer;
+        unsafe { kasan::copy(buffer.as_ptr::<u8>(), data[done..].as_mut_ptr(), received) };
+        done += received;
+        if received < length {
+            break;
+        }
+    }
+    Ok(done)
+}
+
+pub fn bulk_out(
+    device: &UsbDevice,
+    endpoint: &EndpointDescriptor,
+    data: &[u8],
+) -> Result<(), XhciError> {
+    let mut controllers = CONTROLLERS.lock();
+    let controller = controllers
+        .get_mut(device.controller)
+        .ok_or(XhciError::NoDevice)?;
+    let dci = dci(endpoint);
+    for chunk in data.chunks(4096) {
+        let buffer = controller.endpoint(device.slot, dci)?.buffer;
+        unsafe { kasan::copy(chunk.as_ptr(), buffer.as_mut_ptr(), chunk.len()) };
+        controller.transfer(device.slot, dci, chunk.len() as u32)?;
+    }
+    Ok(())
+}
+
+// Device context index: 1 is the default control endpoint, then OUT and IN
+// for each endpoint number
+fn dci(endpoint: &EndpointDescriptor) -> u8 {
+    endpoint.number() * 2 + endpoint.is_in() as u8
+}
+
+// Idle poll callback: drains the event rings, completes interrupt transfers
+// and handles devices being plugged in or out. With no interrupts, plug
+// events are only noticed as often as the idle loop wakes up.
+fn poll() {
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    {
+        let mut controllers = match CONTROLLERS.try_lock() {
+            Some(controllers) => controllers,
+            None => return,
+        };
+        for (index, controller) in controllers.iter_mut().enumerate() {
+            while let Some(event) = controller.next_event() {
+                controller.dispatch(&event);
+            }
+            controller.handle_port_changes(index, &mut added, &mut removed);
+        }
+    }
+
+    // Drivers detach with CONTROLLERS unlocked, as they may still call in;
+    // only then is the slot disabled and its memory reused
+    for (index, slot) in removed {
+        super::remove_device(index, slot);
+        if let Some(controller) = CONTROLLERS.lock().get_mut(index) {
+            if let Err(err) = controller.release_slot(slot) {
+                warn!("xhci: slot {}: {:?}", slot, err);
+            }
+        }
+    }
+    for device in added {
+        super::add_device(device);
+    }
+}
+
+// xHCI endpoint intervals are 2^n units of 125 us
+fn interval(speed: Speed, endpoint: &EndpointDescriptor) -> u32 {
+    match speed {
+        // bInterval counts 1 ms frames
+        Speed::Low | Speed::Full => {
+            let micro_frames = endpoint.interval.max(1) as u32 * 8;
+            31 - micro_frames.leading_zeros()
+        }
+        // bInterval is already an exponent, off by one
+        Speed::High | Speed::Super => endpoint.interval.clamp(1, 16) as u32 - 1,
+    }
+}
+
+impl Xhci {
+    fn start(
+        device: &PciDevice,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        offset: VirtAddr,
+    ) -> Result<Self, XhciError> {
+        let (bar_base, bar_size) = match device.bar(0) {
+            Some(Bar::Memory { base, size, .. }) => (base, size),
+            _ => return Err(XhciError::NoMmioBar),
+        };
+        device.enable_bus_master();
+        let base = crate::map_mmio(
+            mapper,
+            frame_allocator,
+            PhysAddr::new(bar_base),
+            bar_size,
+            CacheMode::Uncached,
+        )
+        .map_err(XhciError::Map)?
+        .as_u64() as usize;
+
+        let caplength = (read32(base) & 0xFF) as usize;
+        let hcsparams1 = read32(base + CAP_HCSPARAMS1);
+        let hcsparams2 = read32(base + CAP_HCSPARAMS2);
+        let hccparams1 = read32(base + CAP_HCCPARAMS1);
+        let op = base + caplength;
+        let doorbells = base + (read32(base + CAP_DBOFF) & !0x3) as usize;
+        let runtime = base + (read32(base + CAP_RTSOFF) & !0x1F) as usize;
+
+        let max_slots = (hcsparams1 & 0xFF).min(MAX_SLOTS);
+        let ports = (hcsparams1 >> 24) as u8;
+        let scratchpads = (((hcsparams2 >> 21) & 0x1F) << 5 | (hcsparams2 >> 27) & 0x1F) as usize;
+        let context_size = if hccparams1 & (1 << 2) != 0 { 64 } else { 32 };
+
+        take_ownership(base, ((hccparams1 >> 16) << 2) as usize)?;
+
+        // Halt and reset
+        wait("controller ready", || {
+            read32(op + OP_USBSTS) & USBSTS_NOT_READY == 0
+        })?;
+        write32(op + OP_USBCMD, read32(op + OP_USBCMD) & !USBCMD_RUN);
+        wait("halt", || read32(op + OP_USBSTS) & USBSTS_HALTED != 0)?;
+        write32(op + OP_USBCMD, USBCMD_RESET);
+        wait("reset", || read32(op + OP_USBCMD) & USBCMD_RESET == 0)?;
+        wait("controller ready", || {
+            read32(op + OP_USBSTS) & USBSTS_NOT_READY == 0
+        })?;
+
+        write32(op + OP_CONFIG, max_slots);
+
+        // Device context base address array, with scratchpad buffers in entry 0
+        let (dcbaa_phys, dcbaa_virt) = dma_frame(frame_allocator, offset)?;
+        let dcbaa: *mut u64 = dcbaa_virt.as_mut_ptr();
+        if scratchpads > 0 {
+            if scratchpads > MAX_SCRATCHPADS {
+                return Err(XhciError::OutOfMemory);
+            }
+            let (array_phys, array_virt) = dma_frame(frame_allocator, offset)?;
+            let array: *mut u64 = array_virt.as_mut_ptr();
+            for index in 0..scratchpads {
+                let (page, _) = dma_frame(frame_allocator, offset)?;
+                unsafe { write_volatile(array.add(index), page.as_u64()) };
+            }
+            unsafe { write_volatile(dcbaa, array_phys.as_u64()) };
+        }
+        write64(op + OP_DCBAAP, dcbaa_phys.as_u64());
+
+        let pool = (0..DMA_POOL_FRAMES)
+            .map(|_| dma_frame(frame_allocator, offset))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let commands = Ring::new(frame_allocator, offset)?;
+        write64(op + OP_CRCR, commands.phys.as_u64() | 1);
+
+        // Single-segment event ring on interrupter 0
+        let (ring_phys, ring_virt) = dma_frame(frame_allocator, offset)?;
+        let (erst_phys, erst_virt) = dma_frame(frame_allocator, offset)?;
+        unsafe {
+            let erst: *mut u64 = erst_virt.as_mut_ptr();
+            write_volatile(erst, ring_phys.as_u64());
+            write_volatile(erst.add(1), TRBS_PER_RING as u64);
+        }
+        write32(runtime + IR0_ERSTSZ, 1);
+        write64(runtime + IR0_ERDP, ring_phys.as_u64());
+        write64(runtime + IR0_ERSTBA, erst_phys.as_u64());
+        let events = EventRing {
+            phys: ring_phys,
+            trbs: ring_virt.as_ptr(),
+            index: 0,
+            cycle: true,
+        };
+
+        write32(op + OP_USBCMD, read32(op + OP_USBCMD) | USBCMD_RUN);
+        wait("start", || read32(op + OP_USBSTS) & USBSTS_HALTED == 0)?;
+
+        info!(
+            "xhci {:02x}:{:02x}.{}: {} ports, {} slots, {}-byte contexts",
+            device.address.bus,
+            device.address.device,
+            device.address.function,
+            ports,
+            max_slots,
+            context_size
+        );
+
+        Ok(Xhci {
+            node: device.node,
+            op,
+            runtime,
+            doorbells,
+            ports,
+            context_size,
+            dcbaa,
+            commands,
+            events,
+            slots: Vec::new(),
+            endpoints: Vec::new(),
+            pool,
+            changed_ports: Vec::new(),
+        })
+    }
+
+    // Frames come back to the pool dirty, so they are cleared on the way out
+    fn take_frame(&mut self) -> Result<(PhysAddr, VirtAddr), XhciError> {
+        let (phys, virt) = self.pool.pop().ok_or(XhciError::OutOfMemory)?;
+        unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, 4096) };
+        Ok((phys, virt))
+    }
+
+    fn give_frame(&mut self, frame: (PhysAddr, VirtAddr)) {
+        self.pool.push(frame);
+    }
+
+    fn slot(&mut self, id: u8) -> Result<&mut Slot, XhciError> {
+        self.slots
+            .iter_mut()
+            .find(|slot| slot.id == id)
+            .ok_or(XhciError::NoDevice)
+    }
+
+    fn ring_doorbell(&self, slot: u8, target: u32) {
+        write32(self.doorbells + 4 * slot as usize, target);
+    }
+
+    fn next_event(&mut self) -> Option<Trb> {
+        let event = self.events.pop()?;
+        write64(
+            self.runtime + IR0_ERDP,
+            self.events.dequeue_pointer() | ERDP_BUSY,
+        );
+        Some(event)
+    }
+
+    // Polls the event ring until an event matching `matches` arrives. Events
+    // that arrive meanwhile are dispatched.
+    fn wait_event(
+        &mut self,
+        what: &'static str,
+        matches: impl Fn(&Trb) -> bool,
+    ) -> Result<Trb, XhciError> {
+        for _ in 0..POLL_LIMIT {
+            let event = match self.next_event() {
+                Some(event) => event,
+                None => {
+                    core::hint::spin_loop();
+                    continue;
+                }
+            };
+            if !matches(&event) {
+                self.dispatch(&event);
+                continue;
+            }
+            return match event.completion_code() {
+                COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET => Ok(event),
+                code => Err(XhciError::Completion(code)),
+            };
+        }
+        Err(XhciError::Timeout(what))
+    }
+
+    // Hands a completed interrupt transfer to its handler and queues the next,
+    // and notes ports that changed for `handle_port_changes`
+    fn dispatch(&mut self, event: &Trb) {
+        if event.kind() == TRB_PORT_STATUS_CHANGE {
+            let port = (event.parameter >> 24) as u8;
+            if !self.changed_ports.contains(&port) {
+                self.changed_ports.push(port);
+            }
+            return;
+        }
+        if event.kind() != TRB_TRANSFER_EVENT {
+            return;
+        }
+        let slot = event.slot_id();
+        let dci = ((event.control >> 16) & 0x1F) as u8;
+        let index = match self
+            .endpoints
+            .iter()
+            .position(|e| e.device.slot == slot && e.dci == dci)
+        {
+            Some(index) => index,
+            None => return,
+        };
+
+        let endpoint = &mut self.endpoints[index];
+        let handler = match endpoint.handler {
+            Some(handler) => handler,
+            None => return,
+        };
+        match event.completion_code() {
+            COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET => {
+                let residue = event.status & 0x00FF_FFFF;
+                let length = endpoint.length.saturating_sub(residue) as usize;
+                let data = unsafe { core::slice::from_raw_parts(endpoint.buffer.as_ptr(), length) };
+                handler(&endpoint.device, data);
+                endpoint.ring.push(Trb::new(
+                    TRB_NORMAL,
+                    endpoint.buffer_phys.as_u64(),
+                    endpoint.length,
+                    TRB_IOC | TRB_ISP,
+                ));
+                self.ring_doorbell(slot, dci as u32);
+            }
+            // The endpoint is halted now; leave it until reset support exists
+            code => warn!(
+                "xhci: slot {} endpoint {}: transfer failed with code {}",
+                slot, dci, code
+            ),
+        }
+    }
+
+    fn command(&mut self, trb: Trb) -> Result<Trb, XhciError> {
+        let addr = self.commands.push(trb);
+        self.ring_doorbell(0, 0);
+        self.wait_event("command", |event| {
+            event.kind() == TRB_COMMAND_COMPLETION && event.parameter == addr
+        })
+    }
+
+    fn portsc(&self, port: u8) -> usize {
+        self.op + OP_PORTSC_BASE + 0x10 * (port as usize - 1)
+    }
+
+    fn reset_port(&mut self, port: u8) -> Result<Speed, XhciError> {
+        let reg = self.portsc(port);
+        if read32(reg) & PORTSC_PP == 0 {
+            write32(reg, (read32(reg) & !PORTSC_RW1C) | PORTSC_PP);
+        }
+        write32(reg, (read32(reg) & !PORTSC_RW1C) | PORTSC_PR);
+        wait("port reset", || read32(reg) & PORTSC_PRC != 0)?;
+
+        let value = read32(reg);
+        write32(reg, (value & !PORTSC_RW1C) | PORTSC_PRC);
+        if value & PORTSC_PED == 0 {
+            return Err(XhciError::PortDisabled);
+        }
+        Ok(match (value >> 10) & 0xF {
+            2 => Speed::Low,
+            3 => Speed::High,
+            4 | 5 => Speed::Super,
+            _ => Speed::Full,
+        })
+    }
+
+    fn context(&self, base: VirtAddr, index: usize) -> *mut u32 {
+        (base + index * self.context_size).as_mut_ptr()
+    }
+
+    // Sets up the slot's contexts and EP0 ring and addresses the device. The
+    // slot is recorded before the command so `release_slot` can undo it.
+    fn address_device(&mut self, id: u8, port: u8, speed: Speed) -> Result<(), XhciError> {
+        if self.pool.len() < 4 {
+            return Err(XhciError::OutOfMemory);
+        }
+        let output = self.take_frame()?;
+        let (input_phys, input) = self.take_frame()?;
+        let (buffer_phys, buffer) = self.take_frame()?;
+        let ep0 = Ring::from_frame(self.take_frame()?);
+        unsafe { write_volatile(self.dcbaa.add(id as usize), output.0.as_u64()) };
+
+        let speed_id = match speed {
+            Speed::Full => 1,
+            Speed::Low => 2,
+            Speed::High => 3,
+            Speed::Super => 4,
+        };
+        unsafe {
+            // Input control context: add the slot and EP0 contexts
+            let control = self.context(input, 0);
+            write_volatile(control.add(1), 0b11);
+
+            let slot = self.context(input, 1);
+            write_volatile(slot, 1 << 27 | speed_id << 20);
+            write_volatile(slot.add(1), (port as u32) << 16);
+
+            let endpoint = self.context(input, 2);
+            let max_packet = speed.default_max_packet_size() as u32;
+            write_volatile(
+                endpoint.add(1),
+                3 << 1 | ENDPOINT_TYPE_CONTROL << 3 | max_packet << 16,
+            );
+            write_volatile(endpoint.add(2), ep0.phys.as_u64() as u32 | 1);
+            write_volatile(endpoint.add(3), (ep0.phys.as_u64() >> 32) as u32);
+            write_volatile(endpoint.add(4), 8);
+        }
+
+        let trb = Trb::new(
+            TRB_ADDRESS_DEVICE,
+            input_phys.as_u64(),
+            0,
+            (id as u32) << 24,
+        );
+        self.slots.push(Slot {
+            id,
+            port,
+            output,
+            ep0,
+            input,
+            input_phys,
+            buffer,
+            buffer_phys,
+        });
+        self.command(trb).map(|_| ())
+    }
+
+    // Updates the default endpoint's max packet size once the first 8 bytes of
+    // the device descriptor are known
+    fn set_max_packet_size(&mut self, id: u8, max_packet: u16) -> Result<(), XhciError> {
+        let (input, input_phys) = {
+            let slot = self.slot(id)?;
+            (slot.input, slot.input_phys)
+        };
+        unsafe {
+            let control = self.context(input, 0);
+            write_volatile(control.add(1), 0b10);
+            let endpoint = self.context(input, 2);
+            let dword = read_volatile(endpoint.add(1));
+            write_volatile(
+                endpoint.add(1),
+                (dword & 0xFFFF) | (max_packet as u32) << 16,
+            );
+        }
+        let trb = Trb::new(
+            TRB_EVALUATE_CONTEXT,
+            input_phys.as_u64(),
+            0,
+            (id as u32) << 24,
+        );
+        self.command(trb).map(|_| ())
+    }
+
+    // Runs a control transfer on the default endpoint. IN data lands in the
+    // slot's buffer page.
+    fn control(&mut self, id: u8, setup: SetupPacket) -> Result<&[u8], XhciError> {
+        let length = setup.length as u32;
+        if length > 4096 {
+            return Err(XhciError::TransferTooLarge);
+        }
+        let slot = self.slot(id)?;
+        let buffer = slot.buffer;
+        let status = if length == 0 {
+            slot.ep0
+                .push(Trb::new(TRB_SETUP, setup.as_u64(), 8, TRB_IDT));
+            slot.ep0
+                .push(Trb::new(TRB_STATUS, 0, 0, TRB_IOC | TRB_DIR_IN))
+        } else {
+            slot.ep0.push(Trb::new(
+                TRB_SETUP,
+                setup.as_u64(),
+                8,
+                TRB_IDT | TRB_TRANSFER_TYPE_IN,
+            ));
+            slot.ep0.push(Trb::new(
+                TRB_DATA,
+                slot.buffer_phys.as_u64(),
+                length,
+                TRB_DIR_IN,
+            ));
+            slot.ep0.push(Trb::new(TRB_STATUS, 0, 0, TRB_IOC))
+        };
+        self.ring_doorbell(id, 1);
+
+        self.wait_event("control transfer", |event| {
+            event.kind() == TRB_TRANSFER_EVENT && event.slot_id() == id && event.parameter == status
+        })?;
+        Ok(unsafe { core::slice::from_raw_parts(buffer.as_ptr(), length as usize) })
+    }
+
+    fn endpoint(&mut self, slot: u8, dci: u8) -> Result<&mut Endpoint, XhciError> {
+        self.endpoints
+            .iter_mut()
+            .find(|e| e.device.slot == slot && e.dci == dci)
+            .ok_or(XhciError::NoDevice)
+    }
+
+    // Runs one transfer of up to a page through the endpoint's buffer and
+    // returns the number of bytes moved
+    fn transfer(&mut self, slot: u8, dci: u8, length: u32) -> Result<usize, XhciError> {
+        let endpoint = self.endpoint(slot, dci)?;
+        let trb = endpoint.ring.push(Trb::new(
+            TRB_NORMAL,
+            endpoint.buffer_phys.as_u64(),
+            length,
+            TRB_IOC | TRB_ISP,
+        ));
+        self.ring_doorbell(slot, dci as u32);
+        let event = self.wait_event("transfer", |event| {
+            event.kind() == TRB_TRANSFER_EVENT && event.parameter == trb
+        })?;
+        Ok(length.saturating_sub(event.status & 0x00FF_FFFF) as usize)
+    }
+
+    fn configure_endpoint(
+        &mut self,
+        device: &UsbDevice,
+        endpoint: &EndpointDescriptor,
+        handler: Option<ReportHandler>,
+    ) -> Result<(), XhciError> {
+        let kind = match (endpoint.transfer_type(), endpoint.is_in()) {
+            (TRANSFER_BULK, false) => ENDPOINT_TYPE_BULK_OUT,
+            (TRANSFER_BULK, true) => ENDPOINT_TYPE_BULK_IN,
+            (TRANSFER_INTERRUPT, false) => ENDPOINT_TYPE_INTERRUPT_OUT,
+            (TRANSFER_INTERRUPT, true) => ENDPOINT_TYPE_INTERRUPT_IN,
+            _ => return Err(XhciError::UnsupportedEndpoint),
+        };
+        let periodic = endpoint.transfer_type() == TRANSFER_INTERRUPT;
+        let dci = dci(endpoint) as usize;
+        let ring = Ring::from_frame(self.take_frame()?);
+        let (buffer_phys, buffer) = self.take_frame()?;
+        let (input, input_phys) = {
+            let slot = self.slot(device.slot)?;
+            (slot.input, slot.input_phys)
+        };
+        let max_packet = endpoint.max_packet_size as u32;
+
+        // The input slot context still holds what Address Device set up; only
+        // the context entry count grows
+        unsafe {
+            let control = self.context(input, 0);
+            write_volatile(control, 0);
+            write_volatile(control.add(1), 1 | 1 << dci);
+
+            let slot = self.context(input, 1);
+            let dword = read_volatile(slot);
+            let entries = (dword >> 27).max(dci as u32);
+            write_volatile(slot, (dword & !(0x1F << 27)) | entries << 27);
+
+            let context = self.context(input, dci + 1);
+            let (interval, average, max_esit) = if periodic {
+                (interval(device.speed, endpoint), max_packet, max_packet)
+            } else {
+                (0, BULK_AVERAGE_TRB_LENGTH, 0)
+            };
+            write_volatile(context, interval << 16);
+            write_volatile(context.add(1), 3 << 1 | kind << 3 | max_packet << 16);
+            write_volatile(context.add(2), ring.phys.as_u64() as u32 | 1);
+            write_volatile(context.add(3), (ring.phys.as_u64() >> 32) as u32);
+            write_volatile(context.add(4), max_esit << 16 | average);
+        }
+        let trb = Trb::new(
+            TRB_CONFIGURE_ENDPOINT,
+            input_phys.as_u64(),
+            0,
+            (device.slot as u32) << 24,
+        );
+        self.command(trb)?;
+
+        let mut endpoint = Endpoint {
+            device: *device,
+            dci: dci as u8,
+            ring,
+            buffer,
+            buffer_phys,
+            length: max_packet,
+            handler,
+        };
+        if handler.is_some() {
+            endpoint.ring.push(Trb::new(
+                TRB_NORMAL,
+                buffer_phys.as_u64(),
+                max_packet,
+                TRB_IOC | TRB_ISP,
+            ));
+            self.ring_doorbell(device.slot, dci as u32);
+        }
+        self.endpoints.push(endpoint);
+        Ok(())
+    }
+
+    fn setup_device(&mut self, controller: usize, port: u8) -> Result<UsbDevice, XhciError> {
+        let speed = self.reset_port(port)?;
+        let id = self.command(Trb::new(TRB_ENABLE_SLOT, 0, 0, 0))?.slot_id();
+        match self.describe_device(id, port, speed) {
+            Ok(descriptor) => {
+                let name = format!("usb{}-{}", controller, port);
+                Ok(UsbDevice {
+                    controller,
+                    slot: id,
+                    port,
+                    speed,
+                    descriptor,
+                    node: device::add(name, Bus::Usb, Some(self.node)).id,
+                })
+            }
+            Err(err) => {
+                // Hand the slot back so a failed device does not use it up
+                if self.release_slot(id).is_err() {
+                    let trb = Trb::new(TRB_DISABLE_SLOT, 0, 0, (id as u32) << 24);
+                    let _ = self.command(trb);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn describe_device(
+        &mut self,
+        id: u8,
+        port: u8,
+        speed: Speed,
+    ) -> Result<DeviceDescriptor, XhciError> {
+        self.address_device(id, port, speed)?;
+
+        let head = self.control(id, SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, 8))?;
+        let max_packet = head[7] as u16;
+        if matches!(speed, Speed::Low | Speed::Full) && max_packet != 8 && max_packet != 0 {
+            self.set_max_packet_size(id, max_packet)?;
+        }
+
+        let bytes = self.control(id, SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, 18))?;
+        DeviceDescriptor::parse(bytes).ok_or(XhciError::BadDescriptor)
+    }
+
+    fn enumerate(&mut self, controller: usize) -> Vec<UsbDevice> {
+        let mut devices = Vec::new();
+        for port in 1..=self.ports {
+            // Connections present at startup are handled here rather than
+            // as plug events
+            let reg = self.portsc(port);
+            let value = read32(reg);
+            write32(reg, (value & !PORTSC_RW1C) | PORTSC_CSC);
+            if value & PORTSC_CCS == 0 {
+                continue;
+            }
+            match self.setup_device(controller, port) {
+                Ok(device) => devices.push(device),
+                Err(err) => warn!("xhci: port {}: {:?}", port, err),
+            }
+        }
+        devices
+    }
+
+    // Looks at ports whose connect status changed. Devices found on them are
+    // set up and returned in `added`; slots of devices that went away are
+    // returned in `removed`, for the caller to detach and then release.
+    fn handle_port_changes(
+        &mut self,
+        controller: usize,
+        added: &mut Vec<UsbDevice>,
+        removed: &mut Vec<(usize, u8)>,
+    ) {
+        while let Some(port) = self.changed_ports.pop() {
+            let reg = self.portsc(port);
+            let value = read32(reg);
+            if value & PORTSC_CSC == 0 {
+                continue;
+            }
+            write32(reg, (value & !PORTSC_RW1C) | PORTSC_CSC);
+
+            // A quick unplug and replug shows up as a single change, so any
+            // device already on the port is gone either way
+            if let Some(slot) = self.slots.iter().find(|slot| slot.port == port) {
+                removed.push((controller, slot.id));
+            }
+            if value & PORTSC_CCS != 0 {
+                match self.setup_device(controller, port) {
+                    Ok(device) => added.push(device),
+                    Err(err) => warn!("xhci: port {}: {:?}", port, err),
+                }
+            }
+        }
+    }
+
+    // Disables a slot, which stops the controller's DMA to all of its
+    // endpoints, then returns its contexts, rings and buffers to the pool
+    fn release_slot(&mut self, id: u8) -> Result<(), XhciError> {
+        let index = self
+            .slots
+            .iter()
+            .position(|slot| slot.id == id)
+            .ok_or(XhciError::NoDevice)?;
+        self.command(Trb::new(TRB_DISABLE_SLOT, 0, 0, (id as u32) << 24))?;
+        unsafe { write_volatile(self.dcbaa.add(id as usize), 0) };
+
+        let slot = self.slots.remove(index);
+        self.give_frame(slot.output);
+        self.give_frame((slot.input_phys, slot.input));
+        self.give_frame((slot.buffer_phys, slot.buffer));
+        self.give_frame(slot.ep0.frame());
+        let mut index = 0;
+        while index < self.endpoints.len() {
+            if self.endpoints[index].device.slot == id {
+                let endpoint = self.endpoints.remove(index);
+                self.give_frame(endpoint.ring.frame());
+                self.give_frame((endpoint.buffer_phys, endpoint.buffer));
+            } else {
+                index += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Claims the controller from the BIOS through the USB legacy support
+// capability and turns off its SMIs, as real hardware otherwise keeps
+// fighting the OS over it
+fn take_ownership(base: usize, mut offset: usize) -> Result<(), XhciError> {
+    while offset != 0 {
+        let cap = read32(base + offset);
+        if cap & 0xFF == XCAP_LEGACY_SUPPORT {
+            write32(base + offset, cap | LEGACY_OS_OWNED);
+            wait("BIOS handoff", || {
+                read32(base + offset) & LEGACY_BIOS_OWNED == 0
+            })?;
+            let control = read32(base + offset + 4);
+            write32(base + offset + 4, (control & !0xE011) | 0xE000_0000);
+            return Ok(());
+        }
+        let next = ((cap >> 8) & 0xFF) as usize;
+        if next == 0 {
+            break;
+        }
+        offset += next << 2;
+    }
+    Ok(())
+}
@@ -0,0 +1,122 @@
+// virtio-console log sink. Kernel log output is streamed from the klog ring
+// to the first console port, so the host can capture it in a file while the
+// serial port is busy with something else:
+//
+//   -device virtio-serial-pci -device virtconsole,chardev=klog
+//   -chardev file,id=klog,path=kernel.log
+//
+// Only the transmit queue is set up; nothing is read from the host. Output
+// logged before the device was found goes out first, as far back as the ring
+// still has it. Logging itself never waits on the device: new output is sent
+// from the idle loop, and from the panic handler on the way down.
+use super::{Buffer, Transport, VirtioError, Virtqueue, DEVICE_ID_BASE, VENDOR_ID};
+use crate::{idle, interrupts, klog, pci};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+const DEVICE_TYPE_CONSOLE: u16 = 3;
+// Port 0 receive queue is 0, transmit is 1
+const TRANSMIT_QUEUE: u16 = 1;
+const BUFFER_SIZE: usize = 4096;
+
+struct Console {
+    // Kept alive for the mapped configuration regions
+    _transport: Transport,
+    transmit: Virtqueue,
+    buffer: (PhysAddr, VirtAddr),
+    // Bytes of klog output handled so far, sent or lost
+    sent: usize,
+    stopped: bool,
+}
+
+// Not a SpinLock: the panic path flushes, and lockdep, with its own state
+// locked, may be what panicked
+static CONSOLE: spin::Mutex<Option<Console>> = spin::Mutex::new(None);
+
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+) {
+    match probe(mapper, frame_allocator, physical_memory_offset) {
+        Ok(true) => {
+            idle::register_poll(flush);
+            flush();
+        }
+        Ok(false) => {}
+        Err(err) => warn!("virtio-console: {:?}", err),
+    }
+}
+
+fn probe(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    offset: VirtAddr,
+) -> Result<bool, VirtioError> {
+    let device = match pci::find_by_id(VENDOR_ID, DEVICE_ID_BASE + DEVICE_TYPE_CONSOLE)
+        .into_iter()
+        .next()
+    {
+        Some(device) => device,
+        None => return Ok(false),
+    };
+    let transport = Transport::new(&device, mapper, frame_allocator)?;
+    transport.negotiate(0)?;
+    let transmit = transport.setup_queue(TRANSMIT_QUEUE, frame_allocator, offset)?;
+    transport.driver_ok();
+    let buffer = crate::alloc_dma_frame(frame_allocator, offset).ok_or(VirtioError::OutOfMemory)?;
+
+    info!(
+        "virtio-console: {:02x}:{:02x}.{}, streaming the kernel log",
+        device.address.bus, device.address.device, device.address.function
+    );
+    crate::device::bind(device.node, "virtio-console");
+    *CONSOLE.lock() = Some(Console {
+        _transport: transport,
+        transmit,
+        buffer,
+        sent: 0,
+        stopped: false,
+    });
+    Ok(true)
+}
+
+// Sends everything logged since the last call. If the console is busy, on
+// this CPU further up the stack or on another one, the output is left for
+// whoever holds it.
+pub fn flush() {
+    let mut console = match CONSOLE.try_lock() {
+        Some(console) => console,
+        None => return,
+    };
+    let console = match console.as_mut() {
+        Some(console) if !console.stopped => console,
+        _ => return,
+    };
+    loop {
+        let buffer = unsafe {
+            core::slice::from_raw_parts_mut(console.buffer.1.as_mut_ptr::<u8>(), BUFFER_SIZE)
+        };
+        let (len, lost) =
+            interrupts::without_interrupts(|| klog::KLOG.lock().read_since(console.sent, buffer));
+        console.sent += len + lost;
+        if lost > 0 {
+            // Lands in the ring, and goes out on the next round
+            warn!("virtio-console: {} bytes of log output lost", lost);
+        }
+        if len == 0 {
+            return;
+        }
+        let chain = [Buffer {
+            addr: console.buffer.0,
+            len: len as u32,
+            device_writes: false,
+        }];
+        if let Err(err) = console.transmit.submit_and_wait(&chain) {
+            // Rather than wait out the timeout on every record
+            console.stopped = true;
+            warn!("virtio-console: {:?}, output stopped", err);
+            return;
+        }
+    }
+}
@@ -0,0 +1,571 @@
+// virtio-gpu 2D driver with double-buffered scanout. Two host resources are
+// backed by guest memory; drawing goes to the back buffer and `flip` uploads
+// it and points the scanout at it. `set_mode` changes resolution at runtime;
+// `video=<width>x<height>` on the command line picks the mode at boot, and
+// whatever draws on the display subscribes to hear about mode changes.
+use super::{Buffer, Transport, VirtioError, Virtqueue, DEVICE_ID_BASE, VENDOR_ID};
+use crate::memory::layout::FRAMEBUFFER_START;
+use crate::sync::SpinLock;
+use crate::{cmdline, pci};
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::{read_volatile, write_volatile};
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+const DEVICE_TYPE_GPU: u16 = 16;
+const CONTROL_QUEUE: u16 = 0;
+
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_RESOURCE_UNREF: u32 = 0x0102;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+// Pixels are 0x00RRGGBB in little-endian u32s
+const FORMAT_B8G8R8X8_UNORM: u32 = 2;
+const MAX_SCANOUTS: usize = 16;
+const DEFAULT_MODE: (u32, u32) = (1024, 768);
+// Offered besides the display's preferred mode. The device takes any size;
+// these are just the usual ones.
+const STANDARD_MODES: [(u32, u32); 8] = [
+    (640, 480),
+    (800, 600),
+    (1024, 768),
+    (1280, 720),
+    (1280, 1024),
+    (1920, 1080),
+    (2560, 1440),
+    (3840, 2160),
+];
+
+// Each of the two buffers has this much virtual space in the window, enough
+// for 4K at 32 bpp
+const FRAMEBUFFER_SPAN: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum GpuError {
+    NoDevice,
+    Virtio(VirtioError),
+    Map(MapToError<Size4KiB>),
+    Response(u32),
+    ModeTooLarge,
+    // Backing memory needs more entries than fit in one request
+    TooFragmented,
+}
+
+impl From<VirtioError> for GpuError {
+    fn from(err: VirtioError) -> Self {
+        GpuError::Virtio(err)
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Header {
+    kind: u32,
+    flags: u32,
+    fence_id: u64,
+    context_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DisplayOne {
+    rect: Rect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DisplayInfo {
+    header: Header,
+    modes: [DisplayOne; MAX_SCANOUTS],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ResourceCreate2d {
+    header: Header,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+// RESOURCE_UNREF and RESOURCE_DETACH_BACKING
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ResourceOnly {
+    header: Header,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct AttachBacking {
+    header: Header,
+    resource_id: u32,
+    entries: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct MemEntry {
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SetScanout {
+    header: Header,
+    rect: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ResourceFlush {
+    header: Header,
+    rect: Rect,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TransferToHost2d {
+    header: Header,
+    rect: Rect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+const MAX_BACKING_ENTRIES: usize = (4096 - size_of::<AttachBacking>()) / size_of::<MemEntry>();
+
+// Guest memory behind one resource. Frames are never returned, so the
+// backing only grows across mode changes.
+struct Surface {
+    resource: u32,
+    base: VirtAddr,
+    // Physically contiguous runs of frames as (start, length in bytes)
+    runs: Vec<(u64, u64)>,
+    pages: u64,
+}
+
+struct Gpu {
+    // Kept alive for the mapped configuration regions
+    _transport: Transport,
+    control: Virtqueue,
+    request: (PhysAddr, VirtAddr),
+    response: (PhysAddr, VirtAddr),
+    scanout: u32,
+    surfaces: [Surface; 2],
+    front: usize,
+    width: u32,
+    height: u32,
+    // What the host reports for the scanout, or DEFAULT_MODE
+    preferred: (u32, u32),
+    next_resource: u32,
+}
+
+// Called with the new width and height after every mode change
+pub type ModeListener = fn(u32, u32);
+
+static GPU: SpinLock<Option<Gpu>> = SpinLock::new("virtio_gpu", None);
+static MODE_LISTENERS: SpinLock<Vec<ModeListener>> =
+    SpinLock::new("virtio_gpu_listeners", Vec::new());
+
+fn header(kind: u32) -> Header {
+    Header {
+        kind,
+        ..Header::default()
+    }
+}
+
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+) {
+    match probe(mapper, frame_allocator, physical_memory_offset) {
+        Ok(()) | Err(GpuError::NoDevice) => {}
+        Err(err) => warn!("virtio-gpu: {:?}", err),
+    }
+}
+
+fn probe(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    offset: VirtAddr,
+) -> Result<(), GpuError> {
+    let device = pci::find_by_id(VENDOR_ID, DEVICE_ID_BASE + DEVICE_TYPE_GPU)
+        .into_iter()
+        .next()
+        .ok_or(GpuError::NoDevice)?;
+    let transport = Transport::new(&device, mapper, frame_allocator)?;
+    transport.negotiate(0)?;
+    let control = transport.setup_queue(CONTROL_QUEUE, frame_allocator, offset)?;
+    transport.driver_ok();
+
+    let request =
+        crate::alloc_dma_frame(frame_allocator, offset).ok_or(VirtioError::OutOfMemory)?;
+    let response =
+        crate::alloc_dma_frame(frame_allocator, offset).ok_or(VirtioError::OutOfMemory)?;
+    let surface = |index: u64| Surface {
+        resource: 0,
+        base: VirtAddr::new(FRAMEBUFFER_START + index * FRAMEBUFFER_SPAN),
+        runs: Vec::new(),
+        pages: 0,
+    };
+    let mut gpu = Gpu {
+        _transport: transport,
+        control,
+        request,
+        response,
+        scanout: 0,
+        surfaces: [surface(0), surface(1)],
+        front: 0,
+        width: 0,
+        height: 0,
+        preferred: DEFAULT_MODE,
+        next_resource: 1,
+    };
+
+    if let Some((scanout, rect)) = gpu.display_info()? {
+        gpu.scanout = scanout;
+        gpu.preferred = (rect.width, rect.height);
+    }
+    let requested = requested_mode();
+    let (width, height) = requested.unwrap_or(gpu.preferred);
+    match gpu.set_mode(width, height, mapper, frame_allocator) {
+        Err(err) if requested.is_some() => {
+            let (width, height) = gpu.preferred;
+            warn!(
+                "virtio-gpu: mode from the command line failed ({:?}), using {}x{}",
+                err, width, height
+            );
+            gpu.set_mode(width, height, mapper, frame_allocator)?;
+        }
+        result => result?,
+    }
+    info!(
+        "virtio-gpu: {:02x}:{:02x}.{}, scanout {} at {}x{}",
+        device.address.bus,
+        device.address.device,
+        device.address.function,
+        gpu.scanout,
+        gpu.width,
+        gpu.height
+    );
+    crate::device::bind(device.node, "virtio-gpu");
+    *GPU.lock() = Some(gpu);
+    Ok(())
+}
+
+// `video=<width>x<height>`
+fn requested_mode() -> Option<(u32, u32)> {
+    let (width, height) = cmdline::value("video")?.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+// Modes that can be set, the display's preferred one first
+pub fn modes() -> Vec<(u32, u32)> {
+    let preferred = match GPU.lock().as_ref() {
+        Some(gpu) => gpu.preferred,
+        None => return Vec::new(),
+    };
+    let mut modes = alloc::vec![preferred];
+    for &mode in STANDARD_MODES.iter() {
+        let fits = mode.0 as u64 * mode.1 as u64 * 4 <= FRAMEBUFFER_SPAN;
+        if fits && !modes.contains(&mode) {
+            modes.push(mode);
+        }
+    }
+    modes
+}
+
+// Listeners run without the GPU lock held, so they may redraw. The current
+// mode, if there is a display, is reported straight away.
+pub fn subscribe(listener: ModeListener) {
+    MODE_LISTENERS.lock().push(listener);
+    if let Some((width, height)) = mode() {
+        listener(width, height);
+    }
+}
+
+// Switches both buffers to a new resolution and shows a cleared screen.
+// Backing memory is mapped on demand, hence the allocator arguments.
+pub fn set_mode(
+    width: u32,
+    height: u32,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), GpuError> {
+    GPU.lock().as_mut().ok_or(GpuError::NoDevice)?.set_mode(
+        width,
+        height,
+        mapper,
+        frame_allocator,
+    )?;
+    let listeners = MODE_LISTENERS.lock().clone();
+    for listener in listeners {
+        listener(width, height);
+    }
+    Ok(())
+}
+
+pub fn mode() -> Option<(u32, u32)> {
+    GPU.lock().as_ref().map(|gpu| (gpu.width, gpu.height))
+}
+
+// Runs `draw` on the back buffer (width * height pixels, no padding). The
+// result shows up on the next `flip`.
+pub fn draw(draw: impl FnOnce(&mut [u32], u32, u32)) -> Result<(), GpuError> {
+    let mut gpu = GPU.lock();
+    let gpu = gpu.as_mut().ok_or(GpuError::NoDevice)?;
+    let back = &gpu.surfaces[1 - gpu.front];
+    let pixels = unsafe {
+        core::slice::from_raw_parts_mut(
+            back.base.as_mut_ptr::<u32>(),
+            (gpu.width * gpu.height) as usize,
+        )
+    };
+    draw(pixels, gpu.width, gpu.height);
+    Ok(())
+}
+
+pub fn flip() -> Result<(), GpuError> {
+    let mut gpu = GPU.lock();
+    gpu.as_mut().ok_or(GpuError::NoDevice)?.flip()
+}
+
+// Used on the panic path, where the lock may be held by the code that panicked
+pub fn force_unlock() {
+    unsafe { GPU.force_unlock() };
+}
+
+impl Gpu {
+    // Sends the request at the start of the request page and checks the
+    // response type
+    fn send(
+        &mut self,
+        length: usize,
+        response_length: usize,
+        expected: u32,
+    ) -> Result<(), GpuError> {
+        self.control.submit_and_wait(&[
+            Buffer {
+                addr: self.request.0,
+                len: length as u32,
+                device_writes: false,
+            },
+            Buffer {
+                addr: self.response.0,
+                len: response_length as u32,
+                device_writes: true,
+            },
+        ])?;
+        let response: Header = unsafe { read_volatile(self.response.1.as_ptr()) };
+        if response.kind != expected {
+            return Err(GpuError::Response(response.kind));
+        }
+        Ok(())
+    }
+
+    fn command<T: Copy>(&mut self, request: T) -> Result<(), GpuError> {
+        unsafe { write_volatile(self.request.1.as_mut_ptr(), request) };
+        self.send(size_of::<T>(), size_of::<Header>(), RESP_OK_NODATA)
+    }
+
+    // First enabled scanout and its preferred size
+    fn display_info(&mut self) -> Result<Option<(u32, Rect)>, GpuError> {
+        unsafe { write_volatile(self.request.1.as_mut_ptr(), header(CMD_GET_DISPLAY_INFO)) };
+        self.send(
+            size_of::<Header>(),
+            size_of::<DisplayInfo>(),
+            RESP_OK_DISPLAY_INFO,
+        )?;
+        let info: DisplayInfo = unsafe { read_volatile(self.response.1.as_ptr()) };
+        Ok(info
+            .modes
+            .iter()
+            .position(|mode| mode.enabled != 0)
+            .map(|scanout| (scanout as u32, info.modes[scanout].rect)))
+    }
+
+    fn set_mode(
+        &mut self,
+        width: u32,
+        height: u32,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), GpuError> {
+        let bytes = width as u64 * height as u64 * 4;
+        if width == 0 || height == 0 || bytes > FRAMEBUFFER_SPAN {
+            return Err(GpuError::ModeTooLarge);
+        }
+
+        for index in 0..2 {
+            let old = self.surfaces[index].resource;
+            if old != 0 {
+                let release = |kind| ResourceOnly {
+                    header: header(kind),
+                    resource_id: old,
+                    padding: 0,
+                };
+                self.command(release(CMD_RESOURCE_DETACH_BACKING))?;
+                self.command(release(CMD_RESOURCE_UNREF))?;
+                self.surfaces[index].resource = 0;
+            }
+
+            grow(&mut self.surfaces[index], bytes, mapper, frame_allocator)?;
+
+            let resource = self.next_resource;
+            self.next_resource += 1;
+            self.command(ResourceCreate2d {
+                header: header(CMD_RESOURCE_CREATE_2D),
+                resource_id: resource,
+                format: FORMAT_B8G8R8X8_UNORM,
+                width,
+                height,
+            })?;
+            self.attach_backing(index, resource, bytes)?;
+            self.surfaces[index].resource = resource;
+            unsafe {
+                core::ptr::write_bytes(
+                    self.surfaces[index].base.as_mut_ptr::<u8>(),
+                    0,
+                    bytes as usize,
+                );
+            }
+        }
+
+        self.width = width;
+        self.height = height;
+        self.flip()
+    }
+
+    // Describes the first `bytes` of the surface's memory to the host
+    fn attach_backing(&mut self, index: usize, resource: u32, bytes: u64) -> Result<(), GpuError> {
+        let mut remaining = (bytes + 4095) & !4095;
+        let mut entries: Vec<MemEntry> = Vec::new();
+        for &(addr, length) in self.surfaces[index].runs.iter() {
+            if remaining == 0 {
+                break;
+            }
+            let length = length.min(remaining);
+            entries.push(MemEntry {
+                addr,
+                length: length as u32,
+                padding: 0,
+            });
+            remaining -= length;
+        }
+        if entries.len() > MAX_BACKING_ENTRIES {
+            return Err(GpuError::TooFragmented);
+        }
+
+        let request = AttachBacking {
+            header: header(CMD_RESOURCE_ATTACH_BACKING),
+            resource_id: resource,
+            entries: entries.len() as u32,
+        };
+        unsafe {
+            let base = self.request.1.as_mut_ptr::<u8>();
+            write_volatile(base as *mut AttachBacking, request);
+            let list = base.add(size_of::<AttachBacking>()) as *mut MemEntry;
+            for (i, entry) in entries.iter().enumerate() {
+                write_volatile(list.add(i), *entry);
+            }
+        }
+        let length = size_of::<AttachBacking>() + entries.len() * size_of::<MemEntry>();
+        self.send(length, size_of::<Header>(), RESP_OK_NODATA)
+    }
+
+    // Uploads the back buffer and makes it the scanout source
+    fn flip(&mut self) -> Result<(), GpuError> {
+        let back = 1 - self.front;
+        let resource = self.surfaces[back].resource;
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        };
+        self.command(TransferToHost2d {
+            header: header(CMD_TRANSFER_TO_HOST_2D),
+            rect,
+            offset: 0,
+            resource_id: resource,
+            padding: 0,
+        })?;
+        self.command(SetScanout {
+            header: header(CMD_SET_SCANOUT),
+            rect,
+            scanout_id: self.scanout,
+            resource_id: resource,
+        })?;
+        self.command(ResourceFlush {
+            header: header(CMD_RESOURCE_FLUSH),
+            rect,
+            resource_id: resource,
+            padding: 0,
+        })?;
+        self.front = back;
+        Ok(())
+    }
+}
+
+// Maps more frames behind the surface until it covers `bytes`
+fn grow(
+    surface: &mut Surface,
+    bytes: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), GpuError> {
+    let pages = (bytes + 4095) / 4096;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    while surface.pages < pages {
+        let frame: PhysFrame = frame_allocator
+            .allocate_frame()
+            .ok_or(GpuError::Map(MapToError::FrameAllocationFailed))?;
+        let page = Page::containing_address(surface.base + surface.pages * 4096);
+        unsafe {
+            mapper
+                .map_to(page, frame, flags, frame_allocator)
+                .map_err(GpuError::Map)?
+                .flush();
+        }
+        let addr = frame.start_address().as_u64();
+        match surface.runs.last_mut() {
+            Some((start, length)) if *start + *length == addr => *length += 4096,
+            _ => surface.runs.push((addr, 4096)),
+        }
+        surface.pages += 1;
+    }
+    Ok(())
+}
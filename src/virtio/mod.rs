@@ -0,0 +1,389 @@
+// Virtio 1.0 over PCI. Devices are set up through their vendor-specific PCI
+// capabilities and use split virtqueues, driven by polling as there is no
+// interrupt routing yet.
+use crate::memory::cache::CacheMode;
+use crate::pci::{Bar, PciDevice};
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+#[cfg(feature = "console")]
+pub mod console;
+#[cfg(feature = "graphics")]
+pub mod gpu;
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+// Modern device IDs are this plus the virtio device type
+pub const DEVICE_ID_BASE: u16 = 0x1040;
+
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+const PCI_CAP_VENDOR: u8 = 0x09;
+const CFG_COMMON: u8 = 1;
+const CFG_NOTIFY: u8 = 2;
+const CFG_DEVICE: u8 = 4;
+
+// Common configuration registers
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 0x80;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+// A queue fits in one page: descriptor table, then available and used rings
+const MAX_QUEUE_SIZE: u16 = 64;
+const AVAIL_OFFSET: usize = 1024;
+const USED_OFFSET: usize = 2048;
+const POLL_LIMIT: usize = 10_000_000;
+
+#[derive(Debug)]
+pub enum VirtioError {
+    MissingCapability,
+    Map(MapToError<Size4KiB>),
+    OutOfMemory,
+    FeaturesRejected,
+    QueueUnavailable,
+    QueueFull,
+    Timeout,
+}
+
+pub struct Transport {
+    common: usize,
+    notify: usize,
+    notify_multiplier: u32,
+    config: usize,
+}
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    descriptors: *mut Descriptor,
+    // flags, idx, then the ring
+    avail: *mut u16,
+    used: VirtAddr,
+    notify: usize,
+    free: u16,
+    free_count: u16,
+    last_used: u16,
+}
+
+// Only used under the owning driver's lock
+unsafe impl Send for Virtqueue {}
+
+// One element of a descriptor chain
+#[derive(Clone, Copy, Debug)]
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    pub device_writes: bool,
+}
+
+fn read8(addr: usize) -> u8 {
+    unsafe { read_volatile(addr as *const u8) }
+}
+
+fn write8(addr: usize, value: u8) {
+    unsafe { write_volatile(addr as *mut u8, value) }
+}
+
+fn read16(addr: usize) -> u16 {
+    unsafe { read_volatile(addr as *const u16) }
+}
+
+fn write16(addr: usize, value: u16) {
+    unsafe { write_volatile(addr as *mut u16, value) }
+}
+
+fn read32(addr: usize) -> u32 {
+    unsafe { read_volatile(addr as *const u32) }
+}
+
+fn write32(addr: usize, value: u32) {
+    unsafe { write_volatile(addr as *mut u32, value) }
+}
+
+fn write64(addr: usize, value: u64) {
+    write32(addr, value as u32);
+    write32(addr + 4, (value >> 32) as u32);
+}
+
+fn wait(mut done: impl FnMut() -> bool) -> Result<(), VirtioError> {
+    for _ in 0..POLL_LIMIT {
+        if done() {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(VirtioError::Timeout)
+}
+
+impl Transport {
+    // Maps the common, notify and device configuration regions
+    pub fn new(
+        device: &PciDevice,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<Self, VirtioError> {
+        device.enable_bus_master();
+        let (mut common, mut notify, mut config) = (None, None, None);
+        let mut notify_multiplier = 0;
+
+        let address = device.address;
+        for (id, offset) in device.capabilities() {
+            if id != PCI_CAP_VENDOR {
+                continue;
+            }
+            let kind = address.read_u8(offset + 3);
+            let slot = match kind {
+                CFG_COMMON => &mut common,
+                CFG_NOTIFY => &mut notify,
+                CFG_DEVICE => &mut config,
+                _ => continue,
+            };
+            if slot.is_some() {
+                continue;
+            }
+            let base = match device.bar(address.read_u8(offset + 4)) {
+                Some(Bar::Memory { base, .. }) => base,
+                _ => continue,
+            };
+            let region = base + address.read_u32(offset + 8) as u64;
+            let length = address.read_u32(offset + 12).max(1) as u64;
+            let virt = crate::map_mmio(
+                mapper,
+                frame_allocator,
+                PhysAddr::new(region),
+                length,
+                CacheMode::Uncached,
+            )
+            .map_err(VirtioError::Map)?;
+            *slot = Some(virt.as_u64() as usize);
+            if kind == CFG_NOTIFY {
+                notify_multiplier = address.read_u32(offset + 16);
+            }
+        }
+
+        match (common, notify, config) {
+            (Some(common), Some(notify), Some(config)) => Ok(Transport {
+                common,
+                notify,
+                notify_multiplier,
+                config,
+            }),
+            _ => Err(VirtioError::MissingCapability),
+        }
+    }
+
+    fn add_status(&self, status: u8) {
+        let current = read8(self.common + COMMON_DEVICE_STATUS);
+        write8(self.common + COMMON_DEVICE_STATUS, current | status);
+    }
+
+    // Resets the device and negotiates features, returning the accepted set.
+    // VIRTIO_F_VERSION_1 is always required.
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, VirtioError> {
+        write8(self.common + COMMON_DEVICE_STATUS, 0);
+        wait(|| read8(self.common + COMMON_DEVICE_STATUS) == 0)?;
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+
+        let mut offered = 0u64;
+        for half in 0..2 {
+            write32(self.common + COMMON_DEVICE_FEATURE_SELECT, half);
+            offered |= (read32(self.common + COMMON_DEVICE_FEATURE) as u64) << (32 * half);
+        }
+        if offered & FEATURE_VERSION_1 == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err(VirtioError::FeaturesRejected);
+        }
+        let features = offered & (wanted | FEATURE_VERSION_1);
+        for half in 0..2 {
+            write32(self.common + COMMON_DRIVER_FEATURE_SELECT, half);
+            write32(
+                self.common + COMMON_DRIVER_FEATURE,
+                (features >> (32 * half)) as u32,
+            );
+        }
+
+        self.add_status(STATUS_FEATURES_OK);
+        if read8(self.common + COMMON_DEVICE_STATUS) & STATUS_FEATURES_OK == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err(VirtioError::FeaturesRejected);
+        }
+        Ok(features)
+    }
+
+    pub fn setup_queue(
+        &self,
+        index: u16,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        physical_memory_offset: VirtAddr,
+    ) -> Result<Virtqueue, VirtioError> {
+        write16(self.common + COMMON_QUEUE_SELECT, index);
+        let max = read16(self.common + COMMON_QUEUE_SIZE);
+        if max == 0 {
+            return Err(VirtioError::QueueUnavailable);
+        }
+        let size = max.min(MAX_QUEUE_SIZE);
+        write16(self.common + COMMON_QUEUE_SIZE, size);
+
+        let (phys, virt) = crate::alloc_dma_frame(frame_allocator, physical_memory_offset)
+            .ok_or(VirtioError::OutOfMemory)?;
+        write64(self.common + COMMON_QUEUE_DESC, phys.as_u64());
+        write64(
+            self.common + COMMON_QUEUE_DRIVER,
+            phys.as_u64() + AVAIL_OFFSET as u64,
+        );
+        write64(
+            self.common + COMMON_QUEUE_DEVICE,
+            phys.as_u64() + USED_OFFSET as u64,
+        );
+        let notify_off = read16(self.common + COMMON_QUEUE_NOTIFY_OFF) as usize;
+        write16(self.common + COMMON_QUEUE_ENABLE, 1);
+
+        let descriptors: *mut Descriptor = virt.as_mut_ptr();
+        for i in 0..size {
+            unsafe { write_volatile(addr_of_mut!((*descriptors.add(i as usize)).next), i + 1) };
+        }
+
+        Ok(Virtqueue {
+            index,
+            size,
+            descriptors,
+            avail: (virt + AVAIL_OFFSET).as_mut_ptr(),
+            used: virt + USED_OFFSET,
+            notify: self.notify + notify_off * self.notify_multiplier as usize,
+            free: 0,
+            free_count: size,
+            last_used: 0,
+        })
+    }
+
+    // Called once all queues are set up
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    pub fn read_config_u32(&self, offset: usize) -> u32 {
+        read32(self.config + offset)
+    }
+
+    pub fn write_config_u32(&self, offset: usize, value: u32) {
+        write32(self.config + offset, value)
+    }
+}
+
+impl Virtqueue {
+    // Queues a descriptor chain, notifies the device and returns the chain's
+    // head index
+    pub fn submit(&mut self, buffers: &[Buffer]) -> Result<u16, VirtioError> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return Err(VirtioError::QueueFull);
+        }
+        let head = self.free;
+        let mut index = head;
+        for (position, buffer) in buffers.iter().enumerate() {
+            let last = position + 1 == buffers.len();
+            unsafe {
+                let descriptor = self.descriptors.add(index as usize);
+                let next = read_volatile(addr_of!((*descriptor).next));
+                let mut flags = if buffer.device_writes {
+                    DESC_F_WRITE
+                } else {
+                    0
+                };
+                if !last {
+                    flags |= DESC_F_NEXT;
+                }
+                write_volatile(addr_of_mut!((*descriptor).addr), buffer.addr.as_u64());
+                write_volatile(addr_of_mut!((*descriptor).len), buffer.len);
+                write_volatile(addr_of_mut!((*descriptor).flags), flags);
+                if last {
+                    self.free = next;
+                } else {
+                    index = next;
+                }
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        unsafe {
+            let avail_index = read_volatile(self.avail.add(1));
+            write_volatile(self.avail.add(2 + (avail_index % self.size) as usize), head);
+            fence(Ordering::SeqCst);
+            write_volatile(self.avail.add(1), avail_index.wrapping_add(1));
+        }
+        fence(Ordering::SeqCst);
+        write16(self.notify, self.index);
+        Ok(head)
+    }
+
+    // Returns the next completed chain as (head index, bytes written by the
+    // device) and puts its descriptors back on the free list
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_index = read16(self.used.as_u64() as usize + 2);
+        if used_index == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let element = self.used.as_u64() as usize + 4 + (self.last_used % self.size) as usize * 8;
+        let head = read32(element) as u16;
+        let len = read32(element + 4);
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let mut index = head;
+        let mut count = 1;
+        unsafe {
+            loop {
+                let descriptor = self.descriptors.add(index as usize);
+                if read_volatile(addr_of!((*descriptor).flags)) & DESC_F_NEXT == 0 {
+                    write_volatile(addr_of_mut!((*descriptor).next), self.free);
+                    break;
+                }
+                index = read_volatile(addr_of!((*descriptor).next));
+                count += 1;
+            }
+        }
+        self.free = head;
+        self.free_count += count;
+        Some((head, len))
+    }
+
+    // Submits a chain and polls until the device has used it
+    pub fn submit_and_wait(&mut self, buffers: &[Buffer]) -> Result<u32, VirtioError> {
+        let head = self.submit(buffers)?;
+        for _ in 0..POLL_LIMIT {
+            match self.pop_used() {
+                Some((used, len)) if used == head => return Ok(len),
+                Some(_) => {}
+                None => core::hint::spin_loop(),
+            }
+        }
+        Err(VirtioError::Timeout)
+    }
+}
@@ -0,0 +1,181 @@
+// Host-side build runner: `cargo xtask build` builds the kernel and its boot
+// image, `cargo xtask run` also launches QEMU. Profile flags pick the QEMU
+// setup and add the kernel command line options that go with it; the command
+// line is baked into the kernel at build time, so every run rebuilds with the
+// options for its profiles.
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+
+const USAGE: &str = "usage: cargo xtask <build|run> [options]
+
+options:
+    --release            build with optimizations
+    --features <list>    kernel features, in addition to the defaults
+    --no-default-features
+    --cmdline <options>  extra kernel command line options
+    --test               headless: serial on stdio, no display, run the self tests
+    --graphics           virtio-gpu display, USB keyboard and mouse, AC'97
+    --gdb                wait for a debugger on tcp::1234
+    --kvm                use KVM and the host CPU model";
+
+struct Profile {
+    flag: &'static str,
+    qemu: &'static [&'static str],
+    cmdline: &'static str,
+}
+
+const PROFILES: &[Profile] = &[
+    Profile {
+        flag: "--test",
+        qemu: &["-display", "none", "-no-reboot"],
+        cmdline: "selftest=1",
+    },
+    Profile {
+        flag: "--graphics",
+        qemu: &[
+            "-device",
+            "virtio-gpu-pci",
+            "-device",
+            "qemu-xhci",
+            "-device",
+            "usb-kbd",
+            "-device",
+            "usb-mouse",
+            "-device",
+            "AC97",
+        ],
+        cmdline: "",
+    },
+    Profile {
+        flag: "--gdb",
+        qemu: &["-s", "-S"],
+        cmdline: "",
+    },
+    Profile {
+        flag: "--kvm",
+        qemu: &["-enable-kvm", "-cpu", "host"],
+        cmdline: "",
+    },
+];
+
+#[derive(Default)]
+struct Options {
+    release: bool,
+    features: Option<String>,
+    no_default_features: bool,
+    cmdline: Vec<String>,
+    profiles: Vec<&'static Profile>,
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("xtask: {}", message);
+    exit(1);
+}
+
+fn parse(mut args: impl Iterator<Item = String>) -> Options {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--release" => options.release = true,
+            "--no-default-features" => options.no_default_features = true,
+            "--features" => {
+                options.features = Some(
+                    args.next()
+                        .unwrap_or_else(|| fail("--features needs a value")),
+                )
+            }
+            "--cmdline" => options.cmdline.push(
+                args.next()
+                    .unwrap_or_else(|| fail("--cmdline needs a value")),
+            ),
+            flag => match PROFILES.iter().find(|profile| profile.flag == flag) {
+                Some(profile) => options.profiles.push(profile),
+                None => fail(&format!("unknown option {}\n\n{}", flag, USAGE)),
+            },
+        }
+    }
+    options
+}
+
+fn root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .expect("xtask lives in the workspace")
+        .to_path_buf()
+}
+
+fn kernel_cmdline(options: &Options) -> String {
+    let profiles = options.profiles.iter().map(|profile| profile.cmdline);
+    let extra = options.cmdline.iter().map(String::as_str);
+    profiles
+        .chain(extra)
+        .filter(|option| !option.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn run(command: &mut Command) {
+    let status = command
+        .status()
+        .unwrap_or_else(|err| fail(&format!("could not run {:?}: {}", command, err)));
+    if !status.success() {
+        fail(&format!("{:?} failed: {}", command, status));
+    }
+}
+
+// Builds the kernel and returns the path of the boot image
+fn build(options: &Options) -> PathBuf {
+    let cmdline = kernel_cmdline(options);
+    println!("xtask: kernel command line {:?}", cmdline);
+
+    let mut cargo = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".into()));
+    cargo
+        .current_dir(root())
+        .args(["bootimage", "--package", "rust_os"])
+        .env("KERNEL_CMDLINE", &cmdline);
+    if options.release {
+        cargo.arg("--release");
+    }
+    if options.no_default_features {
+        cargo.arg("--no-default-features");
+    }
+    if let Some(features) = &options.features {
+        cargo.args(["--features", features]);
+    }
+    run(&mut cargo);
+
+    let profile = if options.release { "release" } else { "debug" };
+    root()
+        .join("target/x86_64-rust_os")
+        .join(profile)
+        .join("bootimage-rust_os.bin")
+}
+
+fn qemu(options: &Options, image: &Path) {
+    let mut qemu = Command::new("qemu-system-x86_64");
+    qemu.arg("-drive")
+        .arg(format!("format=raw,file={}", image.display()))
+        .args(["-serial", "stdio"]);
+    for profile in &options.profiles {
+        qemu.args(profile.qemu);
+    }
+    run(&mut qemu);
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let command = args.next();
+    let options = parse(args);
+    match command.as_deref() {
+        Some("build") => {
+            let image = build(&options);
+            println!("xtask: boot image at {}", image.display());
+        }
+        Some("run") => {
+            let image = build(&options);
+            qemu(&options, &image);
+        }
+        _ => fail(USAGE),
+    }
+}